@@ -0,0 +1,121 @@
+use noodles::bam;
+use noodles::bgzf;
+use noodles::sam;
+use noodles::core::{Position, Region};
+use noodles::sam::alignment::record::cigar::op::Kind;
+use noodles::sam::alignment::record::data::field::Value;
+use File;
+
+pub const CELL_TAG: &[u8; 2] = b"CB";
+pub const UMI_TAG: &[u8; 2] = b"UB";
+
+pub type BamReader = bam::io::IndexedReader<bgzf::io::Reader<File>>;
+
+pub fn open_bam(bam: &str) -> (BamReader, sam::Header) {
+    let mut reader = bam::io::indexed_reader::Builder::default().build_from_path(bam)
+        .expect(&format!("couldn't open bam {}, it needs an index (.bam.bai or .bam.csi)", bam));
+    let header = reader.read_header().expect(&format!("couldn't read header of bam {}", bam));
+    return (reader, header);
+}
+
+pub fn reference_lengths(header: &sam::Header) -> Vec<(String, usize)> {
+    let mut lengths: Vec<(String, usize)> = Vec::new();
+    for (name, reference_sequence) in header.reference_sequences() {
+        lengths.push((name.to_string(), reference_sequence.length().get()));
+    }
+    return lengths;
+}
+
+// splits the reference into one group of (chrom, start, end) regions per thread with roughly equal total length,
+// start and end are 0-based half open
+pub fn get_bam_regions(header: &sam::Header, threads: usize) -> Vec<Vec<(String, usize, usize)>> {
+    let lengths = reference_lengths(header);
+    let total_reference_length: usize = lengths.iter().map(|(_chrom, length)| length).sum();
+    let step_length = ((total_reference_length as f64) / (threads.max(1) as f64)).ceil() as usize;
+    let mut regions: Vec<Vec<(String, usize, usize)>> = Vec::new();
+    let mut region: Vec<(String, usize, usize)> = Vec::new();
+    let mut region_so_far = 0;
+    let mut chrom_so_far = 0;
+    for (chrom, chrom_length) in lengths {
+        loop {
+            if chrom_length - chrom_so_far <= step_length - region_so_far {
+                region.push((chrom.clone(), chrom_so_far, chrom_length));
+                region_so_far += chrom_length - chrom_so_far;
+                chrom_so_far = 0;
+                break;
+            } else {
+                region.push((chrom.clone(), chrom_so_far, chrom_so_far + step_length - region_so_far));
+                regions.push(region);
+                region = Vec::new();
+                chrom_so_far += step_length - region_so_far;
+                region_so_far = 0;
+            }
+        }
+    }
+    if !region.is_empty() { regions.push(region); }
+    return regions;
+}
+
+pub fn query_region(chrom: &str, start: usize, end: usize) -> Region {
+    let start = Position::new(start + 1).unwrap();
+    let end = Position::new(end.max(1)).unwrap();
+    return Region::new(chrom, start..=end);
+}
+
+pub fn string_tag(record: &bam::Record, tag: &[u8; 2]) -> Option<String> {
+    match record.data().get(tag) {
+        Some(Ok(Value::String(value))) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// unmapped, secondary, supplementary, qc fail and duplicate reads are skipped, same as samtools depth
+pub fn is_primary_usable(record: &bam::Record) -> bool {
+    let flags = record.flags();
+    return !(flags.is_unmapped() || flags.is_secondary() || flags.is_supplementary() || flags.is_qc_fail() || flags.is_duplicate());
+}
+
+pub fn mapping_quality(record: &bam::Record) -> u8 {
+    match record.mapping_quality() {
+        Some(mapping_quality) => mapping_quality.get(),
+        None => 255,
+    }
+}
+
+// 1-based start and end (inclusive) of the reference span of the alignment
+pub fn alignment_span(record: &bam::Record) -> Option<(usize, usize)> {
+    let start = usize::from(record.alignment_start()?.ok()?);
+    let mut length = 0;
+    for op in record.cigar().iter() {
+        let op = op.ok()?;
+        if op.kind().consumes_reference() { length += op.len(); }
+    }
+    if length == 0 { return None; }
+    return Some((start, start + length - 1));
+}
+
+// 0-based offset into the read of the base aligned to the 1-based reference position,
+// None if the read does not reach it or has a deletion or splice there
+pub fn read_offset_at(record: &bam::Record, pos: usize) -> Option<usize> {
+    let mut ref_pos = usize::from(record.alignment_start()?.ok()?);
+    let mut read_pos = 0;
+    for op in record.cigar().iter() {
+        let op = op.ok()?;
+        let len = op.len();
+        match op.kind() {
+            Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => {
+                if pos >= ref_pos && pos < ref_pos + len { return Some(read_pos + pos - ref_pos); }
+                ref_pos += len;
+                read_pos += len;
+            },
+            Kind::Insertion | Kind::SoftClip => { read_pos += len; },
+            Kind::Deletion | Kind::Skip => {
+                if pos >= ref_pos && pos < ref_pos + len { return None; }
+                ref_pos += len;
+            },
+            Kind::HardClip | Kind::Pad => {},
+        }
+        if ref_pos > pos { return None; }
+    }
+    return None;
+}
@@ -0,0 +1,257 @@
+use std::fs;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::time::UNIX_EPOCH;
+use hashbrown::HashMap;
+use File;
+use Params;
+use load_data::{CountStore, VcfLocusData};
+
+// binary cache of the parsed inputs (barcodes, vcf loci and the locus-major allele counts) so re-runs on the same
+// inputs with different parameters skip parsing the mtx files or counting the bam. the header records the path,
+// size and modification time of every input (and the read filters and fasta when counting a bam), the cache is only
+// used if they all match and rewritten otherwise
+const MAGIC: &[u8] = b"CELLECTOR_CACHE";
+const VERSION: u32 = 1;
+
+pub struct CachedInputs {
+    pub cell_id_to_barcode: Vec<String>,
+    pub barcode_to_cell_id: HashMap<String, usize>,
+    pub vcf_data: Option<Vec<VcfLocusData>>,
+    pub counts: CountStore,
+}
+
+// a missing file (eg a fasta without a .fai) is recorded as missing so creating it later invalidates the cache
+fn source_line(kind: &str, path: &str) -> String {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return format!("{}\t{}\tmissing\n", kind, path),
+    };
+    let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|time| (time.as_secs(), time.subsec_nanos()));
+    let (seconds, nanos) = modified.unwrap_or((0, 0));
+    return format!("{}\t{}\t{}\t{}.{:09}\n", kind, path, metadata.len(), seconds, nanos);
+}
+
+// what the cached data was parsed from, compared as a whole against the cache header
+fn source_key(params: &Params) -> String {
+    let mut key = String::new();
+    if let Some(vcf) = &params.vcf { key.push_str(&source_line("vcf", vcf)); }
+    key.push_str(&source_line("barcodes", &params.barcodes));
+    match &params.bam {
+        Some(bam) => {
+            // with --bam the counts also depend on the read filters and on the reference (sites where it disagrees
+            // with the vcf ref are skipped), alt.mtx and ref.mtx are outputs
+            key.push_str(&source_line("bam", bam));
+            key.push_str(&format!("min_mapq\t{}\nmin_base_quality\t{}\n", params.min_mapq, params.min_base_quality));
+            if let Some(fasta) = &params.fasta {
+                key.push_str(&source_line("fasta", fasta));
+                key.push_str(&source_line("fasta_index", &format!("{}.fai", fasta)));
+            }
+        },
+        None => {
+            key.push_str(&source_line("alt", &params.alt_mtx));
+            key.push_str(&source_line("ref", &params.ref_mtx));
+        },
+    }
+    return key;
+}
+
+fn write_u64(writer: &mut dyn Write, value: u64) {
+    writer.write_all(&value.to_le_bytes()).expect("could not write cache");
+}
+
+fn write_string(writer: &mut dyn Write, value: &str) {
+    write_u64(writer, value.len() as u64);
+    writer.write_all(value.as_bytes()).expect("could not write cache");
+}
+
+fn write_u32s(writer: &mut dyn Write, values: &Vec<u32>) {
+    write_u64(writer, values.len() as u64);
+    for value in values { writer.write_all(&value.to_le_bytes()).expect("could not write cache"); }
+}
+
+// the reader is limited to the file so a length field can be checked against the bytes left, a corrupt length is
+// then a miss instead of a huge allocation
+type CacheReader<'a> = io::Take<&'a mut dyn Read>;
+
+fn read_u64(reader: &mut CacheReader) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    return Ok(u64::from_le_bytes(bytes));
+}
+
+// a count of items of item_size bytes each that must still fit in the rest of the file
+fn read_length(reader: &mut CacheReader, item_size: u64) -> io::Result<usize> {
+    let len = read_u64(reader)?;
+    match len.checked_mul(item_size) {
+        Some(bytes) if bytes <= reader.limit() => return Ok(len as usize),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "length exceeds the rest of the file")),
+    }
+}
+
+fn read_string(reader: &mut CacheReader) -> io::Result<String> {
+    let mut bytes = vec![0u8; read_length(reader, 1)?];
+    reader.read_exact(&mut bytes)?;
+    return String::from_utf8(bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+}
+
+fn read_u32s(reader: &mut CacheReader) -> io::Result<Vec<u32>> {
+    let len = read_length(reader, 4)?;
+    let mut values: Vec<u32> = Vec::with_capacity(len);
+    let mut bytes = [0u8; 4];
+    for _ in 0..len {
+        reader.read_exact(&mut bytes)?;
+        values.push(u32::from_le_bytes(bytes));
+    }
+    return Ok(values);
+}
+
+// written to {filename}.tmp and renamed so an interrupted run never leaves a partial cache behind
+pub fn write_cache(params: &Params, filename: &str, cell_id_to_barcode: &Vec<String>, vcf_data: &Option<Vec<VcfLocusData>>, counts: &CountStore) {
+    let tmp_filename = format!("{}.tmp", filename);
+    let filehandle = File::create(&tmp_filename).expect(&format!("Unable to create file {}", tmp_filename));
+    let mut writer = BufWriter::new(filehandle);
+    writer.write_all(MAGIC).expect("could not write cache");
+    writer.write_all(&VERSION.to_le_bytes()).expect("could not write cache");
+    write_string(&mut writer, &source_key(params));
+    write_u64(&mut writer, cell_id_to_barcode.len() as u64);
+    for barcode in cell_id_to_barcode { write_string(&mut writer, barcode); }
+    match vcf_data {
+        Some(vcf_data) => {
+            write_u64(&mut writer, 1);
+            write_u64(&mut writer, vcf_data.len() as u64);
+            for locus in vcf_data {
+                for field in [&locus.chrom, &locus.pos, &locus.ref_allele, &locus.alt_allele].iter() { write_string(&mut writer, field); }
+            }
+        },
+        None => write_u64(&mut writer, 0),
+    }
+    write_u64(&mut writer, counts.total_loci as u64);
+    write_u64(&mut writer, counts.total_cells as u64);
+    for offset in &counts.locus_offsets { write_u64(&mut writer, *offset as u64); }
+    write_u32s(&mut writer, &counts.locus_cells);
+    write_u32s(&mut writer, &counts.locus_ref);
+    write_u32s(&mut writer, &counts.locus_alt);
+    let filehandle = writer.into_inner().expect("could not write cache");
+    filehandle.sync_all().expect("could not write cache");
+    fs::rename(&tmp_filename, filename).expect(&format!("could not rename {} to {}", tmp_filename, filename));
+    println!("wrote input cache {}", filename);
+}
+
+// the cached inputs if the cache exists and was written from the current inputs. a cache that cannot be read (eg
+// from an older version or truncated) is a miss and is rewritten, a file that is not a cache is never overwritten
+pub fn load_cache(params: &Params, filename: &str) -> Option<CachedInputs> {
+    let filehandle = match File::open(filename) {
+        Ok(filehandle) => filehandle,
+        Err(_) => return None,
+    };
+    let file_size = filehandle.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    let mut reader = BufReader::with_capacity(1024 * 1024, filehandle);
+    let mut magic = vec![0u8; MAGIC.len()];
+    if reader.read_exact(&mut magic).is_ok() {
+        assert!(magic == MAGIC, "{} exists but is not a cellector cache, remove it or choose another --cache file", filename);
+    }
+    let mut reader = (&mut reader as &mut dyn Read).take(file_size.saturating_sub(MAGIC.len() as u64));
+    match read_cache(&mut reader, params) {
+        Ok(Some(cached)) => {
+            println!("loaded inputs from cache {}", filename);
+            return Some(cached);
+        },
+        Ok(None) => println!("inputs changed since input cache {} was written, rewriting it", filename),
+        Err(error) => println!("could not read input cache {} ({}), rewriting it", filename, error),
+    }
+    return None;
+}
+
+// everything after the magic, None if the cache is for other inputs
+fn read_cache(reader: &mut CacheReader, params: &Params) -> io::Result<Option<CachedInputs>> {
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    if u32::from_le_bytes(version) != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "written by another version of cellector"));
+    }
+    if read_string(reader)? != source_key(params) { return Ok(None); }
+    let num_cells = read_length(reader, 8)?;
+    let mut cell_id_to_barcode: Vec<String> = Vec::new();
+    let mut barcode_to_cell_id: HashMap<String, usize> = HashMap::new();
+    for cell_id in 0..num_cells {
+        let barcode = read_string(reader)?;
+        barcode_to_cell_id.insert(barcode.clone(), cell_id);
+        cell_id_to_barcode.push(barcode);
+    }
+    let mut vcf_data: Option<Vec<VcfLocusData>> = None;
+    if read_u64(reader)? == 1 {
+        let num_loci = read_length(reader, 32)?;
+        let mut loci: Vec<VcfLocusData> = Vec::new();
+        for locus_index in 0..num_loci {
+            loci.push(VcfLocusData {
+                locus_index: locus_index,
+                chrom: read_string(reader)?,
+                pos: read_string(reader)?,
+                ref_allele: read_string(reader)?,
+                alt_allele: read_string(reader)?,
+            });
+        }
+        vcf_data = Some(loci);
+    }
+    let total_loci = read_length(reader, 8)?;
+    let total_cells = read_u64(reader)? as usize;
+    let mut locus_offsets: Vec<usize> = Vec::new();
+    for _ in 0..(total_loci + 1) { locus_offsets.push(read_u64(reader)? as usize); }
+    let locus_cells = read_u32s(reader)?;
+    let locus_ref = read_u32s(reader)?;
+    let locus_alt = read_u32s(reader)?;
+    let consistent = locus_offsets.len() == total_loci + 1 && locus_offsets[total_loci] == locus_cells.len()
+        && locus_ref.len() == locus_cells.len() && locus_alt.len() == locus_cells.len();
+    if !consistent { return Err(io::Error::new(io::ErrorKind::InvalidData, "offsets do not match the entries")); }
+    return Ok(Some(CachedInputs {
+        cell_id_to_barcode: cell_id_to_barcode,
+        barcode_to_cell_id: barcode_to_cell_id,
+        vcf_data: vcf_data,
+        counts: CountStore::from_locus_major(total_loci, total_cells, locus_offsets, locus_cells, locus_ref, locus_alt),
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use load_data;
+
+    #[test]
+    fn cache_round_trip_and_truncation() {
+        let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/combiner");
+        let params = Params {
+            alt_mtx: format!("{}/alt.mtx.gz", data),
+            ref_mtx: format!("{}/ref.mtx.gz", data),
+            barcodes: format!("{}/barcodes.tsv", data),
+            ..Default::default()
+        };
+        let filename = format!("{}/inputs.cache", ::test_directory("cache_round_trip"));
+        let (cell_id_to_barcode, barcode_to_cell_id) = load_data::load_barcodes(&params);
+        let counts = load_data::load_counts(&params, &None);
+        write_cache(&params, &filename, &cell_id_to_barcode, &None, &counts);
+        assert!(!std::path::Path::new(&format!("{}.tmp", filename)).exists());
+
+        let cached = load_cache(&params, &filename).expect("cache written from the same inputs was not used");
+        assert_eq!(cached.cell_id_to_barcode, cell_id_to_barcode);
+        assert_eq!(cached.barcode_to_cell_id, barcode_to_cell_id);
+        assert!(cached.vcf_data.is_none());
+        assert_eq!((cached.counts.total_loci, cached.counts.total_cells), (counts.total_loci, counts.total_cells));
+        assert_eq!(cached.counts.locus_offsets, counts.locus_offsets);
+        assert_eq!(cached.counts.locus_cells, counts.locus_cells);
+        assert_eq!(cached.counts.locus_ref, counts.locus_ref);
+        assert_eq!(cached.counts.locus_alt, counts.locus_alt);
+
+        // a length field larger than the file is a miss, not an allocation of that size
+        let mut bytes = fs::read(&filename).unwrap();
+        bytes[MAGIC.len() + 4..MAGIC.len() + 12].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&filename, &bytes).unwrap();
+        assert!(load_cache(&params, &filename).is_none());
+        write_cache(&params, &filename, &cell_id_to_barcode, &None, &counts);
+
+        // a truncated cache is a miss, not a panic
+        let length = fs::metadata(&filename).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&filename).unwrap().set_len(length - 5).unwrap();
+        assert!(load_cache(&params, &filename).is_none());
+    }
+}
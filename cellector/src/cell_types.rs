@@ -0,0 +1,88 @@
+use std::io::{BufWriter, Write};
+use hashbrown::HashMap;
+use rand::rngs::StdRng;
+use File;
+use Params;
+use FinalResults;
+use stats;
+use stats::FractionEstimate;
+use load_data::CellData;
+
+// per cell type results with --cell_types (a barcode\tcell_type tsv, see load_data::load_cell_types). minority cells
+// are often concentrated in a few lineages (eg residual host t cells), so cellector_cell_types.tsv has the assignment
+// counts, mean minority posterior and minority fraction with its intervals of every cell type next to all cells
+pub struct CellTypeSummary {
+    pub cell_type: String,
+    pub num_cells: usize,
+    pub assignment_counts: HashMap<&'static str, usize>, // 0, 1, doublet, unassigned as in cellector_assignments.tsv
+    pub mean_posterior: f64,
+    pub num_assignable: usize,
+    pub minority_fraction: FractionEstimate,
+}
+
+impl CellTypeSummary {
+    pub fn count(&self, assignment: &str) -> usize {
+        return *self.assignment_counts.get(assignment).unwrap_or(&0);
+    }
+}
+
+// "all" first, then the cell types in name order with --cell_types
+pub fn summarize_cell_types(params: &Params, cell_data: &Vec<CellData>, results: &FinalResults, rng: &mut StdRng) -> Vec<CellTypeSummary> {
+    let mut names: Vec<String> = Vec::new();
+    if params.cell_types.is_some() {
+        names = cell_data.iter().map(|cell| cell.cell_type.clone()).collect();
+        names.sort();
+        names.dedup();
+    }
+    let group_index: HashMap<String, usize> = names.iter().enumerate().map(|(index, name)| (name.clone(), index + 1)).collect();
+    names.insert(0, "all".to_string());
+    let minority_probabilities = ::singlet_minority_probabilities(params, &results.posteriors, &results.doublet_posteriors, &results.cell_num_loci);
+    let mut num_cells: Vec<usize> = vec![0; names.len()];
+    let mut posterior_sums: Vec<f64> = vec![0.0; names.len()];
+    let mut assignment_counts: Vec<HashMap<&'static str, usize>> = vec![HashMap::new(); names.len()];
+    let mut probabilities: Vec<Vec<f64>> = vec![Vec::new(); names.len()];
+    for cell in cell_data {
+        let assignment = ::posterior_assignment(params, results.posteriors[cell.cell_id], results.doublet_posteriors[cell.cell_id], results.cell_num_loci[cell.cell_id]);
+        let mut indices = vec![0];
+        if let Some(index) = group_index.get(&cell.cell_type) { indices.push(*index); }
+        for index in indices {
+            num_cells[index] += 1;
+            posterior_sums[index] += results.posteriors[cell.cell_id];
+            *assignment_counts[index].entry(assignment).or_insert(0) += 1;
+            if let Some(probability) = minority_probabilities[cell.cell_id] { probabilities[index].push(probability); }
+        }
+    }
+    let mut summaries: Vec<CellTypeSummary> = Vec::new();
+    for (index, name) in names.into_iter().enumerate() {
+        summaries.push(CellTypeSummary {
+            cell_type: name,
+            num_cells: num_cells[index],
+            assignment_counts: assignment_counts[index].clone(),
+            mean_posterior: posterior_sums[index] / (num_cells[index].max(1) as f64),
+            num_assignable: probabilities[index].len(),
+            minority_fraction: stats::minority_fraction_estimate(&probabilities[index], params.bootstrap_iterations, rng),
+        });
+    }
+    return summaries;
+}
+
+pub const SUMMARY_HEADER: &str = "cell_type\tcells\tminority\tmajority\tdoublet\tunassigned\tmean_posterior\tassignable_cells\tminority_fraction\tposterior_ci_low\tposterior_ci_high\tbootstrap_ci_low\tbootstrap_ci_high";
+
+pub fn summary_line(summary: &CellTypeSummary) -> String {
+    let estimate = &summary.minority_fraction;
+    return format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", summary.cell_type, summary.num_cells, summary.count("0"), summary.count("1"),
+        summary.count("doublet"), summary.count("unassigned"), summary.mean_posterior, summary.num_assignable, estimate.fraction,
+        estimate.posterior_interval.0, estimate.posterior_interval.1, estimate.bootstrap_interval.0, estimate.bootstrap_interval.1);
+}
+
+pub fn write_cell_type_summary(params: &Params, summaries: &Vec<CellTypeSummary>) {
+    let filename = format!("{}/cellector_cell_types.tsv", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    writer.write_all(format!("{}\n", SUMMARY_HEADER).as_bytes()).expect("could not write cell type summary");
+    for summary in summaries {
+        writer.write_all(format!("{}\n", summary_line(summary)).as_bytes()).expect("could not write cell type summary");
+        println!("{}: {} cells, {} minority, minority fraction {:.4} (95% bootstrap interval {:.4} to {:.4})", summary.cell_type, summary.num_cells,
+            summary.count("0"), summary.minority_fraction.fraction, summary.minority_fraction.bootstrap_interval.0, summary.minority_fraction.bootstrap_interval.1);
+    }
+}
@@ -0,0 +1,298 @@
+use clap::ArgMatches;
+use noodles::bam as noodles_bam;
+use std::path::Path;
+use std::io::BufRead;
+use hashbrown::{HashMap, HashSet};
+use variants;
+use variants::VariantsParams;
+use load_data::reader;
+use bam;
+
+const NUM_READ_TEST: usize = 100000;
+const NUM_SITE_TEST: usize = 1000;
+
+// sanity checks on the pipeline inputs before anything expensive is run, each failed check is reported with what to do about it
+pub struct CheckParams {
+    pub bam: String,
+    pub barcodes: String,
+    pub fasta: Option<String>,
+    pub common_variants: Option<String>,
+    pub ignore: bool,
+}
+
+pub fn load_check_params(params: &ArgMatches) -> CheckParams {
+    let bam = params.value_of("bam").unwrap().to_string();
+    let barcodes = params.value_of("barcodes").unwrap().to_string();
+    let fasta = match params.value_of("fasta") {
+        Some(x) => Some(x.to_string()),
+        None => None,
+    };
+    let common_variants = match params.value_of("common_variants") {
+        Some(x) => Some(x.to_string()),
+        None => None,
+    };
+    return CheckParams {
+        bam: bam,
+        barcodes: barcodes,
+        fasta: fasta,
+        common_variants: common_variants,
+        ignore: params.is_present("ignore"),
+    };
+}
+
+// runs every check, prints the problems found and exits with status 1 if there are any unless --ignore is set
+pub fn check_inputs(params: &CheckParams) {
+    let problems = find_problems(params);
+    if problems.is_empty() {
+        println!("all input checks passed");
+        return;
+    }
+    for problem in &problems {
+        eprintln!("{}: {}", if params.ignore { "WARNING" } else { "ERROR" }, problem);
+    }
+    if !params.ignore {
+        eprintln!("{} input check(s) failed, fix the inputs or rerun with --ignore to continue anyway", problems.len());
+        std::process::exit(1);
+    }
+}
+
+pub fn find_problems(params: &CheckParams) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    let mut files = vec![("bam", params.bam.clone()), ("barcodes", params.barcodes.clone())];
+    if let Some(fasta) = &params.fasta { files.push(("fasta", fasta.clone())); }
+    if let Some(common_variants) = &params.common_variants { files.push(("common_variants", common_variants.clone())); }
+    for (name, path) in &files {
+        if !Path::new(path).exists() { problems.push(format!("--{} {} does not exist", name, path)); }
+    }
+    if !problems.is_empty() { return problems; }
+
+    let barcodes = variants::load_valid_barcodes(&Some(params.barcodes.clone())).unwrap();
+    println!("{} barcodes in {}", barcodes.len(), params.barcodes);
+    if barcodes.len() <= 50 {
+        problems.push(format!("only {} barcodes in {}, expected the cellranger barcodes.tsv with 1 barcode per line", barcodes.len(), params.barcodes));
+    }
+    let (bam_contigs, indexed) = match check_bam_tags(params, &barcodes, &mut problems) {
+        Some(bam_contigs) => bam_contigs,
+        None => return problems,
+    };
+    if let Some(fasta) = &params.fasta {
+        check_fasta_contigs(fasta, &bam_contigs, &mut problems);
+    }
+    if let Some(common_variants) = &params.common_variants {
+        check_vcf(params, common_variants, &bam_contigs, indexed, &barcodes, &mut problems);
+    }
+    return problems;
+}
+
+// looks at the first 100000 reads for CB and UB tags and CBs from the barcodes file, returns the bam contig lengths and
+// whether the bam has an index
+fn check_bam_tags(params: &CheckParams, barcodes: &HashSet<String>, problems: &mut Vec<String>) -> Option<(Vec<(String, usize)>, bool)> {
+    let index_exists = Path::new(&format!("{}.bai", params.bam)).exists() || Path::new(&format!("{}.csi", params.bam)).exists();
+    if !index_exists {
+        problems.push(format!("no index for {}, run samtools index {}", params.bam, params.bam));
+    }
+    let mut reader = match noodles_bam::io::reader::Builder.build_from_path(&params.bam) {
+        Ok(reader) => reader,
+        Err(error) => {
+            problems.push(format!("could not open {} as a bam: {}", params.bam, error));
+            return None;
+        },
+    };
+    let header = match reader.read_header() {
+        Ok(header) => header,
+        Err(error) => {
+            problems.push(format!("could not read the header of {}: {}", params.bam, error));
+            return None;
+        },
+    };
+    let (mut num_reads, mut num_cb, mut num_cb_in_barcodes, mut num_umi) = (0, 0, 0, 0);
+    for record in reader.records().take(NUM_READ_TEST) {
+        let record = match record {
+            Ok(record) => record,
+            Err(error) => {
+                problems.push(format!("could not read records from {}: {}", params.bam, error));
+                break;
+            },
+        };
+        num_reads += 1;
+        if let Some(barcode) = bam::string_tag(&record, bam::CELL_TAG) {
+            num_cb += 1;
+            if barcodes.contains(&barcode) { num_cb_in_barcodes += 1; }
+        }
+        if bam::string_tag(&record, bam::UMI_TAG).is_some() { num_umi += 1; }
+    }
+    println!("of the first {} reads {} have a CB tag, {} have a CB in the barcodes file and {} have a UB tag", num_reads, num_cb, num_cb_in_barcodes, num_umi);
+    let fraction = |count: usize| (count as f64) / (num_reads.max(1) as f64);
+    if num_reads == 0 {
+        problems.push(format!("{} has no reads", params.bam));
+    } else {
+        if fraction(num_cb) <= 0.5 {
+            problems.push(format!("only {:.1}% of the first {} reads have a cell barcode tag (CB), is {} a cellranger bam?", 100.0 * fraction(num_cb), num_reads, params.bam));
+        }
+        if fraction(num_umi) <= 0.5 {
+            problems.push(format!("only {:.1}% of the first {} reads have a UMI tag (UB), is {} a cellranger bam?", 100.0 * fraction(num_umi), num_reads, params.bam));
+        }
+        if fraction(num_cb_in_barcodes) <= 0.05 {
+            problems.push(format!("only {:.1}% of the first {} reads have a cell barcode from {}, is this the barcodes.tsv for this bam (check the -1 suffixes)?",
+                100.0 * fraction(num_cb_in_barcodes), num_reads, params.barcodes));
+        }
+    }
+    return Some((bam::reference_lengths(&header), index_exists));
+}
+
+fn check_fasta_contigs(fasta: &str, bam_contigs: &Vec<(String, usize)>, problems: &mut Vec<String>) {
+    let fai = format!("{}.fai", fasta);
+    if !Path::new(&fai).exists() {
+        problems.push(format!("no index for {}, run samtools faidx {}", fasta, fasta));
+        return;
+    }
+    let mut fasta_lengths: HashMap<String, usize> = HashMap::new();
+    for line in reader(&fai).lines() {
+        let line = line.expect("Unable to read a line in the fasta index");
+        let toks: Vec<&str> = line.split('\t').collect();
+        if toks.len() < 2 { continue; }
+        fasta_lengths.insert(toks[0].to_string(), toks[1].parse::<usize>().unwrap_or(0));
+    }
+    let fasta_contigs: Vec<String> = fasta_lengths.keys().cloned().collect();
+    let mut missing: Vec<String> = Vec::new();
+    let mut wrong_length: Vec<String> = Vec::new();
+    for (chrom, length) in bam_contigs {
+        match fasta_lengths.get(chrom) {
+            Some(fasta_length) => if fasta_length != length { wrong_length.push(format!("{} ({} in bam, {} in fasta)", chrom, length, fasta_length)); },
+            None => missing.push(chrom.clone()),
+        }
+    }
+    if !missing.is_empty() {
+        problems.push(format!("{} of {} bam contigs are not in {} (eg {}). {}", missing.len(), bam_contigs.len(), fasta,
+            missing.iter().take(3).cloned().collect::<Vec<String>>().join(", "), chr_prefix_advice(&missing, &fasta_contigs, "fasta")));
+    }
+    if !wrong_length.is_empty() {
+        problems.push(format!("contig lengths differ between the bam and {} for {}, the fasta must be the reference the bam was aligned to",
+            fasta, wrong_length.iter().take(3).cloned().collect::<Vec<String>>().join(", ")));
+    }
+}
+
+fn check_vcf(params: &CheckParams, common_variants: &str, bam_contigs: &Vec<(String, usize)>, indexed: bool, barcodes: &HashSet<String>, problems: &mut Vec<String>) {
+    let (_header_lines, sites) = variants::load_sites(common_variants);
+    if sites.is_empty() {
+        problems.push(format!("{} has no variant records", common_variants));
+        return;
+    }
+    let bam_contig_set: HashSet<String> = bam_contigs.iter().map(|(chrom, _length)| chrom.clone()).collect();
+    let bam_contig_names: Vec<String> = bam_contigs.iter().map(|(chrom, _length)| chrom.clone()).collect();
+    let mut vcf_contigs: Vec<String> = Vec::new();
+    let mut sites_on_bam_contigs: Vec<(String, usize)> = Vec::new();
+    for site in &sites {
+        if !vcf_contigs.contains(&site.chrom) { vcf_contigs.push(site.chrom.clone()); }
+        if bam_contig_set.contains(&site.chrom) { sites_on_bam_contigs.push((site.chrom.clone(), site.pos)); }
+    }
+    let missing: Vec<String> = vcf_contigs.iter().filter(|chrom| !bam_contig_set.contains(*chrom)).cloned().collect();
+    if !missing.is_empty() {
+        // a few sites on unplaced or extra contigs are just skipped, but most of them missing means a reference mismatch
+        let num_sites_missing = sites.len() - sites_on_bam_contigs.len();
+        let message = format!("{} of {} sites in {} are on contigs that are not in the bam (eg {}). {}", num_sites_missing, sites.len(), common_variants,
+            missing.iter().take(3).cloned().collect::<Vec<String>>().join(", "), chr_prefix_advice(&missing, &bam_contig_names, "vcf"));
+        if num_sites_missing * 2 > sites.len() { problems.push(message); } else { println!("{}", message); }
+    }
+    if sites_on_bam_contigs.is_empty() { return; }
+    // the coverage queries need the index, its absence is already reported
+    if !indexed {
+        println!("skipping the vcf site coverage check, {} has no index", params.bam);
+        return;
+    }
+
+    // coverage of an evenly spaced sample of sites, counted the same way as cellector variants
+    let step = ((sites_on_bam_contigs.len() as f64) / (NUM_SITE_TEST as f64)).ceil().max(1.0) as usize;
+    let mut sampled: Vec<(String, usize)> = sites_on_bam_contigs.iter().step_by(step).cloned().collect();
+    sampled.sort();
+    sampled.dedup();
+    let mut sites_by_chrom: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    let mut region: Vec<(String, usize, usize)> = Vec::new();
+    for (index, (chrom, pos)) in sampled.iter().enumerate() {
+        sites_by_chrom.entry(chrom.clone()).or_insert(Vec::new()).push((*pos, index));
+        region.push((chrom.clone(), pos - 1, *pos));
+    }
+    let variants_params = VariantsParams {
+        bam: params.bam.clone(),
+        common_variants: common_variants.to_string(),
+        barcodes: Some(params.barcodes.clone()),
+        output_directory: String::new(),
+        threads: 1,
+        min_coverage: 1,
+        max_coverage: 100000,
+    };
+    let coverage = variants::region_coverage(&variants_params, &region, &sites_by_chrom, &Some(barcodes.clone()));
+    let num_covered = coverage.iter().filter(|(_site, depth)| *depth > 0).count();
+    let covered_fraction = (num_covered as f64) / (sampled.len() as f64);
+    println!("{:.1}% of {} sampled vcf sites have at least one cell barcoded read", 100.0 * covered_fraction, sampled.len());
+    if num_covered == 0 {
+        problems.push(format!("none of {} sampled sites in {} are covered by reads with a CB from {}, is the vcf for the same reference as the bam?",
+            sampled.len(), common_variants, params.barcodes));
+    }
+}
+
+// if adding or removing a chr prefix makes the missing contigs match, say so
+fn chr_prefix_advice(missing: &Vec<String>, contigs: &Vec<String>, source: &str) -> String {
+    let contig_set: HashSet<&String> = contigs.iter().collect();
+    let mut num_added = 0;
+    let mut num_removed = 0;
+    for chrom in missing {
+        if contig_set.contains(&format!("chr{}", chrom)) { num_added += 1; }
+        if chrom.starts_with("chr") && contig_set.contains(&chrom[3..].to_string()) { num_removed += 1; }
+    }
+    if num_added > 0 && num_added >= num_removed {
+        return format!("{} of them match with a chr prefix added, rename the {} contigs (eg 1 -> chr1)", num_added, source);
+    } else if num_removed > 0 {
+        return format!("{} of them match with the chr prefix removed, rename the {} contigs (eg chr1 -> 1)", num_removed, source);
+    }
+    return "Make sure everything is against the same reference".to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+    use std::fs::File;
+    use std::io::Write;
+    use noodles::sam;
+    use noodles::sam::alignment::io::Write as AlignmentWrite;
+    use noodles::sam::alignment::RecordBuf;
+    use noodles::sam::alignment::record::data::field::Tag;
+    use noodles::sam::alignment::record_buf::data::field::Value;
+    use noodles::sam::header::record::value::Map;
+    use noodles::sam::header::record::value::map::ReferenceSequence;
+
+    #[test]
+    fn unindexed_bam_is_reported_not_fatal() {
+        let directory = ::test_directory("check_unindexed");
+        let bam = format!("{}/reads.bam", directory);
+        let header = sam::Header::builder()
+            .add_reference_sequence("chr1", Map::<ReferenceSequence>::new(NonZeroUsize::new(1000).unwrap()))
+            .build();
+        let mut writer = noodles_bam::io::Writer::new(File::create(&bam).unwrap());
+        writer.write_header(&header).unwrap();
+        let mut barcodes = File::create(format!("{}/barcodes.tsv", directory)).unwrap();
+        for cell in 0..60 {
+            let barcode = format!("AAAC{:04}-1", cell);
+            writeln!(barcodes, "{}", barcode).unwrap();
+            let mut record = RecordBuf::default();
+            record.data_mut().insert(Tag::from(*bam::CELL_TAG), Value::from(barcode.as_str()));
+            record.data_mut().insert(Tag::from(*bam::UMI_TAG), Value::from("ACGTACGT"));
+            writer.write_alignment_record(&header, &record).unwrap();
+        }
+        writer.try_finish().unwrap();
+        let vcf = format!("{}/common.vcf", directory);
+        File::create(&vcf).unwrap().write_all(b"##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\nchr1\t100\t.\tA\tG\t.\t.\t.\n").unwrap();
+
+        let params = CheckParams {
+            bam: bam.clone(),
+            barcodes: format!("{}/barcodes.tsv", directory),
+            fasta: None,
+            common_variants: Some(vcf),
+            ignore: false,
+        };
+        let problems = find_problems(&params);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with(&format!("no index for {}", bam)));
+    }
+}
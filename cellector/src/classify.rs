@@ -0,0 +1,176 @@
+use std::io::{BufWriter, Write, BufRead};
+use hashbrown::{HashMap, HashSet};
+use File;
+use Params;
+use stats;
+use AlphaBeta;
+use CellLogLikelihoodData;
+use FinalResults;
+use load_data;
+use load_data::{reader, CellData, VcfLocusData};
+
+// the per locus beta binomial parameters of the minority, majority and doublet distributions the posteriors of a run
+// were calculated with, written to {output_directory}/cellector_model.tsv keyed by chrom, pos, ref and alt. `cellector
+// classify` loads it and computes the posteriors of the cells of another sample of the same individuals (eg a later
+// timepoint where the minority is too small to learn) at the loci they share, without the outlier iterations
+pub struct Model {
+    pub minority_fraction: f64,
+    pub loci: HashMap<(String, String, String, String), [f64; 6]>, // minority alpha, beta, majority alpha, beta, doublet alpha, beta
+}
+
+fn locus_key(locus: &VcfLocusData) -> (String, String, String, String) {
+    return (locus.chrom.clone(), locus.pos.clone(), locus.ref_allele.clone(), locus.alt_allele.clone());
+}
+
+// the distributions and minority fraction the posteriors of a run were calculated with, write_final_outputs writes it
+pub struct FittedModel {
+    pub minority_fraction: f64,
+    pub loci_used: Vec<bool>, // loci used for the posteriors, indexed by used locus index
+    pub distributions: [Vec<AlphaBeta>; 3], // minority, majority, doublet indexed by used locus index
+}
+
+pub fn write_model(params: &Params, vcf_data: &Vec<VcfLocusData>, locus_ids: &Vec<usize>, model: &FittedModel) {
+    let filename = format!("{}/cellector_model.tsv", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    writer.write_all(format!("# cellector model minority_fraction={}\n", model.minority_fraction).as_bytes()).expect("could not write model");
+    writer.write_all(b"chrom\tpos\tref\talt\tminority_alpha\tminority_beta\tmajority_alpha\tmajority_beta\tdoublet_alpha\tdoublet_beta\n").expect("could not write model");
+    for (locus_index, locus_id) in locus_ids.iter().enumerate() {
+        if !model.loci_used[locus_index] { continue; }
+        let locus = &vcf_data[*locus_id];
+        let mut line = format!("{}\t{}\t{}\t{}", locus.chrom, locus.pos, locus.ref_allele, locus.alt_allele);
+        for alpha_betas in model.distributions.iter() {
+            line.push_str(&format!("\t{}\t{}", alpha_betas[locus_index].alpha, alpha_betas[locus_index].beta));
+        }
+        line.push('\n');
+        writer.write_all(line.as_bytes()).expect("could not write model");
+    }
+}
+
+pub fn load_model(filename: &str) -> Model {
+    let mut minority_fraction: Option<f64> = None;
+    let mut loci: HashMap<(String, String, String, String), [f64; 6]> = HashMap::new();
+    for line in reader(filename).lines() {
+        let line = line.expect(&format!("Unable to read a line in {}", filename));
+        if line.starts_with('#') {
+            if let Some(index) = line.find("minority_fraction=") {
+                minority_fraction = line[index + "minority_fraction=".len()..].trim().parse::<f64>().ok();
+            }
+            continue;
+        }
+        if line.starts_with("chrom\t") { continue; }
+        let toks: Vec<&str> = line.trim_end().split('\t').collect();
+        assert!(toks.len() == 10, "Invalid line in model {}: {}", filename, line);
+        let mut alpha_betas = [0.0; 6];
+        for (index, value) in toks[4..].iter().enumerate() {
+            alpha_betas[index] = value.parse::<f64>().expect(&format!("could not parse {} in model {}", value, filename));
+        }
+        loci.insert((toks[0].to_string(), toks[1].to_string(), toks[2].to_string(), toks[3].to_string()), alpha_betas);
+    }
+    return Model {
+        minority_fraction: minority_fraction.expect(&format!("{} has no minority_fraction header line, is it a cellector_model.tsv?", filename)),
+        loci: loci,
+    };
+}
+
+pub fn classify(params: &Params) {
+    load_data::create_output_dir(&params.output_directory);
+    let model = load_model(params.model.as_ref().unwrap());
+    classify_sample(params, &model);
+}
+
+// posteriors of the cells of one sample under the model, writes the usual outputs to params.output_directory
+pub fn classify_sample(params: &Params, model: &Model) -> (Vec<CellData>, FinalResults) {
+    let (cell_id_to_barcode, barcode_to_cell_id, vcf_data, mut counts, _channel_cells) = ::load_inputs(params, &None);
+    let cell_id_to_assignment = load_data::load_ground_truth(params, &barcode_to_cell_id);
+    let cell_data = load_data::init_cell_data(params, counts.total_cells, &cell_id_to_barcode, &cell_id_to_assignment);
+    let vcf_loci = vcf_data.as_ref().unwrap();
+    assert!(vcf_loci.len() == counts.total_loci, "the matrices have {} loci but --vcf has {} records", counts.total_loci, vcf_loci.len());
+
+    let model_loci: Vec<Option<[f64; 6]>> = vcf_loci.iter().map(|locus| model.loci.get(&locus_key(locus)).cloned()).collect();
+    let in_model: Vec<bool> = model_loci.iter().map(|alpha_betas| alpha_betas.is_some()).collect();
+    let locus_ids = counts.add_cell_view(&in_model);
+    println!("{} of the {} loci in the model and {} in --vcf are shared", locus_ids.len(), model.loci.len(), vcf_loci.len());
+    assert!(!locus_ids.is_empty(), "no locus of --vcf is in the model, was it made from the same variants?");
+    let distribution = |offset: usize| -> Vec<AlphaBeta> {
+        locus_ids.iter().map(|locus_id| {
+            let alpha_betas = model_loci[*locus_id].unwrap();
+            AlphaBeta{alpha: alpha_betas[offset], beta: alpha_betas[offset + 1]}
+        }).collect()
+    };
+    let (alpha_betas_minority_dist, alpha_betas_majority_dist, alpha_betas_doublet_dist) = (distribution(0), distribution(2), distribution(4));
+    // the minority prior is the fraction the model was learned at unless --expected_percent_minority gives this sample's
+    let minority_fraction = match params.expected_percent_minority {
+        Some(percent) => percent / 100.0,
+        None => model.minority_fraction,
+    };
+
+    let precomputed_log_binomial_coefficients = stats::precompute_log_binomial_coefficients(100);
+    let loci_used: Vec<bool> = vec![true; locus_ids.len()];
+    let no_cells: HashSet<usize> = HashSet::new();
+    let minority_dist_likelihoods: CellLogLikelihoodData = ::get_cell_log_likelihoods(&loci_used, &locus_ids, &counts, &alpha_betas_minority_dist, &no_cells, &precomputed_log_binomial_coefficients);
+    let majority_dist_likelihoods: CellLogLikelihoodData = ::get_cell_log_likelihoods(&loci_used, &locus_ids, &counts, &alpha_betas_majority_dist, &no_cells, &precomputed_log_binomial_coefficients);
+    let doublet_dist_likelihoods: CellLogLikelihoodData = ::get_cell_log_likelihoods(&loci_used, &locus_ids, &counts, &alpha_betas_doublet_dist, &no_cells, &precomputed_log_binomial_coefficients);
+    let (posteriors, doublet_posteriors, log_likelihoods) = ::combine_posteriors(minority_fraction, &minority_dist_likelihoods.log_likelihoods,
+        &majority_dist_likelihoods.log_likelihoods, &doublet_dist_likelihoods.log_likelihoods);
+
+    // there are no outlier iterations, the anomaly column is the minority call and the normalized log likelihood is
+    // that of the majority distribution
+    let excluded_cells: HashSet<usize> = (0..cell_data.len()).filter(|cell_id| posteriors[*cell_id] > params.posterior_threshold).collect();
+    let loci_used_per_cell = majority_dist_likelihoods.loci_used_per_cell;
+    let majority_log_likelihoods = majority_dist_likelihoods.log_likelihoods;
+    let normalized_log_likelihoods: Vec<f64> = (0..cell_data.len()).map(|cell_id| {
+        if loci_used_per_cell[cell_id] > 0.0 { majority_log_likelihoods[cell_id] / loci_used_per_cell[cell_id] } else { 0.0 }
+    }).collect();
+    println!("{} of {} cells have a minority posterior above {}", excluded_cells.len(), cell_data.len(), params.posterior_threshold);
+    let locus_alleles = load_data::load_mtx_final(&excluded_cells, &counts);
+    let results = FinalResults {
+        excluded_cells: excluded_cells,
+        posteriors: posteriors,
+        doublet_posteriors: doublet_posteriors,
+        log_likelihoods: log_likelihoods,
+        normalized_log_likelihoods: normalized_log_likelihoods,
+        loci_used_per_cell: loci_used_per_cell,
+        cell_num_loci: (0..cell_data.len()).map(|cell_id| counts.cell_num_loci(cell_id)).collect(),
+        locus_alleles: locus_alleles,
+        iteration_summaries: Vec::new(),
+        num_loci_passing: locus_ids.len(),
+        locus_bootstrap_interval: None,
+        em_iterations: Vec::new(),
+        model: None,
+    };
+    ::write_final_outputs(params, &cell_data, &loci_used, &locus_ids, &vcf_data, None, &results);
+    return (cell_data, results);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // unused loci are left out and every parameter reads back exactly
+    #[test]
+    fn model_write_load_round_trip() {
+        let vcf_data: Vec<VcfLocusData> = (0..4).map(|index| VcfLocusData { locus_index: index, chrom: "chr1".to_string(), pos: (100 * (index + 1)).to_string(),
+            ref_allele: "A".to_string(), alt_allele: "G".to_string() }).collect();
+        let locus_ids: Vec<usize> = vec![0, 2, 3];
+        let loci_used: Vec<bool> = vec![true, false, true];
+        let distribution = |offset: f64| -> Vec<AlphaBeta> { (0..3).map(|index| AlphaBeta { alpha: offset + 1.0 / (index as f64 + 3.0), beta: offset * 0.1 + index as f64 }).collect() };
+        let fitted = FittedModel { minority_fraction: 0.123456789, loci_used: loci_used.clone(), distributions: [distribution(1.0), distribution(20.5), distribution(7.25)] };
+        let (minority, majority, doublet) = (&fitted.distributions[0], &fitted.distributions[1], &fitted.distributions[2]);
+        let params = Params { output_directory: ::test_directory("model_round_trip"), ..Default::default() };
+        write_model(&params, &vcf_data, &locus_ids, &fitted);
+        let model = load_model(&format!("{}/cellector_model.tsv", params.output_directory));
+        assert_eq!(model.minority_fraction, 0.123456789);
+        assert_eq!(model.loci.len(), 2);
+        for (locus_index, locus_id) in locus_ids.iter().enumerate() {
+            let loaded = model.loci.get(&locus_key(&vcf_data[*locus_id]));
+            if !loci_used[locus_index] {
+                assert!(loaded.is_none());
+                continue;
+            }
+            let expected = [minority[locus_index].alpha, minority[locus_index].beta, majority[locus_index].alpha, majority[locus_index].beta,
+                doublet[locus_index].alpha, doublet[locus_index].beta];
+            assert_eq!(loaded.unwrap(), &expected);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use clap::ArgMatches;
+use std::io::{BufWriter, Write, BufRead};
+use hashbrown::HashMap;
+use File;
+use load_data::reader;
+
+// compares cellector_assignments.tsv with souporcell/troublet clusters barcode by barcode and writes the merged
+// {output_directory}/final_output.tsv and {output_directory}/method_comparison.tsv
+pub struct CompareParams {
+    pub cellector_assignments: String,
+    pub troublet: String,
+    pub output_directory: String,
+    pub program_preference: String,
+}
+
+pub fn load_compare_params(params: &ArgMatches) -> CompareParams {
+    let cellector_assignments = params.value_of("cellector_assignments").unwrap().to_string();
+    let troublet = params.value_of("troublet").unwrap().to_string();
+    let output_directory = params.value_of("output_directory").unwrap().to_string();
+    let program_preference = params.value_of("program_preference").unwrap_or("auto").to_string();
+    return CompareParams {
+        cellector_assignments: cellector_assignments,
+        troublet: troublet,
+        output_directory: output_directory,
+        program_preference: program_preference,
+    };
+}
+
+// tsv with a header line, rows keyed by the barcode in the first column
+struct Table {
+    header: Vec<String>,
+    barcodes: Vec<String>,
+    rows: HashMap<String, Vec<String>>,
+}
+
+// per barcode call of one method. label is "0" or "1" for cells assigned to a cluster, otherwise doublet/unassigned/etc.
+// score is the log likelihood of the first cluster over the mean log likelihood of both clusters
+struct Call {
+    label: String,
+    score: Option<f64>,
+}
+
+fn load_table(filename: &str) -> Table {
+    let mut header: Vec<String> = Vec::new();
+    let mut barcodes: Vec<String> = Vec::new();
+    let mut rows: HashMap<String, Vec<String>> = HashMap::new();
+    for (index, line) in reader(filename).lines().enumerate() {
+        let line = line.expect(&format!("Unable to read a line in {}", filename));
+        let toks: Vec<String> = line.trim_end().split('\t').map(|tok| tok.to_string()).collect();
+        if index == 0 {
+            header = toks;
+            continue;
+        }
+        if toks.len() != header.len() { continue; } // troublet logging lines
+        barcodes.push(toks[0].clone());
+        rows.insert(toks[0].clone(), toks);
+    }
+    assert!(header.len() > 1, "{} has no header line", filename);
+    return Table { header: header, barcodes: barcodes, rows: rows };
+}
+
+fn column(table: &Table, name: &str, filename: &str) -> usize {
+    return table.header.iter().position(|column| column == name).expect(&format!("{} has no {} column", filename, name));
+}
+
+fn score(first_log_likelihood: &str, second_log_likelihood: &str) -> Option<f64> {
+    let first = first_log_likelihood.parse::<f64>().ok()?;
+    let second = second_log_likelihood.parse::<f64>().ok()?;
+    if !first.is_finite() || !second.is_finite() || first + second == 0.0 { return None; }
+    return Some(first / ((first + second) / 2.0));
+}
+
+fn cellector_calls(table: &Table, filename: &str) -> HashMap<String, Call> {
+    let assignment = column(table, "posterior_assignment", filename);
+    let majority = column(table, "majority_log_likelihood", filename);
+    let minority = column(table, "minority_log_likelihood", filename);
+    let mut calls: HashMap<String, Call> = HashMap::new();
+    for (barcode, row) in &table.rows {
+        calls.insert(barcode.clone(), Call { label: row[assignment].clone(), score: score(&row[majority], &row[minority]) });
+    }
+    return calls;
+}
+
+// troublet has barcode, status, assignment, log_prob_singleton, log_prob_doublet, then one log probability column per cluster
+fn souporcell_calls(table: &Table, filename: &str) -> HashMap<String, Call> {
+    let status = column(table, "status", filename);
+    let assignment = column(table, "assignment", filename);
+    let cluster_0 = table.header.iter().position(|column| column == "cluster0").unwrap_or(table.header.len() - 2);
+    let cluster_1 = table.header.iter().position(|column| column == "cluster1").unwrap_or(table.header.len() - 1);
+    let mut calls: HashMap<String, Call> = HashMap::new();
+    for (barcode, row) in &table.rows {
+        let label = if row[status] == "singlet" { row[assignment].clone() } else { row[status].clone() };
+        calls.insert(barcode.clone(), Call { label: label, score: score(&row[cluster_0], &row[cluster_1]) });
+    }
+    return calls;
+}
+
+// separation between the two clusters of a method: the difference in mean score between cells assigned to cluster 0 and
+// cells assigned to cluster 1, divided by the pooled standard deviation (d'). the score is scale free so the two methods
+// are comparable, larger means the clusters are further apart relative to the noise within them
+pub fn separation(scores_0: &Vec<f64>, scores_1: &Vec<f64>) -> f64 {
+    if scores_0.len() < 2 || scores_1.len() < 2 { return 0.0; }
+    let mean = |values: &Vec<f64>| values.iter().sum::<f64>() / (values.len() as f64);
+    let (mean_0, mean_1) = (mean(scores_0), mean(scores_1));
+    let sum_squares = |values: &Vec<f64>, mean: f64| values.iter().map(|value| (value - mean).powi(2)).sum::<f64>();
+    let pooled_variance = (sum_squares(scores_0, mean_0) + sum_squares(scores_1, mean_1)) / ((scores_0.len() + scores_1.len() - 2) as f64);
+    if pooled_variance <= 0.0 { return if mean_0 == mean_1 { 0.0 } else { f64::INFINITY }; }
+    return (mean_0 - mean_1).abs() / pooled_variance.sqrt();
+}
+
+fn method_separation(calls: &HashMap<String, Call>) -> f64 {
+    let mut scores_0: Vec<f64> = Vec::new();
+    let mut scores_1: Vec<f64> = Vec::new();
+    for call in calls.values() {
+        if let Some(score) = call.score {
+            if call.label == "0" { scores_0.push(score); } else if call.label == "1" { scores_1.push(score); }
+        }
+    }
+    return separation(&scores_0, &scores_1);
+}
+
+fn is_cluster(label: &str) -> bool {
+    return label == "0" || label == "1";
+}
+
+fn swap_label(label: &str) -> String {
+    return match label {
+        "0" => "1".to_string(),
+        "1" => "0".to_string(),
+        other => other.to_string(),
+    };
+}
+
+// souporcell cluster numbers are arbitrary, swap them if that agrees with cellector (0 minority, 1 majority) for more cells
+fn labels_swapped(cellector: &HashMap<String, Call>, souporcell: &HashMap<String, Call>) -> bool {
+    let mut same = 0;
+    let mut swapped = 0;
+    for (barcode, cellector_call) in cellector {
+        if let Some(souporcell_call) = souporcell.get(barcode) {
+            if !is_cluster(&cellector_call.label) || !is_cluster(&souporcell_call.label) { continue; }
+            if cellector_call.label == souporcell_call.label { same += 1; } else { swapped += 1; }
+        }
+    }
+    return swapped > same;
+}
+
+pub fn compare_methods(params: &CompareParams) {
+    let cellector_table = load_table(&params.cellector_assignments);
+    let souporcell_table = load_table(&params.troublet);
+    let cellector = cellector_calls(&cellector_table, &params.cellector_assignments);
+    let mut souporcell = souporcell_calls(&souporcell_table, &params.troublet);
+
+    let cellector_separation = method_separation(&cellector);
+    let souporcell_separation = method_separation(&souporcell);
+    let swapped = labels_swapped(&cellector, &souporcell);
+    if swapped {
+        for call in souporcell.values_mut() { call.label = swap_label(&call.label); }
+    }
+    let preference = match params.program_preference.as_str() {
+        "auto" => if cellector_separation > souporcell_separation { "cellector" } else { "souporcell" },
+        preference => preference,
+    };
+    println!("cellector separation {:.3}, souporcell separation {:.3}, souporcell clusters swapped {}, prefering the output of {}",
+        cellector_separation, souporcell_separation, swapped, preference);
+
+    let mut barcodes: Vec<String> = cellector_table.barcodes.clone();
+    for barcode in &souporcell_table.barcodes {
+        if !cellector.contains_key(barcode) { barcodes.push(barcode.clone()); }
+    }
+    let filename = format!("{}/final_output.tsv", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    let mut header: Vec<String> = ["barcode", "assignment", "cellector_assignment", "souporcell_assignment", "souporcell_cluster", "agreement"]
+        .iter().map(|column| column.to_string()).collect();
+    for column in &cellector_table.header[1..] { header.push(format!("cellector_{}", column)); }
+    for column in &souporcell_table.header[1..] { header.push(format!("souporcell_{}", column)); }
+    writer.write_all(format!("{}\n", header.join("\t")).as_bytes()).expect("could not write final output");
+    let (mut num_compared, mut num_agree) = (0, 0);
+    for barcode in &barcodes {
+        let cellector_label = cellector.get(barcode).map(|call| call.label.clone()).unwrap_or("na".to_string());
+        let souporcell_label = souporcell.get(barcode).map(|call| call.label.clone()).unwrap_or("na".to_string());
+        let souporcell_cluster = match souporcell_table.rows.get(barcode) {
+            Some(row) => row[column(&souporcell_table, "assignment", &params.troublet)].clone(),
+            None => "na".to_string(),
+        };
+        let agreement = if is_cluster(&cellector_label) && is_cluster(&souporcell_label) {
+            num_compared += 1;
+            if cellector_label == souporcell_label { num_agree += 1; "agree" } else { "disagree" }
+        } else { "na" };
+        let assignment = if preference == "cellector" { &cellector_label } else { &souporcell_label };
+        let mut row: Vec<String> = vec![barcode.clone(), assignment.clone(), cellector_label.clone(), souporcell_label.clone(), souporcell_cluster, agreement.to_string()];
+        match cellector_table.rows.get(barcode) {
+            Some(cellector_row) => row.extend(cellector_row[1..].iter().cloned()),
+            None => row.extend(vec!["na".to_string(); cellector_table.header.len() - 1]),
+        }
+        match souporcell_table.rows.get(barcode) {
+            Some(souporcell_row) => row.extend(souporcell_row[1..].iter().cloned()),
+            None => row.extend(vec!["na".to_string(); souporcell_table.header.len() - 1]),
+        }
+        writer.write_all(format!("{}\n", row.join("\t")).as_bytes()).expect("could not write final output");
+    }
+    println!("cellector and souporcell agree on {} of {} cells assigned to a cluster by both", num_agree, num_compared);
+
+    let filename = format!("{}/method_comparison.tsv", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    let summary = format!("statistic\tvalue\ncellector_separation\t{}\nsouporcell_separation\t{}\nsouporcell_clusters_swapped\t{}\npreferred\t{}\ncells_compared\t{}\ncells_agree\t{}\n",
+        cellector_separation, souporcell_separation, swapped, preference, num_compared, num_agree);
+    writer.write_all(summary.as_bytes()).expect("could not write method comparison");
+}
@@ -0,0 +1,215 @@
+use noodles::fasta;
+use rayon::prelude::*;
+use std::io::{BufWriter, Write};
+use hashbrown::HashMap;
+use File;
+use Params;
+use bam;
+use load_data::{AlleleMatrix, VartrixDatum, VcfLocusData};
+
+// per cell ref and alt umi counts at each vcf record, the built in replacement for vartrix --umi --scoring-method coverage.
+// only snvs are counted, other records keep their locus index but get no counts.
+// reads are skipped if unmapped, secondary, supplementary, qc fail or duplicate, below min_mapq, or missing a UB tag or a CB
+// tag from the barcodes file. each umi is counted once for the allele supported by most of its reads (ties are dropped).
+// records whose ref allele differs from the fasta, or whose contig is not in the fasta, are skipped and counted
+pub fn count_alleles(params: &Params, vcf_data: &Vec<VcfLocusData>, barcode_to_cell_id: &HashMap<String, usize>) -> AlleleMatrix {
+    let bam_file = params.bam.as_ref().unwrap();
+    let mut sites_by_chrom: HashMap<String, Vec<(usize, usize)>> = HashMap::new(); // chrom to sorted (pos, locus index)
+    let mut num_not_snv = 0;
+    for locus in vcf_data {
+        if locus.ref_allele.len() != 1 || locus.alt_allele.len() != 1 {
+            num_not_snv += 1;
+            continue;
+        }
+        let pos = locus.pos.parse::<usize>().unwrap();
+        sites_by_chrom.entry(locus.chrom.clone()).or_insert(Vec::new()).push((pos, locus.locus_index));
+    }
+    for chrom_sites in sites_by_chrom.values_mut() { chrom_sites.sort(); }
+
+    let (_reader, header) = bam::open_bam(bam_file);
+    let regions = bam::get_bam_regions(&header, params.threads);
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(params.threads).build().expect("could not build thread pool");
+    let region_counts: Vec<(Vec<VartrixDatum>, usize, usize)> = pool.install(|| {
+        regions.par_iter().map(|region| count_region(params, region, &sites_by_chrom, vcf_data, barcode_to_cell_id)).collect()
+    });
+    let mut entries: Vec<VartrixDatum> = Vec::new();
+    let (mut num_ref_mismatch, mut num_not_in_fasta) = (0, 0);
+    for (region_entries, region_ref_mismatch, region_not_in_fasta) in region_counts {
+        entries.extend(region_entries);
+        num_ref_mismatch += region_ref_mismatch;
+        num_not_in_fasta += region_not_in_fasta;
+    }
+    entries.sort_by_key(|entry| (entry.locus, entry.cell_id));
+    println!("counted alleles at {} loci in {} cells, skipped {} records that are not snvs, {} whose ref allele does not match the fasta and {} on contigs that are not in the fasta",
+        vcf_data.len(), barcode_to_cell_id.len(), num_not_snv, num_ref_mismatch, num_not_in_fasta);
+    if num_not_in_fasta > 0 {
+        eprintln!("WARNING: {} vcf records are on contigs missing from {}, check that the fasta and vcf use the same contig names (eg chr1 vs 1), cellector check reports mismatches",
+            num_not_in_fasta, params.fasta.as_ref().unwrap());
+    }
+    return AlleleMatrix {
+        total_loci: vcf_data.len(),
+        total_cells: barcode_to_cell_id.len(),
+        entries: entries,
+    };
+}
+
+// the entries of the region and the number of its sites skipped for a ref mismatch and for a contig missing from the fasta
+fn count_region(params: &Params, region: &Vec<(String, usize, usize)>, sites_by_chrom: &HashMap<String, Vec<(usize, usize)>>, vcf_data: &Vec<VcfLocusData>, barcode_to_cell_id: &HashMap<String, usize>) -> (Vec<VartrixDatum>, usize, usize) {
+    let (mut reader, header) = bam::open_bam(params.bam.as_ref().unwrap());
+    let fasta_file = params.fasta.as_ref().unwrap();
+    let mut fasta_reader = fasta::io::indexed_reader::Builder::default().build_from_path(fasta_file)
+        .expect(&format!("couldn't open fasta {}, it needs an index (.fai)", fasta_file));
+    let mut entries: Vec<VartrixDatum> = Vec::new();
+    let (mut num_ref_mismatch, mut num_not_in_fasta) = (0, 0);
+    for (chrom, start, end) in region {
+        let chrom_sites = match sites_by_chrom.get(chrom) {
+            Some(chrom_sites) => chrom_sites,
+            None => continue,
+        };
+        let first = chrom_sites.partition_point(|&(pos, _)| pos <= *start);
+        let last = chrom_sites.partition_point(|&(pos, _)| pos <= *end);
+        // (pos, locus index, ref base, alt base) for sites whose ref allele matches the reference
+        let mut region_sites: Vec<(usize, usize, u8, u8)> = Vec::new();
+        for &(pos, locus_index) in &chrom_sites[first..last] {
+            let locus = &vcf_data[locus_index];
+            let ref_base = locus.ref_allele.as_bytes()[0].to_ascii_uppercase();
+            let alt_base = locus.alt_allele.as_bytes()[0].to_ascii_uppercase();
+            let fasta_record = match fasta_reader.query(&bam::query_region(chrom, pos - 1, pos)) {
+                Ok(fasta_record) => fasta_record,
+                Err(_) => {
+                    num_not_in_fasta += 1;
+                    continue;
+                },
+            };
+            let fasta_base = fasta_record.sequence().as_ref().first().map(|base| base.to_ascii_uppercase());
+            if fasta_base != Some(ref_base) {
+                num_ref_mismatch += 1;
+                continue;
+            }
+            region_sites.push((pos, locus_index, ref_base, alt_base));
+        }
+        if region_sites.is_empty() { continue; }
+
+        let mut umi_votes: HashMap<(usize, usize, String), [usize; 2]> = HashMap::new(); // (locus, cell, umi) to [ref reads, alt reads]
+        let query = reader.query(&header, &bam::query_region(chrom, *start, *end)).expect("could not query bam");
+        for record in query.records() {
+            let record = record.expect("could not read bam record");
+            if !bam::is_primary_usable(&record) || bam::mapping_quality(&record) < params.min_mapq { continue; }
+            let cell_id = match bam::string_tag(&record, bam::CELL_TAG) {
+                Some(barcode) => match barcode_to_cell_id.get(&barcode) {
+                    Some(cell_id) => *cell_id,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let umi = match bam::string_tag(&record, bam::UMI_TAG) {
+                Some(umi) => umi,
+                None => continue,
+            };
+            let (read_start, read_end) = match bam::alignment_span(&record) {
+                Some(span) => span,
+                None => continue,
+            };
+            let sequence = record.sequence();
+            let quality_scores = record.quality_scores();
+            let quality_scores = quality_scores.as_bytes();
+            let from = region_sites.partition_point(|&(pos, _, _, _)| pos < read_start);
+            for &(pos, locus_index, ref_base, alt_base) in &region_sites[from..] {
+                if pos > read_end { break; }
+                let offset = match bam::read_offset_at(&record, pos) {
+                    Some(offset) => offset,
+                    None => continue,
+                };
+                if quality_scores.len() > offset && quality_scores[offset] < params.min_base_quality { continue; }
+                let base = match sequence.get(offset) {
+                    Some(base) => base.to_ascii_uppercase(),
+                    None => continue,
+                };
+                let allele = if base == ref_base { 0 } else if base == alt_base { 1 } else { continue };
+                umi_votes.entry((locus_index, cell_id, umi.clone())).or_insert([0; 2])[allele] += 1;
+            }
+        }
+
+        let mut counts: HashMap<(usize, usize), [usize; 2]> = HashMap::new();
+        for ((locus_index, cell_id, _umi), votes) in umi_votes {
+            if votes[0] == votes[1] { continue; }
+            let allele = if votes[0] > votes[1] { 0 } else { 1 };
+            counts.entry((locus_index, cell_id)).or_insert([0; 2])[allele] += 1;
+        }
+        for ((locus_index, cell_id), count) in counts {
+            entries.push(VartrixDatum{
+                locus: locus_index,
+                cell_id: cell_id,
+                ref_count: count[0],
+                alt_count: count[1],
+            });
+        }
+    }
+    return (entries, num_ref_mismatch, num_not_in_fasta);
+}
+
+// writes alt.mtx and ref.mtx in the same format as vartrix (1-based locus x cell coordinates)
+pub fn write_matrices(params: &Params, allele_matrix: &AlleleMatrix) {
+    for (filename, is_alt) in [(&params.alt_mtx, true), (&params.ref_mtx, false)].iter() {
+        let filehandle = File::create(filename).expect(&format!("Unable to create file {}", filename));
+        let mut writer = BufWriter::new(filehandle);
+        let header = format!("%%MatrixMarket matrix coordinate integer general\n% written by cellector\n{} {} {}\n",
+            allele_matrix.total_loci, allele_matrix.total_cells, allele_matrix.entries.len());
+        writer.write_all(header.as_bytes()).expect("could not write matrix");
+        for entry in &allele_matrix.entries {
+            let count = if *is_alt { entry.alt_count } else { entry.ref_count };
+            writer.write_all(format!("{} {} {}\n", entry.locus + 1, entry.cell_id + 1, count).as_bytes()).expect("could not write matrix");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::bam as noodles_bam;
+    use noodles::sam;
+    use noodles::sam::alignment::io::Write as AlignmentWrite;
+    use std::convert::TryFrom;
+
+    // chr1 is in the bam and the fasta, chr2 only in the bam. every read carries the alt allele at each snv
+    #[test]
+    fn ref_mismatch_and_missing_contig_sites_are_skipped() {
+        let directory = ::test_directory("count_ref_mismatch");
+        let reference: String = "ACGT".repeat(25);
+        let fasta = format!("{}/ref.fa", directory);
+        File::create(&fasta).unwrap().write_all(format!(">chr1\n{}\n", reference).as_bytes()).unwrap();
+        File::create(format!("{}.fai", fasta)).unwrap().write_all(b"chr1\t100\t6\t100\t101\n").unwrap();
+
+        let bam = format!("{}/reads.bam", directory);
+        let header: sam::Header = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100\n@SQ\tSN:chr2\tLN:100\n".parse().unwrap();
+        let mut writer = noodles_bam::io::Writer::new(File::create(&bam).unwrap());
+        writer.write_header(&header).unwrap();
+        let mut read: Vec<u8> = reference.as_bytes()[..50].to_vec();
+        read[9] = b'G';
+        read[19] = b'G';
+        let read = String::from_utf8(read).unwrap();
+        let mut barcode_to_cell_id: HashMap<String, usize> = HashMap::new();
+        for (chrom, cell_id) in [("chr1", 0), ("chr1", 1), ("chr1", 2), ("chr2", 0)].iter() {
+            let barcode = format!("CELL{}-1", cell_id);
+            barcode_to_cell_id.insert(barcode.clone(), *cell_id);
+            let line = format!("read{}{}\t0\t{}\t1\t60\t50M\t*\t0\t0\t{}\t{}\tCB:Z:{}\tUB:Z:UMI{}", chrom, cell_id, chrom, read, "I".repeat(50), barcode, cell_id);
+            let record = sam::Record::try_from(line.as_bytes()).unwrap();
+            writer.write_alignment_record(&header, &record).unwrap();
+        }
+        writer.try_finish().unwrap();
+        drop(writer);
+        match noodles_bam::fs::index(&bam).unwrap() {
+            noodles_bam::Index::Bai(index) => noodles_bam::bai::fs::write(format!("{}.bai", bam), &index).unwrap(),
+            noodles_bam::Index::Csi(_index) => panic!("expected a bai index for a small reference"),
+        }
+
+        let locus = |locus_index: usize, chrom: &str, pos: &str, ref_allele: &str, alt_allele: &str| VcfLocusData {
+            locus_index: locus_index, chrom: chrom.to_string(), pos: pos.to_string(), ref_allele: ref_allele.to_string(), alt_allele: alt_allele.to_string() };
+        // ref matches, ref is T in the fasta, contig not in the fasta, not an snv
+        let vcf_data = vec![locus(0, "chr1", "10", "C", "G"), locus(1, "chr1", "20", "A", "G"), locus(2, "chr2", "10", "C", "G"), locus(3, "chr1", "30", "AT", "A")];
+        let params = Params { bam: Some(bam), fasta: Some(fasta), threads: 1, ..Default::default() };
+        let counted = count_alleles(&params, &vcf_data, &barcode_to_cell_id);
+        let entries: Vec<(usize, usize, usize, usize)> = counted.entries.iter().map(|entry| (entry.locus, entry.cell_id, entry.ref_count, entry.alt_count)).collect();
+        assert_eq!(entries, vec![(0, 0, 0, 1), (0, 1, 0, 1), (0, 2, 0, 1)]);
+    }
+}
@@ -0,0 +1,157 @@
+use std::io::{BufWriter, Write};
+use hashbrown::HashSet;
+use File;
+use Params;
+use stats;
+use classify::FittedModel;
+use AlphaBeta;
+use CellLogLikelihoodData;
+use load_data::CountStore;
+
+// --fit_mode em, a minority/majority/doublet beta binomial mixture fit by expectation maximization over all cells
+// with soft responsibilities instead of the hard exclusion of the iqr iterations. it starts from the posteriors of the
+// iqr solution. each m step estimates the three locus distributions from every cell's umis weighted by the cell's
+// responsibility for that component, and the mixing weights as the mean responsibilities, then each e step recomputes
+// the responsibilities, until the total log likelihood per cell changes by less than em_tolerance
+pub struct EmIteration {
+    pub log_likelihood: f64,
+    pub minority_fraction: f64,
+    pub doublet_fraction: f64,
+    pub num_minority: usize, // cells with a minority posterior above posterior_threshold
+}
+
+pub struct MixtureFit {
+    pub posteriors: Vec<f64>,
+    pub doublet_posteriors: Vec<f64>,
+    pub log_likelihoods: Vec<(f64, f64)>, // (majority, minority)
+    pub excluded_cells: HashSet<usize>, // cells with a minority posterior above posterior_threshold, for the anomaly column and the vcf groups
+    pub iterations: Vec<EmIteration>,
+    pub locus_bootstrap_interval: Option<(f64, f64)>,
+    pub model: FittedModel,
+}
+
+// beta binomial parameters per used locus from every cell's umis weighted by weights[cell_id], with the +1 pseudocounts of init_alpha_betas
+fn weighted_alpha_betas(counts: &CountStore, num_loci: usize, weights: &Vec<f64>) -> Vec<AlphaBeta> {
+    let mut alpha_betas: Vec<AlphaBeta> = (0..num_loci).map(|_| AlphaBeta{alpha: 1.0, beta: 1.0}).collect();
+    for (cell_id, &weight) in weights.iter().enumerate() {
+        if weight == 0.0 { continue; }
+        for (locus_index, ref_count, alt_count) in counts.cell_entries(cell_id) {
+            alpha_betas[locus_index].alpha += weight * alt_count as f64;
+            alpha_betas[locus_index].beta += weight * ref_count as f64;
+        }
+    }
+    return alpha_betas;
+}
+
+// log of the mixture likelihood summed over cells, log_priors are the (minority, majority, doublet) log mixing weights
+fn mixture_log_likelihood(log_priors: (f64, f64, f64), minority_log_likelihoods: &Vec<f64>, majority_log_likelihoods: &Vec<f64>, doublet_log_likelihoods: &Vec<f64>) -> f64 {
+    let (log_prior_minority, log_prior_majority, log_prior_doublet) = log_priors;
+    let mut total = 0.0;
+    for cell_id in 0..minority_log_likelihoods.len() {
+        let singlet = stats::logsumexp(&[log_prior_minority + minority_log_likelihoods[cell_id], log_prior_majority + majority_log_likelihoods[cell_id]]);
+        total += stats::logsumexp(&[singlet, log_prior_doublet + doublet_log_likelihoods[cell_id]]);
+    }
+    return total;
+}
+
+pub fn fit_mixture(params: &Params, loci_used: &Vec<bool>, locus_ids: &Vec<usize>, counts: &CountStore, locus_counts: &Vec<[f64; 2]>, iqr_excluded_cells: &HashSet<usize>,
+        precomputed_log_binomial_coefficients: &Vec<Vec<f64>>, mut posteriors: Vec<f64>, mut doublet_posteriors: Vec<f64>) -> MixtureFit {
+    let num_cells = counts.total_cells;
+    let loci_used_for_posteriors = ::get_loci_used_for_posterior_calc(params, loci_used, &::excluded_locus_counts(counts, loci_used.len(), iqr_excluded_cells), locus_counts);
+    let no_cells: HashSet<usize> = HashSet::new();
+    let mut log_likelihoods: Vec<(f64, f64)> = Vec::new();
+    let mut iterations: Vec<EmIteration> = Vec::new();
+    let mut final_fit: Option<(f64, [Vec<AlphaBeta>; 3], [CellLogLikelihoodData; 3])> = None;
+    let mut previous_log_likelihood: Option<f64> = None;
+    let mut converged = false;
+    for iteration in 0..params.em_iterations {
+        // m step
+        let majority_weights: Vec<f64> = (0..num_cells).map(|cell_id| (1.0 - posteriors[cell_id] - doublet_posteriors[cell_id]).max(0.0)).collect();
+        let minority_fraction = posteriors.iter().sum::<f64>() / (num_cells as f64);
+        let doublet_fraction = doublet_posteriors.iter().sum::<f64>() / (num_cells as f64);
+        let log_priors = (minority_fraction.ln(), (1.0 - minority_fraction - doublet_fraction).ln(), doublet_fraction.ln());
+        let alpha_betas_minority_dist = weighted_alpha_betas(counts, loci_used.len(), &posteriors);
+        let alpha_betas_majority_dist = weighted_alpha_betas(counts, loci_used.len(), &majority_weights);
+        let alpha_betas_doublet_dist = weighted_alpha_betas(counts, loci_used.len(), &doublet_posteriors);
+
+        // e step
+        let minority_dist_likelihoods: CellLogLikelihoodData = ::get_cell_log_likelihoods(&loci_used_for_posteriors, locus_ids, counts, &alpha_betas_minority_dist, &no_cells, precomputed_log_binomial_coefficients);
+        let majority_dist_likelihoods: CellLogLikelihoodData = ::get_cell_log_likelihoods(&loci_used_for_posteriors, locus_ids, counts, &alpha_betas_majority_dist, &no_cells, precomputed_log_binomial_coefficients);
+        let doublet_dist_likelihoods: CellLogLikelihoodData = ::get_cell_log_likelihoods(&loci_used_for_posteriors, locus_ids, counts, &alpha_betas_doublet_dist, &no_cells, precomputed_log_binomial_coefficients);
+        let total_log_likelihood = mixture_log_likelihood(log_priors, &minority_dist_likelihoods.log_likelihoods, &majority_dist_likelihoods.log_likelihoods, &doublet_dist_likelihoods.log_likelihoods);
+        let (new_posteriors, new_doublet_posteriors, new_log_likelihoods) = ::combine_posteriors_with_priors(log_priors, &minority_dist_likelihoods.log_likelihoods,
+            &majority_dist_likelihoods.log_likelihoods, &doublet_dist_likelihoods.log_likelihoods);
+        posteriors = new_posteriors;
+        doublet_posteriors = new_doublet_posteriors;
+        log_likelihoods = new_log_likelihoods;
+
+        let num_minority = posteriors.iter().filter(|posterior| **posterior > params.posterior_threshold).count();
+        println!("em iteration {} total log likelihood {} minority fraction {} doublet fraction {} minority cells {}", iteration + 1, total_log_likelihood, minority_fraction, doublet_fraction, num_minority);
+        iterations.push(EmIteration { log_likelihood: total_log_likelihood, minority_fraction: minority_fraction, doublet_fraction: doublet_fraction, num_minority: num_minority });
+        final_fit = Some((minority_fraction, [alpha_betas_minority_dist, alpha_betas_majority_dist, alpha_betas_doublet_dist],
+            [minority_dist_likelihoods, majority_dist_likelihoods, doublet_dist_likelihoods]));
+        converged = match previous_log_likelihood {
+            Some(previous) => (total_log_likelihood - previous).abs() / (num_cells.max(1) as f64) < params.em_tolerance,
+            None => false,
+        };
+        previous_log_likelihood = Some(total_log_likelihood);
+        if converged { break; }
+    }
+    let (minority_fraction, distributions, likelihoods) = final_fit.expect("--em_iterations must be at least 1");
+    if !converged {
+        println!("em did not converge in {} iterations, using the last one", params.em_iterations);
+    }
+    let locus_bootstrap_interval = match params.locus_bootstrap_iterations {
+        0 => None,
+        _ => Some(::locus_bootstrap_interval(params, counts, &loci_used_for_posteriors, minority_fraction,
+            [&likelihoods[0].all_pmfs, &likelihoods[1].all_pmfs, &likelihoods[2].all_pmfs])),
+    };
+    write_em_iterations(params, &iterations);
+    // the cells counted as minority in em_iterations.tsv
+    let excluded_cells: HashSet<usize> = (0..num_cells).filter(|cell_id| posteriors[*cell_id] > params.posterior_threshold).collect();
+    return MixtureFit {
+        posteriors: posteriors,
+        doublet_posteriors: doublet_posteriors,
+        log_likelihoods: log_likelihoods,
+        excluded_cells: excluded_cells,
+        iterations: iterations,
+        locus_bootstrap_interval: locus_bootstrap_interval,
+        model: FittedModel { minority_fraction: minority_fraction, loci_used: loci_used_for_posteriors, distributions: distributions },
+    };
+}
+
+fn write_em_iterations(params: &Params, iterations: &Vec<EmIteration>) {
+    let filename = format!("{}/em_iterations.tsv", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    writer.write_all(b"iteration\tlog_likelihood\tminority_fraction\tdoublet_fraction\tminority_cells\n").expect("could not write em iterations");
+    for (iteration, em_iteration) in iterations.iter().enumerate() {
+        let line = format!("{}\t{}\t{}\t{}\t{}\n", iteration + 1, em_iteration.log_likelihood, em_iteration.minority_fraction, em_iteration.doublet_fraction, em_iteration.num_minority);
+        writer.write_all(line.as_bytes()).expect("could not write em iterations");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+    use load_data::reader;
+
+    fn lines(filename: &str) -> Vec<String> {
+        return reader(filename).lines().map(|line| line.unwrap()).collect();
+    }
+
+    #[test]
+    fn em_converges_without_losing_likelihood() {
+        let iqr = ::run_test_fixture("em_iqr", &["--fit_mode", "iqr"]);
+        let em = ::run_test_fixture("em_em", &["--fit_mode", "em"]);
+        let iterations: Vec<Vec<f64>> = lines(&format!("{}/em_iterations.tsv", em)).iter().skip(1)
+            .map(|line| line.split('\t').map(|tok| tok.parse::<f64>().unwrap()).collect()).collect();
+        assert!(iterations.len() > 1 && iterations.len() < 100, "em took {} of the 100 default iterations", iterations.len());
+        assert_eq!(iterations[0][0], 1.0);
+        for pair in iterations.windows(2) {
+            assert!(pair[1][1] >= pair[0][1] - 1e-9 * pair[0][1].abs(), "log likelihood fell from {} to {}", pair[0][1], pair[1][1]);
+        }
+        let header = |directory: &str| lines(&format!("{}/cellector_assignments.tsv", directory))[0].clone();
+        assert_eq!(header(&iqr), header(&em));
+    }
+}
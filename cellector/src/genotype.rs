@@ -0,0 +1,94 @@
+use statrs::distribution::{Binomial, Discrete};
+use stats;
+
+// genotype calling from pooled ref/alt umi counts of a group of cells, all in log space so deep loci do not underflow.
+// genotypes are in vcf order: 0/0, 0/1, 1/1
+pub const GENOTYPES: [&str; 3] = ["0/0", "0/1", "1/1"];
+
+pub struct GenotypeModel {
+    pub ambient_fraction: f64, // fraction of umis from the soup, drawn with the pooled allele fraction
+    pub error_rate: f64, // alt fraction of a hom ref (and ref fraction of a hom alt) apart from ambient
+    pub overdispersion: f64, // beta-binomial rho in [0, 1), 0 is binomial
+    pub hwe_prior: bool, // prior from the locus allele frequency under hardy weinberg, flat 1/3 otherwise
+}
+
+pub struct GenotypeCall {
+    pub log_likelihoods: [f64; 3],
+    pub posteriors: [f64; 3],
+    pub genotype: Option<usize>, // index into GENOTYPES if its posterior passes the threshold
+}
+
+// log likelihood of alt_count alt umis out of total with expected alt fraction p
+pub fn log_likelihood(model: &GenotypeModel, alt_count: usize, total: usize, p: f64) -> f64 {
+    if model.overdispersion <= 0.0 {
+        return Binomial::new(p, total as u64).unwrap().ln_pmf(alt_count as u64);
+    }
+    let concentration = (1.0 - model.overdispersion) / model.overdispersion;
+    let ln_coefficient = statrs::function::factorial::ln_binomial(total as u64, alt_count as u64);
+    return stats::log_beta_binomial_pmf(alt_count as f64, (total - alt_count) as f64, p * concentration, (1.0 - p) * concentration, ln_coefficient);
+}
+
+// log prior of each genotype, hardy weinberg from the allele frequency if there is one and the model asks for it
+pub fn log_prior(model: &GenotypeModel, allele_frequency: Option<f64>) -> [f64; 3] {
+    match allele_frequency {
+        Some(af) if model.hwe_prior => {
+            let af = af.clamp(1e-3, 1.0 - 1e-3);
+            return [(2.0 * (1.0 - af).ln()), (2.0f64.ln() + af.ln() + (1.0 - af).ln()), (2.0 * af.ln())];
+        },
+        _ => return [(1.0f64 / 3.0).ln(); 3],
+    }
+}
+
+pub fn call_genotype(model: &GenotypeModel, alt_count: usize, ref_count: usize, pooled_alt_fraction: f64, allele_frequency: Option<f64>, posterior_threshold: f64) -> GenotypeCall {
+    let total = alt_count + ref_count;
+    let fractions = [model.error_rate, 0.5, 1.0 - model.error_rate];
+    let mut log_likelihoods = [0.0; 3];
+    for (genotype, fraction) in fractions.iter().enumerate() {
+        let p = (1.0 - model.ambient_fraction) * fraction + model.ambient_fraction * pooled_alt_fraction;
+        log_likelihoods[genotype] = log_likelihood(model, alt_count, total, p);
+    }
+    let prior = log_prior(model, allele_frequency);
+    let log_joint = [prior[0] + log_likelihoods[0], prior[1] + log_likelihoods[1], prior[2] + log_likelihoods[2]];
+    let log_evidence = stats::logsumexp(&log_joint);
+    let posteriors = [(log_joint[0] - log_evidence).exp(), (log_joint[1] - log_evidence).exp(), (log_joint[2] - log_evidence).exp()];
+    let genotype = (0..3).find(|genotype| posteriors[*genotype] > posterior_threshold);
+    return GenotypeCall { log_likelihoods: log_likelihoods, posteriors: posteriors, genotype: genotype };
+}
+
+// phred scaled likelihoods relative to the most likely genotype
+pub fn phred_likelihoods(call: &GenotypeCall) -> [i64; 3] {
+    let max = call.log_likelihoods.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let mut pl = [0; 3];
+    for (phred, log_likelihood) in pl.iter_mut().zip(call.log_likelihoods.iter()) {
+        *phred = (-10.0 * (log_likelihood - max) / std::f64::consts::LN_10).round() as i64;
+    }
+    return pl;
+}
+
+// first value of the allele frequency field (eg AF) in a vcf INFO column
+pub fn info_allele_frequency(info: &str, field: &str) -> Option<f64> {
+    for entry in info.split(';') {
+        let mut key_value = entry.splitn(2, '=');
+        if key_value.next() == Some(field) {
+            return key_value.next()?.split(',').next()?.parse::<f64>().ok();
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_locus_does_not_underflow() {
+        let model = GenotypeModel { ambient_fraction: 0.03, error_rate: 0.01, overdispersion: 0.0, hwe_prior: false };
+        let call = call_genotype(&model, 6000, 4000, 0.5, None, 0.99);
+        assert!(call.posteriors.iter().all(|posterior| posterior.is_finite()));
+        assert!((call.posteriors.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert_eq!(call.genotype, Some(1));
+        assert_eq!(phred_likelihoods(&call)[1], 0);
+        assert_eq!(stats::logsumexp(&[f64::NEG_INFINITY, f64::NEG_INFINITY]), f64::NEG_INFINITY);
+        assert!((stats::logsumexp(&[-1000.0, -1000.0]) - (-1000.0 + 2f64.ln())).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,99 @@
+use std::io::{BufWriter, Write, BufRead};
+use hashbrown::{HashMap, HashSet};
+use File;
+use Params;
+use load_data;
+use load_data::{reader, AlleleMatrix, VartrixDatum};
+
+// joint run over several 10x channels of the same individuals (--channels). every channel's alt.mtx and ref.mtx must
+// be counted against the same vcf so locus i is the same variant in all of them. the cells are concatenated into one
+// matrix with the barcodes prefixed by the channel name, so the majority and minority locus distributions are learned
+// from the cells of all channels together. cellector_assignments.tsv has every cell, {name}_cellector_assignments.tsv
+// has the cells of one channel with the original barcodes
+pub struct Channel {
+    pub name: String,
+    pub alt_mtx: String,
+    pub ref_mtx: String,
+    pub barcodes: String,
+}
+
+// tsv of name, alt.mtx, ref.mtx, barcodes per channel, lines starting with # are skipped
+pub fn load_channels(filename: &str) -> Vec<Channel> {
+    let mut channels: Vec<Channel> = Vec::new();
+    let mut names: HashSet<String> = HashSet::new();
+    for line in reader(filename).lines() {
+        let line = line.expect(&format!("Unable to read a line in {}", filename));
+        if line.trim().is_empty() || line.starts_with('#') { continue; }
+        let toks: Vec<&str> = line.trim_end().split('\t').collect();
+        assert!(toks.len() == 4, "Invalid line in {}: {}\nThe correct format is: name\talt.mtx\tref.mtx\tbarcodes.tsv", filename, line);
+        assert!(names.insert(toks[0].to_string()), "channel name {} appears twice in {}", toks[0], filename);
+        channels.push(Channel {
+            name: toks[0].to_string(),
+            alt_mtx: toks[1].to_string(),
+            ref_mtx: toks[2].to_string(),
+            barcodes: toks[3].to_string(),
+        });
+    }
+    assert!(!channels.is_empty(), "no channels in {}", filename);
+    return channels;
+}
+
+pub fn channel_barcode(channel: &Channel, barcode: &str) -> String {
+    return format!("{}_{}", channel.name, barcode);
+}
+
+// prefixed barcodes, barcode to cell id, the concatenated allele counts of all channels and the number of cells of each
+pub fn load_joint_counts(params: &Params, channels: &Vec<Channel>) -> (Vec<String>, HashMap<String, usize>, AlleleMatrix, Vec<usize>) {
+    let mut cell_id_to_barcode: Vec<String> = Vec::new();
+    let mut barcode_to_cell_id: HashMap<String, usize> = HashMap::new();
+    let mut entries: Vec<VartrixDatum> = Vec::new();
+    let mut joint_total_loci: Option<usize> = None;
+    let mut channel_cells: Vec<usize> = Vec::new();
+    for channel in channels {
+        let cell_offset = cell_id_to_barcode.len();
+        for line in reader(&channel.barcodes).lines() {
+            let barcode = channel_barcode(channel, &line.expect(&format!("Unable to read a line in {}", channel.barcodes)));
+            barcode_to_cell_id.insert(barcode.clone(), cell_id_to_barcode.len());
+            cell_id_to_barcode.push(barcode);
+        }
+        let (total_loci, total_cells, channel_entries) = load_data::mtx_entries(&channel.alt_mtx, &channel.ref_mtx);
+        assert!(total_cells == cell_id_to_barcode.len() - cell_offset, "channel {} has {} barcodes in {} but {} cells in {}",
+            channel.name, cell_id_to_barcode.len() - cell_offset, channel.barcodes, total_cells, channel.alt_mtx);
+        match joint_total_loci {
+            Some(loci) => assert!(loci == total_loci, "channel {} has {} loci but the first channel has {}, all channels must be counted against the same --vcf", channel.name, total_loci, loci),
+            None => joint_total_loci = Some(total_loci),
+        }
+        channel_cells.push(total_cells);
+        let num_entries = entries.len();
+        for mut data in channel_entries {
+            data.cell_id += cell_offset;
+            entries.push(data);
+        }
+        println!("channel {}: {} cells, {} entries", channel.name, total_cells, entries.len() - num_entries);
+    }
+    let total_loci = joint_total_loci.unwrap();
+    if let Some(vcf_data) = load_data::load_vcf_data(params) {
+        assert!(vcf_data.len() == total_loci, "the channel matrices have {} loci but --vcf has {} records", total_loci, vcf_data.len());
+    }
+    let total_cells = cell_id_to_barcode.len();
+    return (cell_id_to_barcode, barcode_to_cell_id, AlleleMatrix { total_loci: total_loci, total_cells: total_cells, entries: entries }, channel_cells);
+}
+
+// splits the lines of cellector_assignments.tsv (in cell id order) into one file per channel with the channel prefix
+// removed from the barcodes
+pub fn write_channel_assignments(params: &Params, channels: &Vec<Channel>, channel_cells: &Vec<usize>, header: &str, lines: &Vec<String>) {
+    assert!(channel_cells.iter().sum::<usize>() == lines.len(), "the channels have {} cells but there are {} assignments", channel_cells.iter().sum::<usize>(), lines.len());
+    let mut cell_offset = 0;
+    for (channel, &num_cells) in channels.iter().zip(channel_cells.iter()) {
+        let filename = format!("{}/{}_cellector_assignments.tsv", params.output_directory, channel.name);
+        let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+        let mut writer = BufWriter::new(filehandle);
+        writer.write_all(header.as_bytes()).expect("could not write channel assignments");
+        for line in &lines[cell_offset..cell_offset + num_cells] {
+            assert!(line.starts_with(&format!("{}_", channel.name)), "expected a cell of channel {}: {}", channel.name, line);
+            writer.write_all(&line.as_bytes()[channel.name.len() + 1..]).expect("could not write channel assignments");
+        }
+        cell_offset += num_cells;
+    }
+    println!("wrote the assignments of each of the {} channels to {}/{{channel}}_cellector_assignments.tsv", channels.len(), params.output_directory);
+}
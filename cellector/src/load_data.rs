@@ -9,21 +9,127 @@ use std::process::Command;
 use hashbrown::{HashMap,HashSet};
 use Params;
 use AlleleCount;
-
-pub struct CellLocusData {
-    pub locus_index: usize,
-    pub locus_id: usize,
-    pub log_binomial_coefficient: f64,
-    pub alt_count: f64,
-    pub ref_count: f64,
-    pub total: usize,
-}
+use obs::barcode_core;
 
 pub struct CellData {
     pub cell_id: usize,
     pub barcode: String,
     pub assignment: String,
-    pub cell_loci_data: Vec<CellLocusData>,
+    pub cell_type: String, // from --cell_types, unlabeled otherwise
+}
+
+// ref/alt umi counts of every nonzero cell/locus entry as u32, built in one pass over the matrix entries (load_counts).
+// the locus-major (csc) view covers all loci so group totals (load_mtx_final) do not rescan the matrices,
+// the cell-major (csr) view only holds the used loci, indexed by used locus index, for the likelihoods. it is filled
+// by add_cell_view once the used loci are known (min_ref and min_alt, or the loci of a model for classify), so the locus-major view alone can be cached (see cache.rs)
+pub struct CountStore {
+    pub total_loci: usize,
+    pub total_cells: usize,
+    pub locus_offsets: Vec<usize>, // entries of locus_id are locus_offsets[locus_id]..locus_offsets[locus_id + 1]
+    pub locus_cells: Vec<u32>,
+    pub locus_ref: Vec<u32>,
+    pub locus_alt: Vec<u32>,
+    cell_offsets: Vec<usize>, // entries of cell_id are cell_offsets[cell_id]..cell_offsets[cell_id + 1]
+    cell_loci: Vec<u32>, // used locus index
+    cell_ref: Vec<u32>,
+    cell_alt: Vec<u32>,
+}
+
+impl CountStore {
+    pub fn from_locus_major(total_loci: usize, total_cells: usize, locus_offsets: Vec<usize>, locus_cells: Vec<u32>, locus_ref: Vec<u32>, locus_alt: Vec<u32>) -> CountStore {
+        assert!(locus_offsets.len() == total_loci + 1 && locus_offsets[total_loci] == locus_cells.len(), "locus offsets do not match the entries");
+        assert!(locus_ref.len() == locus_cells.len() && locus_alt.len() == locus_cells.len(), "locus ref/alt counts do not match the entries");
+        return CountStore {
+            total_loci: total_loci,
+            total_cells: total_cells,
+            locus_offsets: locus_offsets,
+            locus_cells: locus_cells,
+            locus_ref: locus_ref,
+            locus_alt: locus_alt,
+            cell_offsets: vec![0; total_cells + 1],
+            cell_loci: Vec::new(),
+            cell_ref: Vec::new(),
+            cell_alt: Vec::new(),
+        };
+    }
+
+    // loci that have at least min_ref cells with ref and min_alt cells with alt umis
+    pub fn loci_passing(&self, params: &Params) -> Vec<bool> {
+        let mut passing: Vec<bool> = Vec::new();
+        for locus_id in 0..self.total_loci {
+            let mut cells_with_ref_alt = [0; 2];
+            for (_cell_id, ref_count, alt_count) in self.locus_entries(locus_id) {
+                if ref_count > 0 { cells_with_ref_alt[0] += 1; }
+                if alt_count > 0 { cells_with_ref_alt[1] += 1; }
+            }
+            passing.push(cells_with_ref_alt[0] >= params.min_ref && cells_with_ref_alt[1] >= params.min_alt);
+        }
+        return passing;
+    }
+
+    // fills the cell-major view with the loci in used (indexed by locus id), returns the locus id of each used locus index
+    pub fn add_cell_view(&mut self, used: &Vec<bool>) -> Vec<usize> {
+        let mut locus_ids: Vec<usize> = Vec::new();
+        let mut locus_to_used_index: Vec<Option<u32>> = vec![None; self.total_loci];
+        let mut entries_per_cell: Vec<usize> = vec![0; self.total_cells];
+        for locus_id in 0..self.total_loci {
+            if !used[locus_id] { continue; }
+            locus_to_used_index[locus_id] = Some(locus_ids.len() as u32);
+            locus_ids.push(locus_id);
+            for entry in self.locus_offsets[locus_id]..self.locus_offsets[locus_id + 1] { entries_per_cell[self.locus_cells[entry] as usize] += 1; }
+        }
+        let mut cell_offsets: Vec<usize> = vec![0; self.total_cells + 1];
+        for cell_id in 0..self.total_cells { cell_offsets[cell_id + 1] = cell_offsets[cell_id] + entries_per_cell[cell_id]; }
+        let num_used_entries = cell_offsets[self.total_cells];
+        let mut cell_loci: Vec<u32> = vec![0; num_used_entries];
+        let mut cell_ref: Vec<u32> = vec![0; num_used_entries];
+        let mut cell_alt: Vec<u32> = vec![0; num_used_entries];
+        let mut next: Vec<usize> = cell_offsets[..self.total_cells].to_vec();
+        for (locus_id, used_locus_index) in locus_to_used_index.iter().enumerate() {
+            if let Some(used_locus_index) = *used_locus_index {
+                for entry in self.locus_offsets[locus_id]..self.locus_offsets[locus_id + 1] {
+                    let cell_id = self.locus_cells[entry] as usize;
+                    cell_loci[next[cell_id]] = used_locus_index;
+                    cell_ref[next[cell_id]] = self.locus_ref[entry];
+                    cell_alt[next[cell_id]] = self.locus_alt[entry];
+                    next[cell_id] += 1;
+                }
+            }
+        }
+        self.cell_offsets = cell_offsets;
+        self.cell_loci = cell_loci;
+        self.cell_ref = cell_ref;
+        self.cell_alt = cell_alt;
+        return locus_ids;
+    }
+
+    // (used locus index, ref count, alt count) of the used loci with umis in the cell
+    pub fn cell_entries<'a>(&'a self, cell_id: usize) -> impl Iterator<Item = (usize, u32, u32)> + 'a {
+        let (start, end) = (self.cell_offsets[cell_id], self.cell_offsets[cell_id + 1]);
+        return (start..end).map(move |entry| (self.cell_loci[entry] as usize, self.cell_ref[entry], self.cell_alt[entry]));
+    }
+
+    // the entries back as an AlleleMatrix in locus-major order, to write alt.mtx and ref.mtx
+    pub fn allele_matrix(&self) -> AlleleMatrix {
+        let mut entries: Vec<VartrixDatum> = Vec::new();
+        for locus_id in 0..self.total_loci {
+            for (cell_id, ref_count, alt_count) in self.locus_entries(locus_id) {
+                entries.push(VartrixDatum { locus: locus_id, cell_id: cell_id, alt_count: alt_count as usize, ref_count: ref_count as usize });
+            }
+        }
+        return AlleleMatrix { total_loci: self.total_loci, total_cells: self.total_cells, entries: entries };
+    }
+
+    // number of used loci with umis in the cell
+    pub fn cell_num_loci(&self, cell_id: usize) -> usize {
+        return self.cell_offsets[cell_id + 1] - self.cell_offsets[cell_id];
+    }
+
+    // (cell id, ref count, alt count) of the cells with umis at the locus, locus_id indexes all loci in the matrix
+    pub fn locus_entries<'a>(&'a self, locus_id: usize) -> impl Iterator<Item = (usize, u32, u32)> + 'a {
+        let (start, end) = (self.locus_offsets[locus_id], self.locus_offsets[locus_id + 1]);
+        return (start..end).map(move |entry| (self.locus_cells[entry] as usize, self.locus_ref[entry], self.locus_alt[entry]));
+    }
 }
 
 pub struct VcfLocusData {
@@ -63,9 +169,10 @@ pub fn load_vcf_data(params: &Params) -> Option<Vec<VcfLocusData>> {
 }
 
 
-pub fn create_output_dir(params: &Params) {
+pub fn create_output_dir(output_directory: &str) {
     Command::new("mkdir")
-        .arg(&params.output_directory)
+        .arg("-p")
+        .arg(output_directory)
         .output()
         .expect("failed to create output directory");
 }
@@ -95,7 +202,8 @@ pub fn load_ground_truth(params: &Params, barcode_to_cell_id: &HashMap<String, u
         for line_result in reader.lines() {
             let line = line_result.expect("Unable to read a line in the ground truth file");
             let columns: Vec<&str> = line.split('\t').collect();
-            assert!(columns.len() == 2, "Invalid line format: {}\nThe correct format is: barcode\tassignment", line);
+            // extra columns (eg contamination from combiner) are allowed and ignored
+            assert!(columns.len() >= 2, "Invalid line format: {}\nThe correct format is: barcode\tassignment", line);
             let barcode = columns[0].to_string();
             let assignment = columns[1].to_string();
             if let Some(cell_id) = barcode_to_cell_id.get(&barcode) {
@@ -106,85 +214,144 @@ pub fn load_ground_truth(params: &Params, barcode_to_cell_id: &HashMap<String, u
     return cell_id_to_ground_truth;
 }
 
-pub fn load_mtx_final(params: &Params, excluded_cells: &HashSet<usize>) -> (Vec<AlleleCount>, Vec<AlleleCount>) {
-    let mut locus_alleles_minority: Vec<AlleleCount> = Vec::new();
-    let mut locus_alleles_majority: Vec<AlleleCount> = Vec::new();
-    let (mut alt_reader, mut ref_reader) = (reader(&params.alt_mtx), reader(&params.ref_mtx));
+// cell type per cell id from a barcode\tcell_type tsv (eg exported from seurat meta.data or anndata obs), lines starting
+// with # and a header whose first column is barcode are skipped. barcodes match exactly or without their -N suffix,
+// cells without a label get "unlabeled"
+pub fn load_cell_types(filename: &str, cell_data: &Vec<CellData>) -> Vec<String> {
+    let mut by_barcode: HashMap<String, String> = HashMap::new();
+    let mut by_core: HashMap<String, String> = HashMap::new();
+    for line in reader(filename).lines() {
+        let line = line.expect(&format!("Unable to read a line in {}", filename));
+        if line.trim().is_empty() || line.starts_with('#') { continue; }
+        let columns: Vec<&str> = line.trim_end().split('\t').collect();
+        assert!(columns.len() >= 2, "Invalid line in {}: {}\nThe correct format is: barcode\tcell_type", filename, line);
+        if columns[0] == "barcode" { continue; }
+        by_barcode.insert(columns[0].to_string(), columns[1].to_string());
+        by_core.insert(barcode_core(columns[0]).to_string(), columns[1].to_string());
+    }
+    let mut num_labeled = 0;
+    let cell_types: Vec<String> = cell_data.iter().map(|cell| {
+        match by_barcode.get(&cell.barcode).or(by_core.get(barcode_core(&cell.barcode))) {
+            Some(cell_type) => { num_labeled += 1; cell_type.clone() },
+            None => "unlabeled".to_string(),
+        }
+    }).collect();
+    println!("{} of {} cells have a cell type in {}", num_labeled, cell_data.len(), filename);
+    return cell_types;
+}
 
-    let (total_loci, _total_cells) = consume_mtx_header(&mut alt_reader, &mut ref_reader);
-    for _ in 0..total_loci {
-        locus_alleles_minority.push(AlleleCount{ alt_count: 0, ref_count: 0 });
-        locus_alleles_majority.push(AlleleCount{ alt_count: 0, ref_count: 0 });
+// allele counts per cell and locus counted from a bam (see count.rs) rather than read from alt.mtx and ref.mtx
+pub struct AlleleMatrix {
+    pub total_loci: usize,
+    pub total_cells: usize,
+    pub entries: Vec<VartrixDatum>,
+}
+
+// iterates over the matrix entries either from the in memory matrix or from the mtx files
+pub fn matrix_entries<'a>(params: &Params, allele_matrix: &'a Option<AlleleMatrix>) -> (usize, usize, Box<dyn Iterator<Item = VartrixDatum> + 'a>) {
+    if let Some(allele_matrix) = allele_matrix {
+        return (allele_matrix.total_loci, allele_matrix.total_cells, Box::new(allele_matrix.entries.iter().cloned()));
     }
-    for (alt_line, ref_line) in izip!(alt_reader.lines(), ref_reader.lines()) {
+    return mtx_entries(&params.alt_mtx, &params.ref_mtx);
+}
+
+// iterates over the entries of an alt.mtx and ref.mtx pair
+pub fn mtx_entries(alt_mtx: &str, ref_mtx: &str) -> (usize, usize, Box<dyn Iterator<Item = VartrixDatum>>) {
+    let (mut alt_reader, mut ref_reader) = (reader(alt_mtx), reader(ref_mtx));
+    let (total_loci, total_cells) = consume_mtx_header(&mut alt_reader, &mut ref_reader);
+    let entries = izip!(alt_reader.lines(), ref_reader.lines()).map(|(alt_line, ref_line)| {
         let (alt_line, ref_line) = (alt_line.expect("cannot read alt mtx"), ref_line.expect("cannot read ref mtx"));
-        let data: VartrixDatum = read_mtx_lines(alt_line, ref_line);
-        if excluded_cells.contains(&data.cell_id) {
-            locus_alleles_minority[data.locus].alt_count += data.alt_count;
-            locus_alleles_minority[data.locus].ref_count += data.ref_count;
-        } else {
-            locus_alleles_majority[data.locus].alt_count += data.alt_count;
-            locus_alleles_majority[data.locus].ref_count += data.ref_count;
+        read_mtx_lines(alt_line, ref_line)
+    });
+    return (total_loci, total_cells, Box::new(entries));
+}
+
+pub fn load_mtx_final(excluded_cells: &HashSet<usize>, counts: &CountStore) -> (Vec<AlleleCount>, Vec<AlleleCount>) {
+    let mut locus_alleles_minority: Vec<AlleleCount> = Vec::new();
+    let mut locus_alleles_majority: Vec<AlleleCount> = Vec::new();
+    for locus_id in 0..counts.total_loci {
+        let mut minority = AlleleCount{ alt_count: 0, ref_count: 0 };
+        let mut majority = AlleleCount{ alt_count: 0, ref_count: 0 };
+        for (cell_id, ref_count, alt_count) in counts.locus_entries(locus_id) {
+            if excluded_cells.contains(&cell_id) {
+                minority.alt_count += alt_count as usize;
+                minority.ref_count += ref_count as usize;
+            } else {
+                majority.alt_count += alt_count as usize;
+                majority.ref_count += ref_count as usize;
+            }
         }
+        locus_alleles_minority.push(minority);
+        locus_alleles_majority.push(majority);
     }
 
     return (locus_alleles_minority, locus_alleles_majority);
 }
 
-pub fn load_cell_data(params: &Params, cell_id_to_barcode: &Vec<String>, cell_id_to_assignment: &Vec<String>) ->
-    (Vec<bool>, Vec<usize>, Vec<CellData>, Vec<[f64; 2]>, Vec<Vec<f64>>) { 
-    // loci_used, vec of celldata, locus_counts (vec indexed by locus of [refcount, altcount])
-    // 2 pass on mtx file. First to get loci_used then to get the cell data only for loci_used
-    let (num_loci_used, loci_used, locus_to_used_index) = get_loci_used(params);
-    let (mut alt_reader, mut ref_reader) = (reader(&params.alt_mtx), reader(&params.ref_mtx));
-    let (_total_loci, total_cells) = consume_mtx_header(&mut alt_reader, &mut ref_reader);
-    let mut cell_data = init_cell_data(total_cells, cell_id_to_barcode, cell_id_to_assignment);
+pub fn load_cell_data(params: &Params, cell_id_to_barcode: &Vec<String>, cell_id_to_assignment: &Vec<String>, mut counts: CountStore) ->
+    (Vec<bool>, Vec<usize>, Vec<CellData>, CountStore, Vec<[f64; 2]>, Vec<Vec<f64>>) { 
+    // loci_used, locus_ids (used locus index to locus id), vec of celldata, counts, locus_counts (vec indexed by used locus of [refcount, altcount])
+    let cell_data = init_cell_data(params, counts.total_cells, cell_id_to_barcode, cell_id_to_assignment);
+    let passing = counts.loci_passing(params);
+    let locus_ids = counts.add_cell_view(&passing);
     let mut locus_counts: Vec<[f64; 2]> = Vec::new();
-    for _i in 0..num_loci_used { locus_counts.push([0.0;2]); }
-    let mut locus_ids: Vec<usize> = Vec::new();
-    for locus_id in 0..loci_used.len() {
-        if loci_used[locus_id] { locus_ids.push(locus_id); }
+    for locus_id in &locus_ids {
+        let mut pooled = [0.0; 2];
+        for (_cell_id, ref_count, alt_count) in counts.locus_entries(*locus_id) {
+            pooled[0] += ref_count as f64;
+            pooled[1] += alt_count as f64;
+        }
+        locus_counts.push(pooled);
     }
     // precompute some log_binomial_coefficients
     let max_n = 100;
     let precomputed_log_binomial_coefficients: Vec<Vec<f64>> = stats::precompute_log_binomial_coefficients(max_n);
-    for (alt_line, ref_line) in izip!(alt_reader.lines(), ref_reader.lines()) {
-        let (alt_line, ref_line) = (alt_line.expect("cannot read alt mtx"), ref_line.expect("cannot read ref mtx"));
-        let data: VartrixDatum = read_mtx_lines(alt_line, ref_line);
-        if !loci_used[data.locus] { continue; }
-        let total = data.ref_count + data.alt_count;
-        let used_locus_index = locus_to_used_index[data.locus];
-        locus_counts[used_locus_index][0] += data.ref_count as f64;
-        locus_counts[used_locus_index][1] += data.alt_count as f64;
-        let log_coefficient: f64;
-        if data.ref_count + data.alt_count <= max_n {
-            log_coefficient = precomputed_log_binomial_coefficients[data.ref_count + data.alt_count][data.alt_count];
-        } else {
-            log_coefficient = statrs::function::factorial::ln_binomial((data.alt_count + data.ref_count) as u64, data.alt_count as u64) as f64;
-        }
-        cell_data[data.cell_id].cell_loci_data.push(
-            CellLocusData{
-                locus_index: used_locus_index,
-                locus_id: data.locus,
-                alt_count: data.alt_count as f64,
-                ref_count: data.ref_count as f64,
-                total: total,
-                log_binomial_coefficient: log_coefficient,
-            });
-    }
     // now that we are only using used_loci, all loci are used (until later when we may filter loci bc they are problematic)
     let mut loci_used: Vec<bool> = Vec::new();
-    for _locus in 0..num_loci_used {
+    for _locus in 0..locus_ids.len() {
         loci_used.push(true);
     }
-    return (loci_used, locus_ids, cell_data, locus_counts, precomputed_log_binomial_coefficients);
+    return (loci_used, locus_ids, cell_data, counts, locus_counts, precomputed_log_binomial_coefficients);
 }
 
-struct VartrixDatum {
-    locus: usize,
-    cell_id: usize,
-    alt_count: usize,
-    ref_count: usize,
+// one pass over the matrix entries (from the mtx files or counted from the bam) collects the nonzero entries as compact
+// u32 records (16 bytes each), which a counting sort by locus then places into the locus-major view, so each locus's
+// cells stay in input order. peak memory is those records plus 12 bytes per nonzero entry for this view (every locus,
+// the low coverage ones too since cellector.vcf and the cache need their group totals), the records are dropped once
+// it is built. add_cell_view later adds 12 bytes per entry at a used locus for the cell-major view
+pub fn load_counts(params: &Params, allele_matrix: &Option<AlleleMatrix>) -> CountStore {
+    let (total_loci, total_cells, entries) = matrix_entries(params, allele_matrix);
+    let mut records: Vec<[u32; 4]> = Vec::new(); // locus, cell, ref, alt
+    let mut entries_per_locus: Vec<usize> = vec![0; total_loci];
+    for data in entries {
+        if data.ref_count + data.alt_count == 0 { continue; }
+        assert!(data.locus < total_loci && data.cell_id < total_cells, "matrix entry locus {} cell {} is outside the {} x {} matrix", data.locus + 1, data.cell_id + 1, total_loci, total_cells);
+        assert!(data.ref_count <= u32::MAX as usize && data.alt_count <= u32::MAX as usize, "more than {} umis at one cell and locus", u32::MAX);
+        entries_per_locus[data.locus] += 1;
+        records.push([data.locus as u32, data.cell_id as u32, data.ref_count as u32, data.alt_count as u32]);
+    }
+    let mut locus_offsets: Vec<usize> = vec![0; total_loci + 1];
+    for (locus_id, num_entries) in entries_per_locus.iter().enumerate() { locus_offsets[locus_id + 1] = locus_offsets[locus_id] + num_entries; }
+    let mut locus_cells: Vec<u32> = vec![0; records.len()];
+    let mut locus_ref: Vec<u32> = vec![0; records.len()];
+    let mut locus_alt: Vec<u32> = vec![0; records.len()];
+    let mut next: Vec<usize> = locus_offsets[..total_loci].to_vec();
+    for [locus, cell_id, ref_count, alt_count] in records {
+        let entry = next[locus as usize];
+        locus_cells[entry] = cell_id;
+        locus_ref[entry] = ref_count;
+        locus_alt[entry] = alt_count;
+        next[locus as usize] += 1;
+    }
+    return CountStore::from_locus_major(total_loci, total_cells, locus_offsets, locus_cells, locus_ref, locus_alt);
+}
+
+#[derive(Clone)]
+pub struct VartrixDatum {
+    pub locus: usize,
+    pub cell_id: usize,
+    pub alt_count: usize,
+    pub ref_count: usize,
 }
 
 fn read_mtx_lines(alt_line: String, ref_line: String) ->
@@ -206,23 +373,25 @@ fn read_mtx_lines(alt_line: String, ref_line: String) ->
 fn consume_mtx_header(alt_reader: &mut Box<dyn BufRead>, ref_reader: &mut Box<dyn BufRead>) ->
     (usize, usize) {
     // total_loci, total_cells
+    // skip the banner and any number of % comment lines, then read the size line
     let mut line = String::new();
-    let mut total_loci = 0;
-    let mut total_cells = 0;
-    for x in 0..3 {
+    loop {
+        line.clear();
         alt_reader.read_line(&mut line).expect("cannot read line from alt matrix market file");
+        if !line.starts_with('%') { break; }
+    }
+    loop {
         line.clear();
         ref_reader.read_line(&mut line).expect("cannot read line from ref matrix market file");
-        if x == 2 {
-            let toks: Vec<&str> = line.split_whitespace().collect();
-            total_loci  = toks[0].to_string().parse::<usize>().unwrap();
-            total_cells = toks[1].to_string().parse::<usize>().unwrap();
-        }
+        if !line.starts_with('%') { break; }
     }
+    let toks: Vec<&str> = line.split_whitespace().collect();
+    let total_loci  = toks[0].to_string().parse::<usize>().unwrap();
+    let total_cells = toks[1].to_string().parse::<usize>().unwrap();
     return (total_loci, total_cells);
 }
 
-fn init_cell_data(total_cells: usize, cell_id_to_barcode: &Vec<String>, cell_id_to_assignment: &Vec<String>) ->
+pub fn init_cell_data(params: &Params, total_cells: usize, cell_id_to_barcode: &Vec<String>, cell_id_to_assignment: &Vec<String>) ->
     Vec<CellData> {
     let mut cell_data: Vec<CellData> = Vec::new();
     for cell_id in 0..total_cells {
@@ -230,9 +399,13 @@ fn init_cell_data(total_cells: usize, cell_id_to_barcode: &Vec<String>, cell_id_
             cell_id: cell_id,
             barcode: cell_id_to_barcode[cell_id].clone(),
             assignment: cell_id_to_assignment[cell_id].clone(),
-            cell_loci_data: Vec::new(),
+            cell_type: "unlabeled".to_string(),
         });
     }
+    if let Some(cell_types) = &params.cell_types {
+        let cell_types = load_cell_types(cell_types, &cell_data);
+        for (cell, cell_type) in cell_data.iter_mut().zip(cell_types) { cell.cell_type = cell_type; }
+    }
     return cell_data;
 }
 
@@ -251,30 +424,56 @@ pub fn reader(filename: &str) -> Box<dyn BufRead> {
 }
 
 
-fn get_loci_used(params: &Params) -> (usize, Vec<bool>, Vec<usize>) {
-    let (mut alt_reader, mut ref_reader) = (reader(&params.alt_mtx), reader(&params.ref_mtx));
-    let (total_loci, _total_cells) = consume_mtx_header(&mut alt_reader, &mut ref_reader);
-    let mut locus_counts: Vec<[usize; 2]> = Vec::new();
-    let mut loci_used: Vec<bool> = Vec::new();
-    let mut locus_to_used_index: Vec<usize> = Vec::new();
-    for _i in 0..total_loci {
-        locus_counts.push([0;2]);
-        loci_used.push(false);
-        locus_to_used_index.push(usize::MAX);
-    }
-    for (alt_line, ref_line) in izip!(alt_reader.lines(), ref_reader.lines()) {
-        let (alt_line, ref_line) = (alt_line.expect("cannot read alt mtx"), ref_line.expect("cannot read ref mtx"));
-        let data: VartrixDatum = read_mtx_lines(alt_line, ref_line);
-        if data.ref_count > 0 { locus_counts[data.locus][0] += 1; }
-        if data.alt_count > 0 { locus_counts[data.locus][1] += 1; }
-    }
-    let mut num_loci_used = 0;
-    for locus in 0..total_loci {
-        if locus_counts[locus][0] >= params.min_ref && locus_counts[locus][1] >= params.min_alt {
-            loci_used[locus] = true;
-            locus_to_used_index[locus] = num_loci_used;
-            num_loci_used += 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the combiner's writers output a fresh directory (one zero entry, which the writer drops) and cellector loads it
+    #[test]
+    fn combiner_output_loads() {
+        let directory = ::test_directory("combiner_output");
+        let lines: Vec<(usize, usize, usize, usize)> = vec![(1, 1, 3, 0), (1, 3, 0, 0), (2, 2, 1, 4), (3, 1, 0, 2), (3, 3, 5, 5)]; // locus, cell, ref, alt
+        combiner::write_mtxs(&directory, true, 3, 3, &lines);
+        let cells: Vec<combiner::OutputCell> = (0..3).map(|cell_id| combiner::OutputCell { barcode: format!("AAAC{}-1", cell_id), dataset: 1 + cell_id / 2,
+            assignment: if cell_id < 2 { "majority" } else { "minority" }, contamination: 0.0, original_barcode: format!("AAAC{}-1", cell_id) }).collect();
+        combiner::write_cells(&directory, &cells);
+        let params = Params {
+            alt_mtx: format!("{}/alt.mtx.gz", directory),
+            ref_mtx: format!("{}/ref.mtx.gz", directory),
+            barcodes: format!("{}/barcodes.tsv", directory),
+            ground_truth: Some(format!("{}/gt.tsv", directory)),
+            output_directory: directory.clone(),
+            ..Default::default()
+        };
+        let nonzero: Vec<&(usize, usize, usize, usize)> = lines.iter().filter(|line| line.2 + line.3 > 0).collect();
+        for filename in [&params.alt_mtx, &params.ref_mtx].iter() {
+            let file_lines: Vec<String> = reader(filename).lines().map(|line| line.unwrap()).collect();
+            assert_eq!(file_lines[0], "%%MatrixMarket matrix coordinate integer general");
+            let body: Vec<&String> = file_lines.iter().filter(|line| !line.starts_with('%')).collect();
+            assert_eq!(body[0], &format!("3 3 {}", nonzero.len()));
+            assert_eq!(body.len() - 1, nonzero.len());
+        }
+        let (mut alt_reader, mut ref_reader) = (reader(&params.alt_mtx), reader(&params.ref_mtx));
+        assert_eq!(consume_mtx_header(&mut alt_reader, &mut ref_reader), (3, 3));
+        for ((alt_line, ref_line), line) in izip!(alt_reader.lines(), ref_reader.lines()).zip(nonzero.iter()) {
+            // 1-based in the file, 0-based once read
+            let data = read_mtx_lines(alt_line.unwrap(), ref_line.unwrap());
+            assert_eq!((data.locus + 1, data.cell_id + 1, data.ref_count, data.alt_count), **line);
+        }
+
+        let (cell_id_to_barcode, barcode_to_cell_id) = load_barcodes(&params);
+        assert_eq!(cell_id_to_barcode, vec!["AAAC0-1", "AAAC1-1", "AAAC2-1"]);
+        let cell_id_to_assignment = load_ground_truth(&params, &barcode_to_cell_id);
+        assert_eq!(cell_id_to_assignment, vec!["majority", "majority", "minority"]);
+        let counts = load_counts(&params, &None);
+        assert_eq!((counts.total_loci, counts.total_cells), (3, 3));
+        let mut loaded: Vec<(usize, usize, usize, usize)> = Vec::new();
+        for locus_id in 0..counts.total_loci {
+            for (cell_id, ref_count, alt_count) in counts.locus_entries(locus_id) {
+                assert!(ref_count + alt_count > 0, "zero count entry loaded at locus {} cell {}", locus_id, cell_id);
+                loaded.push((locus_id + 1, cell_id + 1, ref_count as usize, alt_count as usize));
+            }
         }
+        assert_eq!(loaded, nonzero.into_iter().cloned().collect::<Vec<(usize, usize, usize, usize)>>());
     }
-    return (num_loci_used, loci_used, locus_to_used_index);
 }
@@ -0,0 +1,150 @@
+use clap::ArgMatches;
+use std::io::{BufWriter, Write, BufRead};
+use hashbrown::HashSet;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use File;
+use Params;
+use classify;
+use cell_types;
+use load_data;
+use load_data::reader;
+
+// chimerism monitoring over timepoints (eg after a transplant). every sample is classified with the same
+// cellector_model.tsv (see classify.rs) so minority always means the same individual, and the minority fraction of each
+// sample, overall and per cell type if a cell type file is given, is written with 95% intervals to
+// {output_directory}/chimerism.tsv in date order. the usual outputs of each sample go to {output_directory}/{name}
+pub struct LongitudinalParams {
+    pub samples: String,
+    pub model: String,
+    pub output_directory: String,
+    pub seed: u64,
+    pub cellector: Params, // the cellector options shared by every sample, the inputs are set per sample
+}
+
+pub fn load_longitudinal_params(params: &ArgMatches) -> LongitudinalParams {
+    let samples = params.value_of("samples").unwrap().to_string();
+    let model = params.value_of("model").unwrap().to_string();
+    let output_directory = params.value_of("output_directory").unwrap().to_string();
+    let seed = params.value_of("seed").unwrap_or("4");
+    let seed = seed.to_string().parse::<u64>().unwrap();
+    return LongitudinalParams {
+        samples: samples,
+        model: model,
+        output_directory: output_directory,
+        seed: seed,
+        cellector: ::load_options(params),
+    };
+}
+
+pub struct Sample {
+    pub name: String,
+    pub date: (u32, u32, u32), // year, month, day
+    pub alt_mtx: String,
+    pub ref_mtx: String,
+    pub barcodes: String,
+    pub cell_types: Option<String>,
+}
+
+// year, month and day of a yyyy-mm-dd date (the month and day may lack the leading zero), None if it is not a valid date
+pub fn parse_date(date: &str) -> Option<(u32, u32, u32)> {
+    let toks: Vec<&str> = date.trim().split('-').collect();
+    if toks.len() != 3 || toks[0].len() != 4 || !toks.iter().all(|tok| !tok.is_empty() && tok.len() <= 4 && tok.chars().all(|c| c.is_ascii_digit())) { return None; }
+    let (year, month, day) = (toks[0].parse::<u32>().ok()?, toks[1].parse::<u32>().ok()?, toks[2].parse::<u32>().ok()?);
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 => if leap { 29 } else { 28 },
+        _ => return None,
+    };
+    if day == 0 || day > days_in_month { return None; }
+    return Some((year, month, day));
+}
+
+pub fn format_date(date: (u32, u32, u32)) -> String {
+    return format!("{:04}-{:02}-{:02}", date.0, date.1, date.2);
+}
+
+// tsv of name, date (yyyy-mm-dd), alt.mtx, ref.mtx, barcodes and optionally a barcode\tcell_type file per sample,
+// lines starting with # are skipped. the samples are returned in date order
+pub fn load_samples(filename: &str) -> Vec<Sample> {
+    let mut samples: Vec<Sample> = Vec::new();
+    let mut names: HashSet<String> = HashSet::new();
+    for line in reader(filename).lines() {
+        let line = line.expect(&format!("Unable to read a line in {}", filename));
+        if line.trim().is_empty() || line.starts_with('#') { continue; }
+        let toks: Vec<&str> = line.trim_end().split('\t').collect();
+        assert!(toks.len() == 5 || toks.len() == 6, "Invalid line in {}: {}\nThe correct format is: name\tdate\talt.mtx\tref.mtx\tbarcodes.tsv\t[cell_types.tsv]", filename, line);
+        assert!(names.insert(toks[0].to_string()), "sample name {} appears twice in {}", toks[0], filename);
+        let date = parse_date(toks[1]).expect(&format!("the date {} of sample {} in {} is not a valid yyyy-mm-dd date", toks[1], toks[0], filename));
+        samples.push(Sample {
+            name: toks[0].to_string(),
+            date: date,
+            alt_mtx: toks[2].to_string(),
+            ref_mtx: toks[3].to_string(),
+            barcodes: toks[4].to_string(),
+            cell_types: toks.get(5).map(|cell_types| cell_types.to_string()),
+        });
+    }
+    assert!(!samples.is_empty(), "no samples in {}", filename);
+    samples.sort_by_key(|sample| sample.date);
+    return samples;
+}
+
+pub fn run_longitudinal(params: &LongitudinalParams) {
+    load_data::create_output_dir(&params.output_directory);
+    let samples = load_samples(&params.samples);
+    let model = classify::load_model(&params.model);
+    let mut rng: StdRng = StdRng::seed_from_u64(params.seed);
+
+    let filename = format!("{}/chimerism.tsv", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    writer.write_all(format!("sample\tdate\t{}\n", cell_types::SUMMARY_HEADER).as_bytes()).expect("could not write chimerism");
+    for sample in &samples {
+        let date = format_date(sample.date);
+        println!("sample {} ({})", sample.name, date);
+        let sample_params = sample_params(params, sample);
+        load_data::create_output_dir(&sample_params.output_directory);
+        let (cell_data, results) = classify::classify_sample(&sample_params, &model);
+        for summary in cell_types::summarize_cell_types(&sample_params, &cell_data, &results, &mut rng) {
+            writer.write_all(format!("{}\t{}\t{}\n", sample.name, date, cell_types::summary_line(&summary)).as_bytes()).expect("could not write chimerism");
+            println!("{} {}: minority fraction {:.4} (95% bootstrap interval {:.4} to {:.4}) from {} assignable of {} cells", sample.name, summary.cell_type,
+                summary.minority_fraction.fraction, summary.minority_fraction.bootstrap_interval.0, summary.minority_fraction.bootstrap_interval.1,
+                summary.num_assignable, summary.num_cells);
+        }
+    }
+    println!("wrote the minority fraction of each of the {} samples to {}", samples.len(), filename);
+}
+
+// the params of one sample, the options every sample shares with its inputs and output directory
+fn sample_params(params: &LongitudinalParams, sample: &Sample) -> Params {
+    return Params {
+        alt_mtx: sample.alt_mtx.clone(),
+        ref_mtx: sample.ref_mtx.clone(),
+        barcodes: sample.barcodes.clone(),
+        cell_types: sample.cell_types.clone(),
+        output_directory: format!("{}/{}", params.output_directory, sample.name),
+        ..params.cellector.clone()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_are_parsed_and_sorted_as_dates() {
+        assert_eq!(parse_date("2024-1-5"), Some((2024, 1, 5)));
+        assert_eq!(parse_date("2024-02-29"), Some((2024, 2, 29)));
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("day 30"), None);
+        let filename = format!("{}/samples.tsv", ::test_directory("longitudinal_dates"));
+        File::create(&filename).unwrap().write_all(b"late\t2024-10-01\ta\tr\tb\nearly\t2024-1-5\ta\tr\tb\n").unwrap();
+        let samples = load_samples(&filename);
+        assert_eq!(samples.iter().map(|sample| sample.name.as_str()).collect::<Vec<&str>>(), vec!["early", "late"]);
+        assert_eq!(format_date(samples[0].date), "2024-01-05");
+    }
+}
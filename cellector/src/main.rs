@@ -1,17 +1,44 @@
-#[macro_use]
 extern crate clap;
+extern crate yaml_rust;
 extern crate hashbrown;
 extern crate statrs;
 extern crate flate2;
 extern crate itertools;
+extern crate rayon;
+extern crate noodles;
+extern crate plotters;
+extern crate rand;
+#[cfg(test)]
+extern crate combiner;
 
 mod stats;
 mod load_data;
+mod bam;
+mod variants;
+mod count;
+mod pipeline;
+mod check;
+mod compare;
+mod plot;
+mod report;
+mod obs;
+mod genotype;
+mod cache;
+mod streaming;
+mod joint;
+mod classify;
+mod longitudinal;
+mod cell_types;
+mod em;
+use report::{IterationSummary, GroupThreshold};
 use load_data::CellData;
+use load_data::CountStore;
+use load_data::AlleleMatrix;
 use load_data::VcfLocusData;
 use load_data::reader;
 
-use clap::App;
+use clap::{App, Arg, ArgMatches, YamlLoader};
+use yaml_rust::Yaml;
 use std::fs::File;
 use std::io::{BufWriter, Write, BufRead};
 
@@ -20,137 +47,387 @@ use itertools::izip;
 
 use statrs::statistics::OrderStatistics;
 use statrs::statistics::Data;
-use statrs::distribution::Discrete;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 
 fn main() {
-    let params = load_params();
-    load_data::create_output_dir(&params);
-    let (cell_id_to_barcode, barcode_to_cell_id) = load_data::load_barcodes(&params);
-    let cell_id_to_assignment = load_data::load_ground_truth(&params, &barcode_to_cell_id);
-    let (mut loci_used, locus_ids, cell_data, locus_counts, precomputed_log_binomial_coefficients) = 
-        load_data::load_cell_data(&params, &cell_id_to_barcode, &cell_id_to_assignment);
-    let vcf_data = load_data::load_vcf_data(&params);
-    cellector(&params, &mut loci_used, &locus_ids, &cell_data, &locus_counts, &vcf_data, &precomputed_log_binomial_coefficients);
+    let yaml = YamlLoader::load_from_str(include_str!("params.yml")).expect("failed to load params.yml");
+    let matches = cellector_app(&yaml).get_matches();
+    match matches.subcommand() {
+        ("variants", Some(sub_matches)) => {
+            let params = variants::load_variants_params(sub_matches);
+            variants::select_covered_variants(&params);
+        },
+        ("check", Some(sub_matches)) => {
+            let params = check::load_check_params(sub_matches);
+            check::check_inputs(&params);
+        },
+        ("compare", Some(sub_matches)) => {
+            let params = compare::load_compare_params(sub_matches);
+            compare::compare_methods(&params);
+        },
+        ("plot", Some(sub_matches)) => {
+            let params = plot::load_plot_params(sub_matches);
+            plot::plot_iterations(&params);
+        },
+        ("classify", Some(sub_matches)) => classify::classify(&load_params(sub_matches)),
+        ("longitudinal", Some(sub_matches)) => {
+            let params = longitudinal::load_longitudinal_params(sub_matches);
+            longitudinal::run_longitudinal(&params);
+        },
+        ("pipeline", Some(sub_matches)) => {
+            let params = pipeline::load_pipeline_params(sub_matches);
+            pipeline::run_pipeline(&params);
+        },
+        _ => run(&load_params(&matches)),
+    }
+}
+
+// cellector params from a command line, used by the pipeline to run the counting and cellector stages
+fn params_from_args(args: &Vec<String>) -> Params {
+    let yaml = YamlLoader::load_from_str(include_str!("params.yml")).expect("failed to load params.yml");
+    let matches = cellector_app(&yaml).get_matches_from(args);
+    return load_params(&matches);
+}
+
+// args of the main command that classify and longitudinal take as well
+const CLASSIFY_SHARED_ARGS: [&str; 20] = ["ref", "alt", "bam", "fasta", "threads", "min_mapq", "min_base_quality", "cache", "ground_truth", "posterior_threshold",
+    "min_loci_for_assignment", "bgzip_vcf", "genotype_prior", "af_field", "overdispersion", "ambient_fraction", "genotype_threshold", "cell_types", "write_obs", "expression_barcodes"];
+const LONGITUDINAL_SHARED_ARGS: [&str; 6] = ["posterior_threshold", "genotype_prior", "af_field", "overdispersion", "ambient_fraction", "genotype_threshold"];
+
+// params.yml is the main command (with the other subcommands) followed by classify and longitudinal, which get their
+// shared args from the main command's definitions so there is no second copy to keep in step
+fn cellector_app<'a>(yaml: &'a Vec<Yaml>) -> App<'a, 'a> {
+    return App::from_yaml(&yaml[0])
+        .subcommand(App::from_yaml(&yaml[1]).args(&shared_args(&yaml[0], &CLASSIFY_SHARED_ARGS)))
+        .subcommand(App::from_yaml(&yaml[2]).args(&shared_args(&yaml[0], &LONGITUDINAL_SHARED_ARGS)));
+}
+
+fn shared_args<'a>(main_yaml: &'a Yaml, names: &[&str]) -> Vec<Arg<'a, 'a>> {
+    let main_args = main_yaml["args"].as_vec().expect("params.yml has no args");
+    return names.iter().map(|name| {
+        let key = Yaml::String(name.to_string());
+        let arg = main_args.iter().filter_map(|arg| arg.as_hash()).find(|arg| arg.contains_key(&key))
+            .expect(&format!("shared arg {} is not an arg of the main command in params.yml", name));
+        Arg::from_yaml(arg)
+    }).collect();
+}
+
+fn run(params: &Params) {
+    load_data::create_output_dir(&params.output_directory);
+    if params.streaming {
+        let (cell_id_to_barcode, barcode_to_cell_id) = load_data::load_barcodes(params);
+        let cell_id_to_assignment = load_data::load_ground_truth(params, &barcode_to_cell_id);
+        streaming::run_streaming(params, &cell_id_to_barcode, &cell_id_to_assignment, &load_data::load_vcf_data(params));
+        return;
+    }
+    let channels = params.channels.as_ref().map(|channels| joint::load_channels(channels));
+    let (cell_id_to_barcode, barcode_to_cell_id, vcf_data, counts, channel_cells) = load_inputs(params, &channels);
+    let cell_id_to_assignment = load_data::load_ground_truth(params, &barcode_to_cell_id);
+    let (mut loci_used, locus_ids, cell_data, counts, locus_counts, precomputed_log_binomial_coefficients) = 
+        load_data::load_cell_data(params, &cell_id_to_barcode, &cell_id_to_assignment, counts);
+    let channel_cells = channels.as_ref().map(|channels| (channels, &channel_cells));
+    cellector(params, &mut loci_used, &locus_ids, &cell_data, &counts, &locus_counts, &vcf_data, &precomputed_log_binomial_coefficients, channel_cells);
 }
 
-fn cellector(params: &Params, loci_used: &mut Vec<bool>, locus_ids: &Vec<usize>, cell_data: &Vec<CellData>, locus_counts: &Vec<[f64; 2]>, vcf_data: &Option<Vec<VcfLocusData>>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) {
+// barcodes, barcode to cell id, vcf loci and the locus-major allele counts from the cache, the channels, the bam or
+// the mtx files, and the number of cells of each channel
+fn load_inputs(params: &Params, channels: &Option<Vec<joint::Channel>>) -> (Vec<String>, HashMap<String, usize>, Option<Vec<VcfLocusData>>, CountStore, Vec<usize>) {
+    let mut channel_cells: Vec<usize> = Vec::new();
+    let cached = match &params.cache {
+        Some(cache) => cache::load_cache(params, cache),
+        None => None,
+    };
+    let (cell_id_to_barcode, barcode_to_cell_id, vcf_data, counts) = match cached {
+        Some(cached) => {
+            if params.bam.is_some() && params.write_matrices { count::write_matrices(params, &cached.counts.allele_matrix()); }
+            (cached.cell_id_to_barcode, cached.barcode_to_cell_id, cached.vcf_data, cached.counts)
+        },
+        None => {
+            let vcf_data = load_data::load_vcf_data(params);
+            let mut allele_matrix: Option<AlleleMatrix> = None;
+            let (cell_id_to_barcode, barcode_to_cell_id) = match &channels {
+                Some(channels) => {
+                    let (cell_id_to_barcode, barcode_to_cell_id, joint_matrix, cells) = joint::load_joint_counts(params, channels);
+                    allele_matrix = Some(joint_matrix);
+                    channel_cells = cells;
+                    (cell_id_to_barcode, barcode_to_cell_id)
+                },
+                None => load_data::load_barcodes(params),
+            };
+            if params.bam.is_some() {
+                let counted = count::count_alleles(params, vcf_data.as_ref().unwrap(), &barcode_to_cell_id);
+                if params.write_matrices { count::write_matrices(params, &counted); }
+                allele_matrix = Some(counted);
+            }
+            let counts = load_data::load_counts(params, &allele_matrix);
+            if let Some(cache) = &params.cache { cache::write_cache(params, cache, &cell_id_to_barcode, &vcf_data, &counts); }
+            (cell_id_to_barcode, barcode_to_cell_id, vcf_data, counts)
+        },
+    };
+    return (cell_id_to_barcode, barcode_to_cell_id, vcf_data, counts, channel_cells);
+}
+
+fn cellector(params: &Params, loci_used: &mut Vec<bool>, locus_ids: &Vec<usize>, cell_data: &Vec<CellData>, counts: &CountStore, locus_counts: &Vec<[f64; 2]>, vcf_data: &Option<Vec<VcfLocusData>>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>, channel_cells: Option<ChannelCells>) {
     let mut excluded_cells: HashSet<usize> = HashSet::new();
     let mut any_change;
     let mut iteration = 0;
     let mut log_likelihoods_loci_normalized;
     let mut loci_used_per_cell;
+    let mut iteration_summary;
+    let mut iteration_summaries: Vec<IterationSummary> = Vec::new();
+    let num_loci_passing = loci_used.len();
     loop {
-        (any_change, excluded_cells, log_likelihoods_loci_normalized, loci_used_per_cell) = compute_new_excluded(params, loci_used, locus_ids, cell_data, locus_counts, &excluded_cells, iteration, vcf_data, precomputed_log_binomial_coefficients);
+        (any_change, excluded_cells, log_likelihoods_loci_normalized, loci_used_per_cell, iteration_summary) = compute_new_excluded(params, loci_used, locus_ids, cell_data, counts, locus_counts, &excluded_cells, iteration, vcf_data, precomputed_log_binomial_coefficients);
+        iteration_summaries.push(iteration_summary);
         iteration += 1;
         if !any_change { break; }
     }
-    let (posteriors, doublet_posteriors, log_likelihoods) = calculate_posteriors(params, loci_used, cell_data, locus_counts, &excluded_cells, precomputed_log_binomial_coefficients);
-    output_final_vcf(params, cell_data, loci_used, locus_ids, &excluded_cells, vcf_data);
-    output_final_assignments(params, cell_data, &posteriors, &doublet_posteriors, &log_likelihoods, &excluded_cells, &log_likelihoods_loci_normalized, &loci_used_per_cell);
+    let (mut posteriors, mut doublet_posteriors, mut log_likelihoods, mut locus_bootstrap_interval, mut model) = calculate_posteriors(params, loci_used, locus_ids, cell_data, counts, locus_counts, &excluded_cells, precomputed_log_binomial_coefficients);
+    let mut em_iterations: Vec<em::EmIteration> = Vec::new();
+    if params.fit_mode == "em" {
+        let fit = em::fit_mixture(params, loci_used, locus_ids, counts, locus_counts, &excluded_cells, precomputed_log_binomial_coefficients, posteriors, doublet_posteriors);
+        posteriors = fit.posteriors;
+        doublet_posteriors = fit.doublet_posteriors;
+        log_likelihoods = fit.log_likelihoods;
+        excluded_cells = fit.excluded_cells;
+        locus_bootstrap_interval = fit.locus_bootstrap_interval;
+        em_iterations = fit.iterations;
+        model = fit.model;
+    }
+    let locus_alleles = if params.vcf.is_some() { load_data::load_mtx_final(&excluded_cells, counts) } else { (Vec::new(), Vec::new()) };
+    write_final_outputs(params, cell_data, loci_used, locus_ids, vcf_data, channel_cells, &FinalResults {
+        excluded_cells: excluded_cells,
+        posteriors: posteriors,
+        doublet_posteriors: doublet_posteriors,
+        log_likelihoods: log_likelihoods,
+        normalized_log_likelihoods: log_likelihoods_loci_normalized,
+        loci_used_per_cell: loci_used_per_cell,
+        cell_num_loci: (0..cell_data.len()).map(|cell_id| counts.cell_num_loci(cell_id)).collect(),
+        locus_alleles: locus_alleles,
+        iteration_summaries: iteration_summaries,
+        num_loci_passing: num_loci_passing,
+        locus_bootstrap_interval: locus_bootstrap_interval,
+        em_iterations: em_iterations,
+        model: Some(model),
+    });
 }
 
-fn output_final_vcf(params: &Params, cell_data: &Vec<CellData>, loci_used: &Vec<bool>, locus_ids: &Vec<usize>, excluded_cells: &HashSet<usize>, vcf_data: &Option<Vec<VcfLocusData>>) {
-    if params.vcf.is_none() { return; }
-    let (locus_alleles_minority, locus_alleles_majority) = load_data::load_mtx_final(params, excluded_cells);
-    // so I need to load the vcf file to get the header,
-    let vcf = &params.vcf.as_ref().unwrap().to_string();
-    let mut record_index: usize = 0;
-    let ambient_percent = 0.03; // TODO dont hard code stuff
-    let gt_threshold = 0.99; // TODO dont hard code stuff
-    let reader = reader(&vcf);
-    // and I need to make the output vcf file
-    let filename = format!("{}/cellector.vcf",params.output_directory);
+// everything the outputs need once the iterations and posteriors are done, from either the in memory or the streaming run
+struct FinalResults {
+    excluded_cells: HashSet<usize>,
+    posteriors: Vec<f64>,
+    doublet_posteriors: Vec<f64>,
+    log_likelihoods: Vec<(f64, f64)>, // (majority, minority)
+    normalized_log_likelihoods: Vec<f64>, // from the last iteration
+    loci_used_per_cell: Vec<f64>, // from the last iteration
+    cell_num_loci: Vec<usize>, // used loci with umis in each cell, for min_loci_used
+    locus_alleles: (Vec<AlleleCount>, Vec<AlleleCount>), // (minority, majority) counts of every locus, empty without --vcf
+    iteration_summaries: Vec<IterationSummary>,
+    num_loci_passing: usize,
+    locus_bootstrap_interval: Option<(f64, f64)>, // minority fraction interval from resampling loci, with --locus_bootstrap_iterations
+    em_iterations: Vec<em::EmIteration>, // empty unless --fit_mode em
+    model: Option<classify::FittedModel>, // written to cellector_model.tsv with --vcf, None for classify which applies a model
+}
+
+// the channels of a --channels run and the number of cells of each, cell ids are in channel order
+type ChannelCells<'a> = (&'a Vec<joint::Channel>, &'a Vec<usize>);
+
+fn write_final_outputs(params: &Params, cell_data: &Vec<CellData>, loci_used: &Vec<bool>, locus_ids: &Vec<usize>, vcf_data: &Option<Vec<VcfLocusData>>, channel_cells: Option<ChannelCells>, results: &FinalResults) {
+    if let (Some(vcf_data), Some(model)) = (vcf_data, &results.model) { classify::write_model(params, vcf_data, locus_ids, model); }
+    let genotype_pair_counts = output_final_vcf(params, &results.locus_alleles, loci_used, locus_ids, vcf_data);
+    let assignment_gt_counts = output_final_assignments(params, cell_data, channel_cells, &results.cell_num_loci, &results.posteriors, &results.doublet_posteriors, &results.log_likelihoods, &results.excluded_cells, &results.normalized_log_likelihoods, &results.loci_used_per_cell);
+    let minority_fraction = output_minority_fraction(params, results);
+    let mut cell_type_summaries: Vec<cell_types::CellTypeSummary> = Vec::new();
+    if params.cell_types.is_some() {
+        cell_type_summaries = cell_types::summarize_cell_types(params, cell_data, results, &mut StdRng::seed_from_u64(params.seed));
+        cell_types::write_cell_type_summary(params, &cell_type_summaries);
+    }
+    report::write_report(params, &report::Report {
+        cell_data: cell_data,
+        num_input_loci: vcf_data.as_ref().map(|vcf_data| vcf_data.len()),
+        num_loci_passing: results.num_loci_passing,
+        iterations: &results.iteration_summaries,
+        normalized_log_likelihoods: &results.normalized_log_likelihoods,
+        loci_used_per_cell: &results.loci_used_per_cell,
+        assignment_gt_counts: &assignment_gt_counts,
+        genotype_pair_counts: &genotype_pair_counts,
+        minority_fraction: &minority_fraction,
+        locus_bootstrap_interval: results.locus_bootstrap_interval,
+        cell_type_summaries: &cell_type_summaries,
+        em_iterations: &results.em_iterations,
+    });
+}
+
+// posterior weighted minority fraction of the assignable cells with its intervals, printed and written to
+// {output_directory}/cellector_minority_fraction.tsv
+fn output_minority_fraction(params: &Params, results: &FinalResults) -> stats::FractionEstimate {
+    let mut rng: StdRng = StdRng::seed_from_u64(params.seed);
+    let probabilities: Vec<f64> = singlet_minority_probabilities(params, &results.posteriors, &results.doublet_posteriors, &results.cell_num_loci)
+        .into_iter().flatten().collect();
+    let estimate = stats::minority_fraction_estimate(&probabilities, params.bootstrap_iterations, &mut rng);
+    let filename = format!("{}/cellector_minority_fraction.tsv", params.output_directory);
     let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
     let mut writer = BufWriter::new(filehandle);
-    for line in reader.lines() {
-        let mut line = line.expect("Unable to read a line in the ground truth file").to_string();
-        if line.starts_with("##") {
-            let line = format!("{}\n",line);
-            writer.write_all(line.as_bytes()).expect("could not write to vcf");
+    writer.write_all(b"cells\tassignable_cells\tminority_fraction\tposterior_ci_low\tposterior_ci_high\tbootstrap_ci_low\tbootstrap_ci_high\tlocus_bootstrap_ci_low\tlocus_bootstrap_ci_high\n").expect("could not write minority fraction");
+    let (locus_low, locus_high) = match results.locus_bootstrap_interval {
+        Some((low, high)) => (low.to_string(), high.to_string()),
+        None => ("na".to_string(), "na".to_string()),
+    };
+    let line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", results.posteriors.len(), probabilities.len(), estimate.fraction,
+        estimate.posterior_interval.0, estimate.posterior_interval.1, estimate.bootstrap_interval.0, estimate.bootstrap_interval.1, locus_low, locus_high);
+    writer.write_all(line.as_bytes()).expect("could not write minority fraction");
+    println!("minority fraction {:.4} of {} assignable cells, 95% interval {:.4} to {:.4} (bootstrap over cells), {:.4} to {:.4} (call uncertainty only)",
+        estimate.fraction, probabilities.len(), estimate.bootstrap_interval.0, estimate.bootstrap_interval.1, estimate.posterior_interval.0, estimate.posterior_interval.1);
+    if let Some((low, high)) = results.locus_bootstrap_interval {
+        println!("minority fraction 95% interval {:.4} to {:.4} (bootstrap over loci)", low, high);
+    }
+    return estimate;
+}
+
+// header lines for the fields cellector adds, existing ##FORMAT lines are dropped along with the input sample columns
+const VCF_HEADER_LINES: &str = "##INFO=<ID=CELLECTOR_AO,Number=1,Type=Integer,Description=\"Alt allele umis pooled over all cells\">
+##INFO=<ID=CELLECTOR_RO,Number=1,Type=Integer,Description=\"Ref allele umis pooled over all cells\">
+##INFO=<ID=CELLECTOR_LOCUS,Number=1,Type=String,Description=\"used if cellector used the locus, filtered if it was removed by the locus contribution filter, low_coverage if it had fewer than min_ref ref or min_alt alt umis\">
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+##FORMAT=<ID=GP,Number=G,Type=Float,Description=\"Genotype posterior probabilities\">
+##FORMAT=<ID=PL,Number=G,Type=Integer,Description=\"Phred-scaled genotype likelihoods, rounded to the closest integer\">
+##FORMAT=<ID=AO,Number=A,Type=Integer,Description=\"Alt allele umis in the cells of this group\">
+##FORMAT=<ID=RO,Number=1,Type=Integer,Description=\"Ref allele umis in the cells of this group\">
+";
+
+fn output_final_vcf(params: &Params, locus_alleles: &(Vec<AlleleCount>, Vec<AlleleCount>), loci_used: &Vec<bool>, locus_ids: &Vec<usize>, _vcf_data: &Option<Vec<VcfLocusData>>) -> HashMap<(String, String), usize> {
+    let mut genotype_pair_counts: HashMap<(String, String), usize> = HashMap::new(); // (majority, minority) genotype to loci
+    if params.vcf.is_none() { return genotype_pair_counts; }
+    let mut locus_status: Vec<&str> = vec!["low_coverage"; locus_alleles.0.len()];
+    for (used_index, locus_id) in locus_ids.iter().enumerate() {
+        locus_status[*locus_id] = if loci_used[used_index] { "used" } else { "filtered" };
+    }
+    let filename = if params.bgzip_vcf { format!("{}/cellector.vcf.gz", params.output_directory) } else { format!("{}/cellector.vcf", params.output_directory) };
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    // finish the writer explicitly so a failed flush or bgzf eof block is an error rather than lost on drop
+    let written = if params.bgzip_vcf {
+        let mut writer = noodles::bgzf::io::Writer::new(filehandle);
+        write_vcf_records(params, locus_alleles, &locus_status, &mut genotype_pair_counts, &mut writer)
+            .and_then(|num_multiallelic| { writer.finish()?.sync_all()?; Ok(num_multiallelic) })
+    } else {
+        let mut writer = BufWriter::new(filehandle);
+        write_vcf_records(params, locus_alleles, &locus_status, &mut genotype_pair_counts, &mut writer)
+            .and_then(|num_multiallelic| { writer.into_inner()?.sync_all()?; Ok(num_multiallelic) })
+    };
+    let num_multiallelic = written.expect(&format!("could not write {}", filename));
+    if num_multiallelic > 0 {
+        println!("skipped {} multi-allelic records in {}, the genotype model only has 0/0, 0/1 and 1/1", num_multiallelic, filename);
+    }
+    if params.bgzip_vcf {
+        match noodles::vcf::fs::index(&filename) {
+            // tabix unless a contig is too long for it, then csi
+            Ok(noodles::vcf::Index::Tabix(index)) => noodles::tabix::fs::write(format!("{}.tbi", filename), &index).expect("could not write vcf index"),
+            Ok(noodles::vcf::Index::Csi(index)) => noodles::csi::fs::write(format!("{}.csi", filename), &index).expect("could not write vcf index"),
+            Err(error) => println!("could not index {}, is the input vcf sorted? {}", filename, error),
+        }
+    }
+    return genotype_pair_counts;
+}
+
+// copies the input vcf with majority and minority genotype calls, returns the number of multi-allelic records skipped
+fn write_vcf_records(params: &Params, locus_alleles: &(Vec<AlleleCount>, Vec<AlleleCount>), locus_status: &Vec<&str>, genotype_pair_counts: &mut HashMap<(String, String), usize>, writer: &mut dyn Write) -> std::io::Result<usize> {
+    let (locus_alleles_minority, locus_alleles_majority) = (&locus_alleles.0, &locus_alleles.1);
+    let genotype_model = genotype::GenotypeModel {
+        ambient_fraction: params.ambient_fraction,
+        error_rate: 0.01,
+        overdispersion: params.overdispersion,
+        hwe_prior: params.genotype_prior == "hwe",
+    };
+    let mut record_index: usize = 0;
+    let mut num_multiallelic: usize = 0;
+    let reader = reader(params.vcf.as_ref().unwrap());
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line.expect("Unable to read a line in the vcf").to_string();
+        // the input's fileformat line is kept, a vcf without one gets 4.2 whose header rules the added lines follow
+        if line_number == 0 && !line.starts_with("##fileformat") {
+            writer.write_all(b"##fileformat=VCFv4.2\n")?;
+        }
+        if line.starts_with("##FORMAT=") {
+            continue;
+        } else if line.starts_with("##") {
+            writer.write_all(format!("{}\n", line).as_bytes())?;
         } else if line.starts_with("#CHROM") {
-            // last line of header need to add samples majority and minority
-            //line.pop(); // remove trailing newline
-            let line = format!("{}\tmajority\tminority\n",line);
-            writer.write_all(line.as_bytes()).expect("could not write to vcf");
+            // last line of header, replace any input samples with majority and minority
+            writer.write_all(VCF_HEADER_LINES.as_bytes())?;
+            let toks: Vec<&str> = line.split('\t').take(8).collect();
+            writer.write_all(format!("{}\tFORMAT\tmajority\tminority\n", toks.join("\t")).as_bytes())?;
         } else {
-            // so I need to go from allele counts to a genotype... 
-            // so a homozygous ref allele is drawn from a binomial with p = 0+err
-            // het is drawn from binomial p = 0.5 + err and hom alt is p = 1-err
+            // GP and PL are Number=G, a second alt allele would need six genotypes so these records are left out
+            if line.split('\t').nth(4).unwrap_or(".").contains(',') {
+                num_multiallelic += 1;
+                record_index += 1;
+                continue;
+            }
             let total_alt = locus_alleles_minority[record_index].alt_count + locus_alleles_majority[record_index].alt_count;
             let total_ref = locus_alleles_minority[record_index].ref_count + locus_alleles_majority[record_index].ref_count;
             let mut soup_frac = 0.5;
             if total_alt + total_ref > 0 {
                 soup_frac = (total_alt as f64)/((total_alt + total_ref) as f64);
             }
-            let mut p_hom_alt = (1.0 - ambient_percent) * 0.99 + ambient_percent * soup_frac;
-            let mut p_het = (1.0 - ambient_percent) * 0.5 + ambient_percent * soup_frac;
-            let mut p_hom_ref = (1.0 - ambient_percent) * 0.01 + ambient_percent * soup_frac;
-            let minority_alt = locus_alleles_minority[record_index].alt_count;
-            let minority_ref = locus_alleles_minority[record_index].ref_count;
-            let majority_alt = locus_alleles_majority[record_index].alt_count;
-            let majority_ref = locus_alleles_majority[record_index].ref_count;
-            let min_hom_alt_like = statrs::distribution::Binomial::new(p_hom_alt, (minority_alt+minority_ref) as u64).unwrap().pmf(minority_alt as u64);
-            let min_het_like = statrs::distribution::Binomial::new(p_het, (minority_alt+minority_ref) as u64).unwrap().pmf(minority_alt as u64);
-            let min_hom_ref_like = statrs::distribution::Binomial::new(p_hom_ref, (minority_alt+minority_ref) as u64).unwrap().pmf(minority_alt as u64);
-            let maj_hom_alt_like = statrs::distribution::Binomial::new(p_hom_alt, (majority_alt+majority_ref) as u64).unwrap().pmf(majority_alt as u64);
-            let maj_het_like = statrs::distribution::Binomial::new(p_het, (majority_alt+majority_ref) as u64).unwrap().pmf(majority_alt as u64);
-            let maj_hom_ref_like = statrs::distribution::Binomial::new(p_hom_ref, (majority_alt+majority_ref) as u64).unwrap().pmf(majority_alt as u64);
-            let mut gt_majority = "./.";
-            let mut gt_minority = "./.";
-            let min_denom = 1.0/3.0 * min_hom_alt_like + 1.0/3.0 * min_het_like + 1.0/3.0 * min_hom_ref_like;
-            let maj_denom = 1.0/3.0 * maj_hom_alt_like + 1.0/3.0 * maj_het_like + 1.0/3.0 * maj_hom_ref_like;
-            let min_hom_alt_post = min_hom_alt_like * 1.0/3.0 / min_denom;
-            let min_het_post = min_het_like * 1.0/3.0 / min_denom;
-            let min_hom_ref_post = min_hom_ref_like * 1.0/3.0 / min_denom;
-            
-            let max_post_minority = min_hom_alt_post.max(min_het_post).max(min_hom_ref_post);
-            if min_hom_alt_post > gt_threshold {
-                gt_minority = "1/1";
-            } else if min_het_post > gt_threshold {
-                gt_minority = "0/1";
-            } else if min_hom_ref_post > gt_threshold {
-                gt_minority = "0/0";
-            }
-            let maj_hom_alt_post = maj_hom_alt_like * 1.0/3.0 / maj_denom;
-            let maj_het_post = maj_het_like * 1.0/3.0 / maj_denom;
-            let maj_hom_ref_post = maj_hom_ref_like * 1.0/3.0 / maj_denom;
-            let max_post_majority = maj_hom_alt_post.max(maj_het_post).max(maj_hom_ref_post);
-            if maj_hom_alt_post > gt_threshold {
-                gt_majority = "1/1";
-            } else if maj_het_post > gt_threshold {
-                gt_majority = "0/1";
-            } else if maj_hom_ref_post > gt_threshold {
-                gt_majority = "0/0";
+            let info = line.split('\t').nth(7).unwrap_or(".");
+            let allele_frequency = genotype::info_allele_frequency(info, &params.af_field);
+            let mut samples: Vec<String> = Vec::new();
+            let mut genotypes: Vec<String> = Vec::new();
+            for alleles in [&locus_alleles_majority[record_index], &locus_alleles_minority[record_index]].iter() {
+                let call = genotype::call_genotype(&genotype_model, alleles.alt_count, alleles.ref_count, soup_frac, allele_frequency, params.genotype_threshold);
+                let gt = match call.genotype {
+                    Some(genotype) => genotype::GENOTYPES[genotype],
+                    None => "./.",
+                };
+                let pl: Vec<String> = genotype::phred_likelihoods(&call).iter().map(|pl| pl.to_string()).collect();
+                let gp: Vec<String> = call.posteriors.iter().map(|posterior| format!("{:.6}", posterior)).collect();
+                samples.push(format!("{}:{}:{}:{}:{}", gt, gp.join(","), pl.join(","), alleles.alt_count, alleles.ref_count));
+                genotypes.push(gt.to_string());
             }
-            //line.pop(); // remove trailing newline
-            let line = format!("{}\tGT:GP:AO:RO\t{}:{}:{}:{}\t{}:{}:{}:{}\n", line, gt_majority, max_post_majority, majority_alt, majority_ref,  gt_minority, max_post_minority, minority_alt, minority_ref);
-            writer.write_all(line.as_bytes()).expect("could not write to vcf");
+            let mut toks: Vec<String> = line.split('\t').take(8).map(|tok| tok.to_string()).collect();
+            let info = format!("CELLECTOR_AO={};CELLECTOR_RO={};CELLECTOR_LOCUS={}", total_alt, total_ref, locus_status[record_index]);
+            toks[7] = if toks[7] == "." || toks[7].is_empty() { info } else { format!("{};{}", toks[7], info) };
+            let line = format!("{}\tGT:GP:PL:AO:RO\t{}\t{}\n", toks.join("\t"), samples[0], samples[1]);
+            writer.write_all(line.as_bytes())?;
+            *genotype_pair_counts.entry((genotypes[0].clone(), genotypes[1].clone())).or_insert(0) += 1;
             record_index += 1;
         }
     }
-} 
+    return Ok(num_multiallelic);
+}
+
+// 0 (minority), 1 (majority), doublet or unassigned
+fn posterior_assignment(params: &Params, posterior: f64, doublet_posterior: f64, num_loci: usize) -> &'static str {
+    let mut posterior_assignment = "unassigned";
+    if posterior > params.posterior_threshold {
+        posterior_assignment = "0";
+    } else if 1.0 - posterior > params.posterior_threshold {
+        posterior_assignment = "1";
+    }
+    if doublet_posterior > 0.5 {
+        posterior_assignment = "doublet";
+    }
+    if num_loci < params.min_loci_used { posterior_assignment = "unassigned"; }
+    return posterior_assignment;
+}
 
-fn output_final_assignments(params: &Params, cell_data: &Vec<CellData>, posteriors: &Vec<f64>, doublet_posteriors: &Vec<f64>, log_likelihoods: &Vec<(f64, f64)>, excluded_cells: &HashSet<usize>, normalized_log_likelihoods: &Vec<f64>, loci_used_per_cell: &Vec<f64>) {
+fn output_final_assignments(params: &Params, cell_data: &Vec<CellData>, channel_cells: Option<ChannelCells>, cell_num_loci: &Vec<usize>, posteriors: &Vec<f64>, doublet_posteriors: &Vec<f64>, log_likelihoods: &Vec<(f64, f64)>, excluded_cells: &HashSet<usize>, normalized_log_likelihoods: &Vec<f64>, loci_used_per_cell: &Vec<f64>) -> HashMap<String, HashMap<String, usize>> {
     let filename = format!("{}/cellector_assignments.tsv",params.output_directory);
     let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
     let mut writer = BufWriter::new(filehandle);
-    let header = format!("barcode\tposterior_assignment\tanomally_assignment\tlog_likelihood_loci_normalized\tloci_used\tposterior_assign_qual\tmajority_log_likelihood\tminority_log_likelihood\tground_truth_assignment\n");
+    let mut header = format!("barcode\tposterior_assignment\tanomally_assignment\tlog_likelihood_loci_normalized\tloci_used\tposterior_assign_qual\tmajority_log_likelihood\tminority_log_likelihood\tground_truth_assignment");
+    if params.cell_types.is_some() { header.push_str("\tcell_type"); }
+    header.push('\n');
     writer.write_all(header.as_bytes()).expect("could not write to cellector assignment file");
     let mut assignment_gt_counts: HashMap<String, HashMap<String, usize>> = HashMap::new();
     let mut gt_counts: HashMap<String, usize> = HashMap::new();
+    let mut assignments: Vec<String> = Vec::new();
+    let mut quals: Vec<usize> = Vec::new();
+    let mut lines: Vec<String> = Vec::new();
     for cell_id in 0..cell_data.len() {
         let cell = &cell_data[cell_id];
-        let mut posterior_assignment = "unassigned";
-        //let mut assignment = "unassigned";
-        if posteriors[cell_id] > params.posterior_threshold {
-            posterior_assignment = "0";
-        } else if 1.0 - posteriors[cell_id] > params.posterior_threshold {
-            posterior_assignment = "1";
-        }
-        if doublet_posteriors[cell_id] > 0.5 {
-            posterior_assignment = "doublet";
-        }
-        if cell.cell_loci_data.len() < params.min_loci_used { posterior_assignment = "unassigned"; }
+        let posterior_assignment = posterior_assignment(params, posteriors[cell_id], doublet_posteriors[cell_id], cell_num_loci[cell_id]);
         let counts = assignment_gt_counts.entry(posterior_assignment.to_string()).or_insert(HashMap::new());
         let count = counts.entry(cell.assignment.clone()).or_insert(0);
         *count += 1;
@@ -167,10 +444,30 @@ fn output_final_assignments(params: &Params, cell_data: &Vec<CellData>, posterio
         let post = posteriors[cell_id].max(1.0-posteriors[cell_id]);
         let qual = -10.0 * (1.0 - post).log10();
         let qual = qual.min(255.0) as usize;
-        let line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", cell.barcode, posterior_assignment, anomally_assignment, normalized_log_likelihoods[cell_id], loci_used_per_cell[cell_id] as usize, qual, log_likelihoods[cell_id].0, log_likelihoods[cell_id].1, cell.assignment);
+        let mut line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", cell.barcode, posterior_assignment, anomally_assignment, normalized_log_likelihoods[cell_id], loci_used_per_cell[cell_id] as usize, qual, log_likelihoods[cell_id].0, log_likelihoods[cell_id].1, cell.assignment);
+        if params.cell_types.is_some() { line.push_str(&format!("\t{}", cell.cell_type)); }
+        line.push('\n');
         writer.write_all(line.as_bytes()).expect("could not write to cellector assignment file");
+        if channel_cells.is_some() { lines.push(line); }
+        assignments.push(posterior_assignment.to_string());
+        quals.push(qual);
     }
-    pretty_print(params, assignment_gt_counts, gt_counts);
+    if let Some((channels, channel_cells)) = channel_cells { joint::write_channel_assignments(params, channels, channel_cells, &header, &lines); }
+    if params.write_obs {
+        let anomalous: Vec<bool> = (0..cell_data.len()).map(|cell_id| excluded_cells.contains(&cell_id)).collect();
+        let written = obs::write_obs(params, cell_data, &obs::CellResults {
+            assignments: &assignments,
+            posteriors: posteriors,
+            doublet_posteriors: doublet_posteriors,
+            anomalous: &anomalous,
+            normalized_log_likelihoods: normalized_log_likelihoods,
+            loci_used: loci_used_per_cell,
+            quals: &quals,
+        });
+        if let Err(error) = written { eprintln!("ERROR: {}", error); }
+    }
+    pretty_print(params, assignment_gt_counts.clone(), gt_counts);
+    return assignment_gt_counts;
 }
 
 //TODO add detailed output for incorrectly assigned cells
@@ -225,73 +522,151 @@ fn pretty_print(params: &Params, assignment_gt_counts: HashMap<String, HashMap<S
     println!("\n\n{}",string_build);
 } 
 
-fn calculate_posteriors(params: &Params, loci_used: &Vec<bool>, cell_data: &Vec<CellData>, locus_counts: &Vec<[f64;2]>, excluded_cells: &HashSet<usize>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> (Vec<f64>, Vec<f64>, Vec<(f64, f64)>) {
-    let mut posteriors: Vec<f64> = Vec::new();
-    let mut doublet_posteriors: Vec<f64> = Vec::new();
-
-    let mut log_likelihoods: Vec<(f64, f64)> = Vec::new();
+fn calculate_posteriors(params: &Params, loci_used: &Vec<bool>, locus_ids: &Vec<usize>, cell_data: &Vec<CellData>, counts: &CountStore, locus_counts: &Vec<[f64;2]>, excluded_cells: &HashSet<usize>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> (Vec<f64>, Vec<f64>, Vec<(f64, f64)>, Option<(f64, f64)>, classify::FittedModel) {
     let mut included_cells: HashSet<usize> = HashSet::new();
     for cell_id in 0..cell_data.len() {
         if !excluded_cells.contains(&cell_id) {
             included_cells.insert(cell_id);
         }
     }
-    let mut alpha_betas_majority_dist = init_alpha_betas(locus_counts, excluded_cells, cell_data);
+    let alpha_betas_majority_dist = init_alpha_betas(locus_counts, excluded_cells, counts);
     let minority_fraction = (excluded_cells.len() as f64 + 1.0)/(cell_data.len() as f64 + 1.0);
-    let alpha_betas_minority_dist = init_alpha_betas(locus_counts, &included_cells, cell_data);
+    let alpha_betas_minority_dist = init_alpha_betas(locus_counts, &included_cells, counts);
+    let (alpha_betas_minority_dist, alpha_betas_majority_dist, alpha_betas_doublet_dist) = posterior_alpha_betas(minority_fraction, alpha_betas_minority_dist, alpha_betas_majority_dist);
+    let loci_used_for_posteriors: Vec<bool> = get_loci_used_for_posterior_calc(params, loci_used, &excluded_locus_counts(counts, loci_used.len(), excluded_cells), locus_counts);
+    let minority_dist_likelihoods: CellLogLikelihoodData = get_cell_log_likelihoods(&loci_used_for_posteriors, locus_ids, counts, &alpha_betas_minority_dist, excluded_cells, precomputed_log_binomial_coefficients);
+    let majority_dist_likelihoods: CellLogLikelihoodData = get_cell_log_likelihoods(&loci_used_for_posteriors, locus_ids, counts, &alpha_betas_majority_dist, &included_cells, precomputed_log_binomial_coefficients);
+    let doublet_dist_likelihoods: CellLogLikelihoodData = get_cell_log_likelihoods(&loci_used_for_posteriors, locus_ids, counts, &alpha_betas_doublet_dist, &included_cells, precomputed_log_binomial_coefficients);
+    let minority_fraction = minority_fraction.max(0.01);
+    // with --fit_mode em the interval is computed on the em fit instead
+    let locus_bootstrap_interval = match (params.locus_bootstrap_iterations, params.fit_mode.as_str()) {
+        (0, _) | (_, "em") => None,
+        _ => Some(locus_bootstrap_interval(params, counts, &loci_used_for_posteriors, minority_fraction,
+            [&minority_dist_likelihoods.all_pmfs, &majority_dist_likelihoods.all_pmfs, &doublet_dist_likelihoods.all_pmfs])),
+    };
+    let (posteriors, doublet_posteriors, log_likelihoods) = combine_posteriors(minority_fraction, &minority_dist_likelihoods.log_likelihoods, &majority_dist_likelihoods.log_likelihoods, &doublet_dist_likelihoods.log_likelihoods);
+    // with --fit_mode em this is only the starting point and the em fit replaces it
+    let model = classify::FittedModel {
+        minority_fraction: minority_fraction,
+        loci_used: loci_used_for_posteriors,
+        distributions: [alpha_betas_minority_dist, alpha_betas_majority_dist, alpha_betas_doublet_dist],
+    };
+    return (posteriors, doublet_posteriors, log_likelihoods, locus_bootstrap_interval, model);
+}
+
+// 95% percentile interval of the minority fraction when the loci used for the posteriors are resampled with
+// replacement, each cell's log likelihoods are the sums of its per locus log pmfs weighted by how often each locus was
+// drawn. pmfs are the minority, majority and doublet pmfs of get_cell_log_likelihoods, which are in the same order
+fn locus_bootstrap_interval(params: &Params, counts: &CountStore, loci_used_for_posteriors: &Vec<bool>, minority_fraction: f64, pmfs: [&Vec<PMFData>; 3]) -> (f64, f64) {
+    let mut rng: StdRng = StdRng::seed_from_u64(params.seed);
+    let used_loci: Vec<usize> = (0..loci_used_for_posteriors.len()).filter(|locus_index| loci_used_for_posteriors[*locus_index]).collect();
+    let cell_num_loci: Vec<usize> = (0..counts.total_cells).map(|cell_id| counts.cell_num_loci(cell_id)).collect();
+    let mut fractions: Vec<f64> = Vec::new();
+    for _ in 0..params.locus_bootstrap_iterations {
+        let mut weights: Vec<f64> = vec![0.0; loci_used_for_posteriors.len()];
+        for _ in 0..used_loci.len() { weights[used_loci[rng.gen_range(0..used_loci.len())]] += 1.0; }
+        let mut log_likelihoods: Vec<Vec<f64>> = vec![vec![0.0; counts.total_cells]; 3];
+        for (dist, dist_pmfs) in pmfs.iter().enumerate() {
+            for pmf in dist_pmfs.iter() {
+                log_likelihoods[dist][pmf.cell_id] += weights[pmf.locus_index] * pmf.log_pmf;
+            }
+        }
+        let (posteriors, doublet_posteriors, _) = combine_posteriors(minority_fraction, &log_likelihoods[0], &log_likelihoods[1], &log_likelihoods[2]);
+        let probabilities: Vec<f64> = singlet_minority_probabilities(params, &posteriors, &doublet_posteriors, &cell_num_loci).into_iter().flatten().collect();
+        fractions.push(probabilities.iter().fold(0.0, |sum, probability| sum + probability) / (probabilities.len().max(1) as f64));
+    }
+    return stats::percentile_interval(fractions).expect("the locus bootstrap needs --locus_bootstrap_iterations above 0");
+}
+
+// (minority, majority, doublet) distributions for the posteriors from the minority and majority cells' alpha betas,
+// the majority pseudocounts are scaled down to the size of the minority
+fn posterior_alpha_betas(minority_fraction: f64, alpha_betas_minority_dist: Vec<AlphaBeta>, mut alpha_betas_majority_dist: Vec<AlphaBeta>) -> (Vec<AlphaBeta>, Vec<AlphaBeta>, Vec<AlphaBeta>) {
     let mut alpha_betas_doublet_dist: Vec<AlphaBeta> = Vec::new();
 
-    for locus in 0..loci_used.len() {
+    for locus in 0..alpha_betas_majority_dist.len() {
         let alpha = (alpha_betas_majority_dist[locus].alpha - 1.0) * minority_fraction + (alpha_betas_minority_dist[locus].alpha - 1.0) + 1.0;
         let beta = (alpha_betas_majority_dist[locus].beta - 1.0) * minority_fraction + (alpha_betas_minority_dist[locus].beta - 1.0) + 1.0;
         alpha_betas_doublet_dist.push(AlphaBeta{alpha: alpha, beta: beta});
     }
 
     let minority_fraction = minority_fraction.max(0.01);
-    for locus in 0..loci_used.len() {
+    for locus in 0..alpha_betas_majority_dist.len() {
         alpha_betas_majority_dist[locus].alpha = (alpha_betas_majority_dist[locus].alpha - 1.0) * minority_fraction + 1.0;
         alpha_betas_majority_dist[locus].beta = (alpha_betas_majority_dist[locus].beta - 1.0) * minority_fraction + 1.0;
     }
-        let loci_used_for_posteriors: Vec<bool> = get_loci_used_for_posterior_calc(params, loci_used, cell_data, excluded_cells, locus_counts);
-    let minority_dist_likelihoods: CellLogLikelihoodData = get_cell_log_likelihoods(&loci_used_for_posteriors, cell_data, &alpha_betas_minority_dist, excluded_cells, precomputed_log_binomial_coefficients);
-    let majority_dist_likelihoods: CellLogLikelihoodData = get_cell_log_likelihoods(&loci_used_for_posteriors, cell_data, &alpha_betas_majority_dist, &included_cells, precomputed_log_binomial_coefficients);
-    let doublet_dist_likelihoods: CellLogLikelihoodData = get_cell_log_likelihoods(&loci_used_for_posteriors, cell_data, &alpha_betas_doublet_dist, &included_cells, precomputed_log_binomial_coefficients);
-    let log_prior_doublet: f64 = ((cell_data.len() as f64)/1000.0/100.0*minority_fraction.max(0.1)).ln(); // 1% doublets per 1000 cells so if there are 10000 cells we have 10000/1000 = 10% so for .1 we div by 100 again.
+    return (alpha_betas_minority_dist, alpha_betas_majority_dist, alpha_betas_doublet_dist);
+}
+
+// log (minority, majority, doublet) priors of a cell
+fn log_priors(minority_fraction: f64, num_cells: usize) -> (f64, f64, f64) {
+    let log_prior_doublet: f64 = ((num_cells as f64)/1000.0/100.0*minority_fraction.max(0.1)).ln(); // 1% doublets per 1000 cells so if there are 10000 cells we have 10000/1000 = 10% so for .1 we div by 100 again.
     // then we need to consider that we can only detect cross genotype doublets. So multiply
     // by minority fraction. But if the minority fraction is like 1% or .1%, there is basically no way we will 
     // have enough evidence to detect the true doublets so limit minority fraction to 10%
     
     let log_prior_minority: f64 = minority_fraction.ln();
     let log_prior_majority: f64 = (1.0 - minority_fraction).ln();
-    for cell_id in 0..cell_data.len() {
-        let log_numerator = log_prior_minority + minority_dist_likelihoods.log_likelihoods[cell_id];
-        let mut log_denominator = stats::logsumexp(log_numerator, log_prior_majority + majority_dist_likelihoods.log_likelihoods[cell_id]);
+    return (log_prior_minority, log_prior_majority, log_prior_doublet);
+}
+
+// posteriors of the minority and of a doublet, and the (majority, minority) log likelihoods, per cell
+fn combine_posteriors(minority_fraction: f64, minority_log_likelihoods: &Vec<f64>, majority_log_likelihoods: &Vec<f64>, doublet_log_likelihoods: &Vec<f64>) -> (Vec<f64>, Vec<f64>, Vec<(f64, f64)>) {
+    return combine_posteriors_with_priors(log_priors(minority_fraction, minority_log_likelihoods.len()), minority_log_likelihoods, majority_log_likelihoods, doublet_log_likelihoods);
+}
+
+// combine_posteriors with the (minority, majority, doublet) log priors given, em.rs estimates them as mixing weights
+fn combine_posteriors_with_priors(log_priors: (f64, f64, f64), minority_log_likelihoods: &Vec<f64>, majority_log_likelihoods: &Vec<f64>, doublet_log_likelihoods: &Vec<f64>) -> (Vec<f64>, Vec<f64>, Vec<(f64, f64)>) {
+    let mut posteriors: Vec<f64> = Vec::new();
+    let mut doublet_posteriors: Vec<f64> = Vec::new();
+    let mut log_likelihoods: Vec<(f64, f64)> = Vec::new();
+    let (log_prior_minority, log_prior_majority, log_prior_doublet) = log_priors;
+    for cell_id in 0..minority_log_likelihoods.len() {
+        let log_numerator = log_prior_minority + minority_log_likelihoods[cell_id];
+        let mut log_denominator = stats::logsumexp(&[log_numerator, log_prior_majority + majority_log_likelihoods[cell_id]]);
         
-        let log_doublet_numerator = log_prior_doublet + doublet_dist_likelihoods.log_likelihoods[cell_id];
-        log_denominator = stats::logsumexp(log_denominator, log_doublet_numerator);
+        let log_doublet_numerator = log_prior_doublet + doublet_log_likelihoods[cell_id];
+        log_denominator = stats::logsumexp(&[log_denominator, log_doublet_numerator]);
         let log_minority_posterior = log_numerator - log_denominator;
         let posterior = log_minority_posterior.exp();
         posteriors.push(posterior);
         let doublet_posterior = (log_doublet_numerator - log_denominator).exp();
         doublet_posteriors.push(doublet_posterior);
-        log_likelihoods.push((majority_dist_likelihoods.log_likelihoods[cell_id], minority_dist_likelihoods.log_likelihoods[cell_id]));
+        log_likelihoods.push((majority_log_likelihoods[cell_id], minority_log_likelihoods[cell_id]));
     }
     return (posteriors, doublet_posteriors, log_likelihoods);
 }
 
-fn get_loci_used_for_posterior_calc(params: &Params, loci_used: &Vec<bool>, cell_data: &Vec<CellData>, excluded_cells: &HashSet<usize>, locus_counts: &Vec<[f64; 2]>) -> Vec<bool> {
-    let mut locus_counts_minority: Vec<[usize; 2]> = Vec::new();
-    let mut loci_used_for_posteriors: Vec<bool> = Vec::new();
-    for _i in 0..loci_used.len() {
-        locus_counts_minority.push([0;2]);
+const MIN_CELLS_PER_STRATUM: usize = 30;
+const MAX_CELL_TYPE_IQR_RATIO: f64 = 2.0;
+
+// each assignable cell's probability of being minority rather than majority, None for doublets and cells with fewer
+// than min_loci_used loci as they are left out of the minority fraction (same rules as cellector_assignments.tsv)
+fn singlet_minority_probabilities(params: &Params, posteriors: &Vec<f64>, doublet_posteriors: &Vec<f64>, cell_num_loci: &Vec<usize>) -> Vec<Option<f64>> {
+    let mut probabilities: Vec<Option<f64>> = Vec::new();
+    for cell_id in 0..posteriors.len() {
+        if doublet_posteriors[cell_id] > 0.5 || cell_num_loci[cell_id] < params.min_loci_used {
+            probabilities.push(None);
+        } else {
+            probabilities.push(Some((posteriors[cell_id] / (1.0 - doublet_posteriors[cell_id])).min(1.0)));
+        }
     }
+    return probabilities;
+}
+
+// [ref, alt] umis of the excluded cells per used locus
+fn excluded_locus_counts(counts: &CountStore, num_loci: usize, excluded_cells: &HashSet<usize>) -> Vec<[usize; 2]> {
+    let mut locus_counts_minority: Vec<[usize; 2]> = vec![[0; 2]; num_loci];
     for cell_id in excluded_cells {
-        let cell = &cell_data[*cell_id];
-        for locus in &cell.cell_loci_data {
-            locus_counts_minority[locus.locus_index][0] += locus.ref_count.round() as usize;
-            locus_counts_minority[locus.locus_index][1] += locus.alt_count.round() as usize;
+        for (locus_index, ref_count, alt_count) in counts.cell_entries(*cell_id) {
+            locus_counts_minority[locus_index][0] += ref_count as usize;
+            locus_counts_minority[locus_index][1] += alt_count as usize;
         }
     }
+    return locus_counts_minority;
+}
+
+fn get_loci_used_for_posterior_calc(params: &Params, loci_used: &Vec<bool>, locus_counts_minority: &Vec<[usize; 2]>, locus_counts: &Vec<[f64; 2]>) -> Vec<bool> {
+    let mut loci_used_for_posteriors: Vec<bool> = Vec::new();
     for locus_index in 0..loci_used.len() {
         let minority_alt = locus_counts_minority[locus_index][1];
         let minority_ref = locus_counts_minority[locus_index][0];
@@ -305,34 +680,116 @@ fn get_loci_used_for_posterior_calc(params: &Params, loci_used: &Vec<bool>, cell
     return loci_used_for_posteriors;
 }
 
-fn compute_new_excluded(params: &Params, loci_used: &mut Vec<bool>, locus_ids: &Vec<usize>, cell_data: &Vec<CellData>, locus_counts: &Vec<[f64; 2]>, excluded_cells: &HashSet<usize>, iteration: usize, vcf_data: &Option<Vec<VcfLocusData>>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> (bool, HashSet<usize>, Vec<f64>, Vec<f64>) {
-    let alpha_betas = init_alpha_betas(locus_counts, excluded_cells, cell_data);
-    let mut new_excluded: HashSet<usize> = HashSet::new();
+fn compute_new_excluded(params: &Params, loci_used: &mut Vec<bool>, locus_ids: &Vec<usize>, cell_data: &Vec<CellData>, counts: &CountStore, locus_counts: &Vec<[f64; 2]>, excluded_cells: &HashSet<usize>, iteration: usize, vcf_data: &Option<Vec<VcfLocusData>>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> (bool, HashSet<usize>, Vec<f64>, Vec<f64>, IterationSummary) {
+    let alpha_betas = init_alpha_betas(locus_counts, excluded_cells, counts);
 
-    let cell_log_likelihood_data = get_cell_log_likelihoods(loci_used, cell_data, &alpha_betas, excluded_cells, precomputed_log_binomial_coefficients);
+    let cell_log_likelihood_data = get_cell_log_likelihoods(loci_used, locus_ids, counts, &alpha_betas, excluded_cells, precomputed_log_binomial_coefficients);
+    let (new_excluded, normalized_log_likelihoods, cell_thresholds, mut summary) = find_outlier_cells(params, cell_data, &cell_log_likelihood_data, excluded_cells, iteration);
+    let locus_data = get_locus_log_likelihoods(&cell_log_likelihood_data.all_pmfs, cell_data, loci_used, &new_excluded);
+    locus_filter_and_output_locus_data(params, loci_used, &locus_data, locus_ids, vcf_data, iteration);
+    output_iteration_tsv(params, cell_data, &cell_log_likelihood_data, &cell_thresholds, &summary, iteration);
+    summary.num_loci_used = loci_used.iter().filter(|used| **used).count();
+    let any_change = summary.num_new_anomalous > 0 || summary.num_rescued > 0;
+    return (any_change, new_excluded, normalized_log_likelihoods, cell_log_likelihood_data.loci_used_per_cell, summary);
+}
+
+// cells whose log likelihood / loci used is below the first quartile minus interquartile_range_multiple times the
+// interquartile range, with the normalized log likelihoods, the threshold each cell was held to and the iteration
+// summary (num_loci_used is left to the caller). cells with fewer than min_loci_for_threshold used loci are left out of
+// the quartiles and never called outliers. the spread of the normalized log likelihood shrinks with the number of loci
+// so with --depth_bins the cells are split into that many bins of about the same size by loci used, and with
+// --stratify_threshold by cell type, and every group with at least MIN_CELLS_PER_STRATUM cells gets its own quartiles
+// (smaller groups use the overall threshold). minority cells widen the quartiles of a cell type they are enriched in and
+// hide themselves, so a cell type whose interquartile range is more than MAX_CELL_TYPE_IQR_RATIO times that of all the
+// cells of its depth bin uses the threshold of the depth bin instead
+fn find_outlier_cells(params: &Params, cell_data: &Vec<CellData>, cell_log_likelihood_data: &CellLogLikelihoodData, excluded_cells: &HashSet<usize>, iteration: usize) -> (HashSet<usize>, Vec<f64>, Vec<CellThreshold>, IterationSummary) {
+    let mut new_excluded: HashSet<usize> = HashSet::new();
     let mut normalized_log_likelihoods: Vec<f64> = Vec::new();
-    for i in 0..cell_data.len() {
-        if cell_log_likelihood_data.loci_used_per_cell[i] > 0.0 {
-            normalized_log_likelihoods.push(cell_log_likelihood_data.log_likelihoods[i] / cell_log_likelihood_data.loci_used_per_cell[i]);
+    let loci_used_per_cell = &cell_log_likelihood_data.loci_used_per_cell;
+    for i in 0..cell_log_likelihood_data.log_likelihoods.len() {
+        if loci_used_per_cell[i] > 0.0 {
+            normalized_log_likelihoods.push(cell_log_likelihood_data.log_likelihoods[i] / loci_used_per_cell[i]);
             //normalized_log_likelihoods.push((cell_log_likelihood_data.log_likelihoods[i] - cell_log_likelihood_data.expected_log_likelihoods[i]) /
             //    cell_log_likelihood_data.expected_log_variances[i].sqrt());
         
-        } else { // need to filter cells with < some number of used loci TODO
+        } else {
             normalized_log_likelihoods.push(0.0);
         }
     }
-    let mut normalized_tmp = Data::new(normalized_log_likelihoods.clone());
+    let mut eligible: Vec<bool> = loci_used_per_cell.iter().map(|loci| *loci > 0.0 && *loci >= params.min_loci_for_threshold as f64).collect();
+    if !eligible.contains(&true) {
+        eligible = loci_used_per_cell.iter().map(|loci| *loci > 0.0).collect();
+        if eligible.contains(&true) {
+            println!("no cell has {} or more loci, using every cell with loci for the threshold", params.min_loci_for_threshold);
+        } else {
+            println!("WARNING: no cell has any used loci, no outliers will be called");
+        }
+    }
+    let eligible_cells: Vec<usize> = (0..normalized_log_likelihoods.len()).filter(|cell_id| eligible[*cell_id]).collect();
+    // the overall quartiles of all cells (all 0) if none has loci, so the threshold is still a number
+    let quartile_cells: Vec<usize> = if eligible_cells.is_empty() { (0..normalized_log_likelihoods.len()).collect() } else { eligible_cells.clone() };
+    let mut normalized_tmp = Data::new(quartile_cells.iter().map(|cell_id| normalized_log_likelihoods[*cell_id]).collect::<Vec<f64>>());
     let median = normalized_tmp.median();
     let q1 = normalized_tmp.lower_quartile();
     let q3 = normalized_tmp.upper_quartile();
     let iqr = q3 - q1;
     let threshold = q1 - params.interquartile_range_multiple * iqr;
+
+    let edges = depth_bin_edges(eligible_cells.iter().map(|cell_id| loci_used_per_cell[*cell_id]).collect(), params.depth_bins);
+    let mut groups: HashMap<(String, usize), Vec<usize>> = HashMap::new();
+    let mut bins: HashMap<usize, Vec<usize>> = HashMap::new();
+    for cell_id in &eligible_cells {
+        let cell_type = if params.stratify_threshold { cell_data[*cell_id].cell_type.clone() } else { "all".to_string() };
+        let bin = edges.iter().filter(|edge| loci_used_per_cell[*cell_id] >= **edge).count();
+        groups.entry((cell_type, bin)).or_insert(Vec::new()).push(*cell_id);
+        bins.entry(bin).or_insert(Vec::new()).push(*cell_id);
+    }
+    // interquartile range and threshold of all the cell types in each depth bin, to check the cell type groups against
+    let mut bin_thresholds: HashMap<usize, (f64, f64)> = HashMap::new();
+    for (bin, cells) in &bins {
+        let mut bin_threshold = (iqr, threshold);
+        if bins.len() > 1 && cells.len() >= MIN_CELLS_PER_STRATUM {
+            let mut group = Data::new(cells.iter().map(|cell_id| normalized_log_likelihoods[*cell_id]).collect::<Vec<f64>>());
+            let (bin_q1, bin_q3) = (group.lower_quartile(), group.upper_quartile());
+            bin_threshold = (bin_q3 - bin_q1, bin_q1 - params.interquartile_range_multiple * (bin_q3 - bin_q1));
+        }
+        bin_thresholds.insert(*bin, bin_threshold);
+    }
+    let mut cell_thresholds: Vec<CellThreshold> = (0..normalized_log_likelihoods.len()).map(|_| CellThreshold { group: "low_loci".to_string(), threshold: None }).collect();
+    let mut group_thresholds: Vec<GroupThreshold> = Vec::new();
+    let mut keys: Vec<&(String, usize)> = groups.keys().collect();
+    keys.sort();
+    for key in keys {
+        let cells = &groups[key];
+        let loci_range = cells.iter().fold((f64::MAX, 0.0f64), |(low, high), cell_id| (low.min(loci_used_per_cell[*cell_id]), high.max(loci_used_per_cell[*cell_id])));
+        let mut label = key.0.clone();
+        if params.depth_bins > 1 { label = format!("{}:{}-{}_loci", label, loci_range.0, loci_range.1); }
+        let mut group_threshold = threshold;
+        if groups.len() > 1 {
+            if cells.len() < MIN_CELLS_PER_STRATUM {
+                println!("\t{} has {} cells, using the overall threshold", label, cells.len());
+            } else {
+                let mut group = Data::new(cells.iter().map(|cell_id| normalized_log_likelihoods[*cell_id]).collect::<Vec<f64>>());
+                let (group_q1, group_q3) = (group.lower_quartile(), group.upper_quartile());
+                group_threshold = group_q1 - params.interquartile_range_multiple * (group_q3 - group_q1);
+                println!("\t{} ({} cells) median {} with interquartile range {}, threshold {}", label, cells.len(), group.median(), group_q3 - group_q1, group_threshold);
+                let (bin_iqr, bin_threshold) = bin_thresholds[&key.1];
+                if params.stratify_threshold && group_q3 - group_q1 > MAX_CELL_TYPE_IQR_RATIO * bin_iqr {
+                    group_threshold = bin_threshold;
+                    println!("\t{} interquartile range is more than {} times the {} of all cell types, it may be minority enriched, using their threshold {}", label, MAX_CELL_TYPE_IQR_RATIO, bin_iqr, group_threshold);
+                }
+            }
+        }
+        group_thresholds.push(GroupThreshold { group: label.clone(), num_cells: cells.len(), threshold: group_threshold });
+        for cell_id in cells { cell_thresholds[*cell_id] = CellThreshold { group: label.clone(), threshold: Some(group_threshold) }; }
+    }
     for (cell_id, normalized_likelihood) in normalized_log_likelihoods.iter().enumerate() {
-        if *normalized_likelihood < threshold { new_excluded.insert(cell_id); }
+        if let Some(cell_threshold) = cell_thresholds[cell_id].threshold {
+            if *normalized_likelihood < cell_threshold { new_excluded.insert(cell_id); }
+        }
     }
     let num_new_cells_excluded = new_excluded.difference(&excluded_cells).collect::<Vec<&usize>>().len();
     let num_cells_rescued = excluded_cells.difference(&new_excluded).collect::<Vec<&usize>>().len();
-    let any_change = num_new_cells_excluded > 0 || num_cells_rescued > 0;
     
     
     println!("detected {} new anomylous cells and rescued {} cells to the majority in iteration {}", num_new_cells_excluded, num_cells_rescued, iteration+1);
@@ -340,29 +797,62 @@ fn compute_new_excluded(params: &Params, loci_used: &mut Vec<bool>, locus_ids: &
     //for cell_id in &new_excluded {
         //println!("\tnew excluded cell {} with {} loci, log likelihood {}",cell_id,cell_log_likelihood_data.loci_used_per_cell[*cell_id],normalized_log_likelihoods[*cell_id]);
     //}
-    let locus_data = get_locus_log_likelihoods(&cell_log_likelihood_data.all_pmfs, cell_data, loci_used, &new_excluded);
-    locus_filter_and_output_locus_data(params, loci_used, &locus_data, locus_ids, vcf_data, iteration);
-    output_iteration_tsv(params, cell_data, &cell_log_likelihood_data, threshold, iteration);
-    return (any_change, new_excluded, normalized_log_likelihoods, cell_log_likelihood_data.loci_used_per_cell);
+    let summary = IterationSummary {
+        num_anomalous: new_excluded.len(),
+        num_new_anomalous: num_new_cells_excluded,
+        num_rescued: num_cells_rescued,
+        median: median,
+        interquartile_range: iqr,
+        threshold: threshold,
+        group_thresholds: group_thresholds,
+        num_loci_used: 0,
+    };
+    return (new_excluded, normalized_log_likelihoods, cell_thresholds, summary);
+}
+
+// the --depth_bins edges at the loci used quantiles of the cells. many cells share a loci count at low depth, so
+// repeated edges (and one at the minimum, which would leave the first bin empty) are dropped and there can be fewer
+// bins than asked for
+fn depth_bin_edges(mut loci_used: Vec<f64>, depth_bins: usize) -> Vec<f64> {
+    if loci_used.is_empty() { return Vec::new(); }
+    loci_used.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut edges: Vec<f64> = (1..depth_bins).map(|bin| loci_used[bin * loci_used.len() / depth_bins]).filter(|edge| *edge > loci_used[0]).collect();
+    edges.dedup();
+    if edges.len() + 1 < depth_bins {
+        println!("{} effective depth bins of the {} asked for, many cells have the same number of loci", edges.len() + 1, depth_bins);
+    }
+    return edges;
+}
+
+// the outlier threshold group (cell type and/or depth bin, all, or low_loci) of a cell and the threshold it was held to
+struct CellThreshold {
+    group: String,
+    threshold: Option<f64>,
 }
 
-fn output_iteration_tsv(params: &Params, cell_data: &Vec<CellData>, cell_log_likelihood_data: &CellLogLikelihoodData, threshold: f64, iteration: usize) {
+fn output_iteration_tsv(params: &Params, cell_data: &Vec<CellData>, cell_log_likelihood_data: &CellLogLikelihoodData, cell_thresholds: &Vec<CellThreshold>, summary: &IterationSummary, iteration: usize) {
     let filename = format!("{}/iteration_{}.tsv",params.output_directory, iteration);
     let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
     let mut writer = BufWriter::new(filehandle);
-    let header = format!("cell_id\tbarcode\tassignment\tlog_likelihood\texpected_log_likelihood\tnum_loci_used\n");
+    let header = format!("cell_id\tbarcode\tassignment\tlog_likelihood\texpected_log_likelihood\tnum_loci_used\tthreshold_group\tthreshold\n");
     writer.write_all(header.as_bytes()).expect("could not write to iteration tsv file");
     for cell_id in 0..cell_data.len() {
         let cell = &cell_data[cell_id];
         assert!(cell.cell_id == cell_id, "I did something wrong, cell_id != cell_data[cell_id].cell_id");
-        let line = format!("{}\t{}\t{}\t{}\t{}\t{}\n", cell.cell_id, cell.barcode, cell.assignment, cell_log_likelihood_data.log_likelihoods[cell_id], cell_log_likelihood_data.expected_log_likelihoods[cell_id], cell_log_likelihood_data.loci_used_per_cell[cell_id]);
+        let cell_threshold = match cell_thresholds[cell_id].threshold { Some(threshold) => threshold.to_string(), None => "na".to_string() };
+        let line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", cell.cell_id, cell.barcode, cell.assignment, cell_log_likelihood_data.log_likelihoods[cell_id], cell_log_likelihood_data.expected_log_likelihoods[cell_id], cell_log_likelihood_data.loci_used_per_cell[cell_id], cell_thresholds[cell_id].group, cell_threshold);
         writer.write_all(line.as_bytes()).expect("could not write to iteration tsv file"); 
     }
+    // the global threshold, then with --depth_bins or --stratify_threshold the threshold of every group
     let filename = format!("{}/iteration_{}_threshold.tsv",params.output_directory, iteration);
     let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
     let mut writer = BufWriter::new(filehandle);
-    let line = format!("{}",threshold);
-    writer.write_all(line.as_bytes()).expect("could not write to threshold file");
+    let num_thresholded: usize = summary.group_thresholds.iter().map(|group| group.num_cells).sum();
+    let mut lines = format!("group\tcells\tthreshold\nglobal\t{}\t{}\n", num_thresholded, summary.threshold);
+    if summary.group_thresholds.len() > 1 {
+        for group in &summary.group_thresholds { lines.push_str(&format!("{}\t{}\t{}\n", group.group, group.num_cells, group.threshold)); }
+    }
+    writer.write_all(lines.as_bytes()).expect("could not write to threshold file");
 }
 
 fn get_locus_log_likelihoods(all_pmfs: &Vec<PMFData>, cell_data: &Vec<CellData>, loci_used: &Vec<bool>, excluded_cells: &HashSet<usize>) -> LocusLogLikelihoodData {
@@ -538,37 +1028,38 @@ struct PMFData { // this is overkill, but maybe will provide some insight
     expected_log_variance: f64,
 }
 
-fn get_cell_log_likelihoods(loci_used: &Vec<bool>, cell_data: &Vec<CellData>, alpha_betas: &Vec<AlphaBeta>, excluded_cells: &HashSet<usize>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> 
+fn get_cell_log_likelihoods(loci_used: &Vec<bool>, locus_ids: &Vec<usize>, counts: &CountStore, alpha_betas: &Vec<AlphaBeta>, excluded_cells: &HashSet<usize>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> 
         CellLogLikelihoodData {
     let mut cell_log_likelihoods: Vec<f64> = Vec::new();
     let mut cell_loci_used: Vec<f64> = Vec::new();
     let mut cell_expected_log_likelihood: Vec<f64> = Vec::new();
     let mut cell_expected_log_variance: Vec<f64> = Vec::new();
     let mut all_pmfs: Vec<PMFData> = Vec::new();
-    for cell_id in 0..cell_data.len() {
+    for cell_id in 0..counts.total_cells {
         let mut log_likelihood: f64 = 0.0;
         let mut expected_log_likelihood: f64 = 0.0;
         let mut expected_log_variance: f64 = 0.0;
         let mut loci_used_for_cell: f64 = 0.0;
         let excluded: bool = excluded_cells.contains(&cell_id);
-        let cell = &cell_data[cell_id];
-        for locus in &cell.cell_loci_data {
-            if loci_used[locus.locus_index] {
-                let log_pmf = stats::log_beta_binomial_pmf(locus.alt_count, locus.ref_count, alpha_betas[locus.locus_index].alpha, alpha_betas[locus.locus_index].beta, locus.log_binomial_coefficient);
+        for (locus_index, ref_count, alt_count) in counts.cell_entries(cell_id) {
+            if loci_used[locus_index] {
+                let total = (ref_count + alt_count) as usize;
+                let log_binomial_coefficient = stats::log_binomial_coefficient(total, alt_count as usize, precomputed_log_binomial_coefficients);
+                let log_pmf = stats::log_beta_binomial_pmf(alt_count as f64, ref_count as f64, alpha_betas[locus_index].alpha, alpha_betas[locus_index].beta, log_binomial_coefficient);
                 log_likelihood += log_pmf;
-                let expected_log_pmf = stats::expected_log_beta_binomial_pmf(locus.total, alpha_betas[locus.locus_index].alpha, alpha_betas[locus.locus_index].beta, precomputed_log_binomial_coefficients);
+                let expected_log_pmf = stats::expected_log_beta_binomial_pmf(total, alpha_betas[locus_index].alpha, alpha_betas[locus_index].beta, precomputed_log_binomial_coefficients);
                 expected_log_likelihood += expected_log_pmf.expected_log_likelihood;
                 expected_log_variance += expected_log_pmf.expected_log_variance;
                 all_pmfs.push(PMFData{
                     log_pmf: log_pmf,
                     cell_id: cell_id,
                     excluded: excluded,
-                    locus: locus.locus_id,
-                    locus_index: locus.locus_index,
-                    alt_count: locus.alt_count as usize,
-                    ref_count: locus.ref_count as usize,
-                    alpha: alpha_betas[locus.locus_index].alpha,
-                    beta: alpha_betas[locus.locus_index].beta,
+                    locus: locus_ids[locus_index],
+                    locus_index: locus_index,
+                    alt_count: alt_count as usize,
+                    ref_count: ref_count as usize,
+                    alpha: alpha_betas[locus_index].alpha,
+                    beta: alpha_betas[locus_index].beta,
                     expected_log_pmf: expected_log_pmf.expected_log_likelihood,
                     expected_log_variance: expected_log_pmf.expected_log_variance,
                 });
@@ -595,21 +1086,21 @@ struct AlphaBeta {
     beta: f64,
 }
 
-fn init_alpha_betas(locus_counts: &Vec<[f64;2]>, excluded_cells: &HashSet<usize>, cell_data: &Vec<CellData>) -> Vec<AlphaBeta> {
+fn init_alpha_betas(locus_counts: &Vec<[f64;2]>, excluded_cells: &HashSet<usize>, counts: &CountStore) -> Vec<AlphaBeta> {
     let mut alpha_betas: Vec<AlphaBeta> = Vec::new();
     for counts in locus_counts {
         alpha_betas.push(AlphaBeta{alpha: counts[1] + 1.0, beta: counts[0] + 1.0});
     }
     for excluded_cell in excluded_cells {
-        let cell = &cell_data[*excluded_cell];
-        for locus_data in &cell.cell_loci_data {
-            alpha_betas[locus_data.locus_index].alpha -= locus_data.alt_count as f64;
-            alpha_betas[locus_data.locus_index].beta -= locus_data.ref_count as f64;
+        for (locus_index, ref_count, alt_count) in counts.cell_entries(*excluded_cell) {
+            alpha_betas[locus_index].alpha -= alt_count as f64;
+            alpha_betas[locus_index].beta -= ref_count as f64;
         }
     }
     return alpha_betas;
 }
 
+#[derive(Default, Clone)]
 pub struct Params {
     ref_mtx: String,
     alt_mtx: String,
@@ -624,14 +1115,125 @@ pub struct Params {
     min_alleles_posterior: usize,
     expected_percent_minority: Option<f64>,
     min_loci_used: usize,
+    bam: Option<String>,
+    fasta: Option<String>,
+    threads: usize,
+    min_mapq: u8,
+    min_base_quality: u8,
+    write_matrices: bool,
+    write_obs: bool,
+    expression_barcodes: Option<String>,
+    bgzip_vcf: bool,
+    genotype_prior: String,
+    af_field: String,
+    overdispersion: f64,
+    ambient_fraction: f64,
+    genotype_threshold: f64,
+    cache: Option<String>,
+    streaming: bool,
+    channels: Option<String>,
+    model: Option<String>,
+    bootstrap_iterations: usize,
+    locus_bootstrap_iterations: usize,
+    seed: u64,
+    cell_types: Option<String>,
+    stratify_threshold: bool,
+    depth_bins: usize,
+    min_loci_for_threshold: usize,
+    fit_mode: String,
+    em_iterations: usize,
+    em_tolerance: f64,
+}
+
+fn load_params(params: &ArgMatches) -> Params{
+    let loaded = load_options(params);
+    assert!((params.is_present("alt") && params.is_present("ref")) || loaded.channels.is_some() || (loaded.bam.is_some() && loaded.fasta.is_some() && loaded.vcf.is_some()),
+        "either --alt and --ref, --channels or --bam, --fasta and --vcf are required");
+    return loaded;
 }
 
-fn load_params() -> Params{
-    let yaml = load_yaml!("params.yml");
-    let params = App::from_yaml(yaml).get_matches();
-    let ref_mtx = params.value_of("ref").unwrap().to_string();
-    let alt_mtx = params.value_of("alt").unwrap().to_string();
-    let barcodes = params.value_of("barcodes").unwrap().to_string();
+// every option of the main command with its default, without requiring the inputs, so longitudinal can load its
+// options here and set the inputs of each sample
+fn load_options(params: &ArgMatches) -> Params {
+    let output_directory = params.value_of("output_directory").unwrap().to_string();
+    let bam: Option<String> = match params.value_of("bam") {
+        None => None,
+        Some(x) => Some(x.to_string()),
+    };
+    let fasta: Option<String> = match params.value_of("fasta") {
+        None => None,
+        Some(x) => Some(x.to_string()),
+    };
+    let channels: Option<String> = match params.value_of("channels") {
+        None => None,
+        Some(x) => Some(x.to_string()),
+    };
+    // with --bam the alleles are counted in memory and alt.mtx and ref.mtx are only written to the output directory with --write_matrices
+    let (alt_mtx, ref_mtx) = match (params.value_of("alt"), params.value_of("ref")) {
+        (Some(alt_mtx), Some(ref_mtx)) => (alt_mtx.to_string(), ref_mtx.to_string()),
+        _ => (format!("{}/alt.mtx", output_directory), format!("{}/ref.mtx", output_directory)),
+    };
+    let threads = params.value_of("threads").unwrap_or("1");
+    let threads = threads.to_string().parse::<usize>().unwrap();
+    let min_mapq = params.value_of("min_mapq").unwrap_or("30");
+    let min_mapq = min_mapq.to_string().parse::<u8>().unwrap();
+    let min_base_quality = params.value_of("min_base_quality").unwrap_or("13");
+    let min_base_quality = min_base_quality.to_string().parse::<u8>().unwrap();
+    let write_matrices = params.is_present("write_matrices");
+    let expression_barcodes: Option<String> = match params.value_of("expression_barcodes") {
+        None => None,
+        Some(x) => Some(x.to_string()),
+    };
+    let write_obs = params.is_present("write_obs") || expression_barcodes.is_some();
+    let bgzip_vcf = params.is_present("bgzip_vcf");
+    let genotype_prior = params.value_of("genotype_prior").unwrap_or("flat").to_string();
+    let af_field = params.value_of("af_field").unwrap_or("AF").to_string();
+    let overdispersion = params.value_of("overdispersion").unwrap_or("0");
+    let overdispersion = overdispersion.to_string().parse::<f64>().unwrap();
+    assert!((0.0..1.0).contains(&overdispersion), "--overdispersion must be in [0, 1)");
+    let ambient_fraction = params.value_of("ambient_fraction").unwrap_or("0.03");
+    let ambient_fraction = ambient_fraction.to_string().parse::<f64>().unwrap();
+    assert!((0.0..1.0).contains(&ambient_fraction), "--ambient_fraction must be in [0, 1)");
+    let genotype_threshold = params.value_of("genotype_threshold").unwrap_or("0.99");
+    let genotype_threshold = genotype_threshold.to_string().parse::<f64>().unwrap();
+    assert!(genotype_threshold > 0.0 && genotype_threshold <= 1.0, "--genotype_threshold must be in (0, 1]");
+    let cache: Option<String> = match params.value_of("cache") {
+        None => None,
+        Some(x) => Some(x.to_string()),
+    };
+    let model: Option<String> = match params.value_of("model") {
+        None => None,
+        Some(x) => Some(x.to_string()),
+    };
+    let bootstrap_iterations = params.value_of("bootstrap_iterations").unwrap_or("1000");
+    let bootstrap_iterations = bootstrap_iterations.to_string().parse::<usize>().unwrap();
+    let locus_bootstrap_iterations = params.value_of("locus_bootstrap_iterations").unwrap_or("0");
+    let locus_bootstrap_iterations = locus_bootstrap_iterations.to_string().parse::<usize>().unwrap();
+    let seed = params.value_of("seed").unwrap_or("4");
+    let seed = seed.to_string().parse::<u64>().unwrap();
+    let cell_types: Option<String> = match params.value_of("cell_types") {
+        None => None,
+        Some(x) => Some(x.to_string()),
+    };
+    let stratify_threshold = params.is_present("stratify_threshold");
+    let depth_bins = params.value_of("depth_bins").unwrap_or("1");
+    let depth_bins = depth_bins.to_string().parse::<usize>().unwrap();
+    assert!(depth_bins >= 1, "--depth_bins must be at least 1");
+    let min_loci_for_threshold = params.value_of("min_loci_for_threshold").unwrap_or("1");
+    let min_loci_for_threshold = min_loci_for_threshold.to_string().parse::<usize>().unwrap();
+    assert!(!stratify_threshold || cell_types.is_some(), "--stratify_threshold needs --cell_types");
+    let fit_mode = params.value_of("fit_mode").unwrap_or("iqr").to_string();
+    let em_iterations = params.value_of("em_iterations").unwrap_or("100");
+    let em_iterations = em_iterations.to_string().parse::<usize>().unwrap();
+    assert!(em_iterations >= 1, "--em_iterations must be at least 1");
+    let em_tolerance = params.value_of("em_tolerance").unwrap_or("0.0001");
+    let em_tolerance = em_tolerance.to_string().parse::<f64>().unwrap();
+    let streaming = params.is_present("streaming");
+    assert!(!streaming || (bam.is_none() && cache.is_none()), "--streaming reads alt.mtx and ref.mtx on every pass, it cannot be used with --bam (count with --write_matrices first) or --cache");
+    assert!(!streaming || fit_mode == "iqr", "--fit_mode em needs the allele counts in memory, it cannot be used with --streaming");
+    assert!(!streaming || locus_bootstrap_iterations == 0, "--locus_bootstrap_iterations needs the allele counts in memory, it cannot be used with --streaming");
+    assert!(channels.is_none() || (bam.is_none() && cache.is_none() && !streaming), "--channels cannot be used with --bam, --cache or --streaming");
+    let barcodes = params.value_of("barcodes").unwrap_or("").to_string();
     let min_alt = params.value_of("min_alt").unwrap_or("4");
     let min_alt = min_alt.to_string().parse::<usize>().unwrap();
     let min_ref = params.value_of("min_ref").unwrap_or("4");
@@ -640,6 +1242,9 @@ fn load_params() -> Params{
         None => None,
         Some(x) => Some(x.to_string()),
     };
+    // the same barcode can be in several channels so a barcode keyed file cannot be matched to the {name}_ prefixed cells
+    assert!(channels.is_none() || (ground_truth.is_none() && cell_types.is_none() && expression_barcodes.is_none()),
+        "--channels cannot be used with --ground_truth, --cell_types or --expression_barcodes, run each channel on its own for those");
     let vcf: Option<String> = match params.value_of("vcf") {
         None => None,
         Some(x) => Some(x.to_string()),
@@ -648,7 +1253,6 @@ fn load_params() -> Params{
     let posterior_threshold = posterior_threshold.to_string().parse::<f64>().unwrap();
     let interquartile_range_multiple = params.value_of("interquartile_range_multiple").unwrap_or("5");
     let interquartile_range_multiple = interquartile_range_multiple.to_string().parse::<f64>().unwrap();
-    let output_directory = params.value_of("output_directory").unwrap().to_string();
     let min_alleles_posterior = params.value_of("min_alleles_posterior").unwrap_or("5");
     let min_alleles_posterior = min_alleles_posterior.to_string().parse::<usize>().unwrap();
     let expected_percent_minority = match params.value_of("expected_percent_minority") {
@@ -672,8 +1276,177 @@ fn load_params() -> Params{
         min_alleles_posterior: min_alleles_posterior,
         expected_percent_minority: expected_percent_minority,
         min_loci_used: min_loci_used,
+        bam: bam,
+        fasta: fasta,
+        threads: threads,
+        min_mapq: min_mapq,
+        min_base_quality: min_base_quality,
+        write_matrices: write_matrices,
+        write_obs: write_obs,
+        expression_barcodes: expression_barcodes,
+        bgzip_vcf: bgzip_vcf,
+        genotype_prior: genotype_prior,
+        af_field: af_field,
+        overdispersion: overdispersion,
+        ambient_fraction: ambient_fraction,
+        genotype_threshold: genotype_threshold,
+        cache: cache,
+        streaming: streaming,
+        channels: channels,
+        model: model,
+        bootstrap_iterations: bootstrap_iterations,
+        locus_bootstrap_iterations: locus_bootstrap_iterations,
+        seed: seed,
+        cell_types: cell_types,
+        stratify_threshold: stratify_threshold,
+        depth_bins: depth_bins,
+        min_loci_for_threshold: min_loci_for_threshold,
+        fit_mode: fit_mode,
+        em_iterations: em_iterations,
+        em_tolerance: em_tolerance,
     };
     return params;
 }
 
 
+
+// an empty scratch directory for tests that write files
+#[cfg(test)]
+fn test_directory(name: &str) -> String {
+    let directory = format!("{}/cellector_test_{}_{}", std::env::temp_dir().display(), std::process::id(), name);
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).expect("could not create test directory");
+    return directory;
+}
+
+// runs cellector on tests/data/combiner with the extra arguments into a fresh test directory and returns it
+#[cfg(test)]
+fn run_test_fixture(name: &str, extra_args: &[&str]) -> String {
+    let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/combiner");
+    let directory = test_directory(name);
+    let mut args: Vec<String> = vec!["cellector".to_string(), "--alt".to_string(), format!("{}/alt.mtx.gz", data), "--ref".to_string(), format!("{}/ref.mtx.gz", data),
+        "--barcodes".to_string(), format!("{}/barcodes.tsv", data), "--output_directory".to_string(), directory.clone()];
+    args.extend(extra_args.iter().map(|arg| arg.to_string()));
+    run(&params_from_args(&args));
+    return directory;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the same cells as two channels, each channel's file has every cell once with the original barcodes
+    #[test]
+    fn channel_assignments_are_split_without_the_prefix() {
+        let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/combiner");
+        let directory = test_directory("channels");
+        let channels = format!("{}/channels.tsv", directory);
+        let channel_line = format!("{}/alt.mtx.gz\t{}/ref.mtx.gz\t{}/barcodes.tsv\n", data, data, data);
+        File::create(&channels).unwrap().write_all(format!("a\t{}b\t{}", channel_line, channel_line).as_bytes()).unwrap();
+        let args: Vec<String> = ["cellector", "--channels", &channels, "--output_directory", &directory].iter().map(|arg| arg.to_string()).collect();
+        run(&params_from_args(&args));
+        let barcodes: Vec<String> = load_data::reader(&format!("{}/barcodes.tsv", data)).lines().map(|line| line.unwrap()).collect();
+        let joint: Vec<String> = load_data::reader(&format!("{}/cellector_assignments.tsv", directory)).lines().map(|line| line.unwrap()).collect();
+        assert_eq!(joint.len(), 2 * barcodes.len() + 1);
+        for (channel_index, channel) in ["a", "b"].iter().enumerate() {
+            let lines: Vec<String> = load_data::reader(&format!("{}/{}_cellector_assignments.tsv", directory, channel)).lines().map(|line| line.unwrap()).collect();
+            assert_eq!(lines[0], joint[0]);
+            assert_eq!(lines.len(), barcodes.len() + 1);
+            for (cell, line) in lines[1..].iter().enumerate() {
+                assert_eq!(line.split('\t').next().unwrap(), barcodes[cell]);
+                assert_eq!(format!("{}_{}", channel, line), joint[1 + channel_index * barcodes.len() + cell]);
+            }
+        }
+    }
+
+    #[test]
+    fn minority_fraction_file_has_the_fraction_inside_its_intervals() {
+        assert_eq!(stats::percentile_interval((0..200).rev().map(|value| value as f64).collect()), Some((5.0, 195.0)));
+        assert_eq!(stats::percentile_interval(Vec::new()), None);
+        for (name, extra_args) in [("minority_fraction", vec![]), ("minority_fraction_loci", vec!["--locus_bootstrap_iterations", "20"])].iter() {
+            let directory = run_test_fixture(name, extra_args);
+            let lines: Vec<String> = load_data::reader(&format!("{}/cellector_minority_fraction.tsv", directory)).lines().map(|line| line.unwrap()).collect();
+            assert_eq!(lines.len(), 2);
+            assert_eq!(lines[0].split('\t').count(), 9);
+            let toks: Vec<&str> = lines[1].split('\t').collect();
+            assert_eq!(toks[0], "12");
+            assert!(toks[1].parse::<usize>().unwrap() <= 12);
+            let values: Vec<f64> = toks[2..7].iter().map(|tok| tok.parse::<f64>().unwrap()).collect();
+            let fraction = values[0];
+            assert!((0.0..=1.0).contains(&fraction));
+            assert!(values[1] <= fraction && fraction <= values[2], "posterior interval {:?}", values);
+            assert!(values[3] <= fraction && fraction <= values[4], "bootstrap interval {:?}", values);
+            if extra_args.is_empty() {
+                assert_eq!((toks[7], toks[8]), ("na", "na"));
+            } else {
+                assert!(toks[7].parse::<f64>().unwrap() <= toks[8].parse::<f64>().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn tied_depth_bin_edges_are_merged() {
+        assert_eq!(depth_bin_edges(vec![9.0, 5.0, 5.0, 5.0, 5.0, 5.0, 5.0, 9.0], 4), vec![9.0]);
+        assert_eq!(depth_bin_edges(vec![1.0, 2.0, 3.0, 4.0], 2), vec![3.0]);
+        assert!(depth_bin_edges(Vec::new(), 3).is_empty());
+    }
+
+    // a deep cell that only stands out against the other deep cells, the shallow cells widen the global quartiles
+    #[test]
+    fn depth_binned_thresholds() {
+        let mut normalized: Vec<f64> = (0..60).map(|cell| -2.0 + (cell as f64) / 59.0).collect();
+        let mut loci_used: Vec<f64> = vec![5.0; 60];
+        normalized.extend((0..59).map(|cell| -1.05 + 0.1 * (cell as f64) / 58.0));
+        normalized.push(-1.5);
+        loci_used.extend(vec![100.0; 60]);
+        let num_cells = normalized.len();
+        let cell_data: Vec<CellData> = (0..num_cells).map(|cell_id| CellData { cell_id: cell_id, barcode: format!("cell{}", cell_id), assignment: "na".to_string(), cell_type: "unlabeled".to_string() }).collect();
+        let likelihoods = CellLogLikelihoodData {
+            log_likelihoods: (0..num_cells).map(|cell_id| normalized[cell_id] * loci_used[cell_id]).collect(),
+            loci_used_per_cell: loci_used,
+            expected_log_likelihoods: vec![0.0; num_cells],
+            expected_log_variances: vec![0.0; num_cells],
+            all_pmfs: Vec::new(),
+        };
+        let mut params = Params { interquartile_range_multiple: 1.5, min_loci_for_threshold: 1, depth_bins: 1, ..Default::default() };
+        let (excluded, _normalized, _cell_thresholds, summary) = find_outlier_cells(&params, &cell_data, &likelihoods, &HashSet::new(), 0);
+        assert!(excluded.is_empty());
+        assert_eq!(summary.group_thresholds.len(), 1);
+
+        params.depth_bins = 2;
+        let (excluded, _normalized, cell_thresholds, summary) = find_outlier_cells(&params, &cell_data, &likelihoods, &HashSet::new(), 0);
+        assert_eq!(excluded.into_iter().collect::<Vec<usize>>(), vec![num_cells - 1]);
+        assert_eq!(summary.group_thresholds.len(), 2);
+        assert!(summary.group_thresholds.iter().all(|group| group.num_cells == 60));
+        assert!(cell_thresholds[0].group != cell_thresholds[num_cells - 1].group);
+        assert!(cell_thresholds[num_cells - 1].threshold.unwrap() > summary.threshold);
+    }
+
+    // a third of the b cells are minority, their own quartiles would put the threshold below all of them
+    #[test]
+    fn minority_enriched_cell_type_uses_the_overall_threshold() {
+        let mut normalized: Vec<f64> = (0..100).map(|cell| -1.05 + 0.1 * (cell as f64) / 99.0).collect();
+        normalized.extend((0..20).map(|cell| -2.0 + 0.1 * (cell as f64) / 19.0));
+        let num_cells = normalized.len();
+        let cell_data: Vec<CellData> = (0..num_cells).map(|cell_id| {
+            let cell_type = if cell_id % 5 < 2 || cell_id >= 100 { "b_cell" } else { "t_cell" };
+            CellData { cell_id: cell_id, barcode: format!("cell{}", cell_id), assignment: "na".to_string(), cell_type: cell_type.to_string() }
+        }).collect();
+        let likelihoods = CellLogLikelihoodData {
+            log_likelihoods: normalized.iter().map(|normalized| normalized * 50.0).collect(),
+            loci_used_per_cell: vec![50.0; num_cells],
+            expected_log_likelihoods: vec![0.0; num_cells],
+            expected_log_variances: vec![0.0; num_cells],
+            all_pmfs: Vec::new(),
+        };
+        let params = Params { interquartile_range_multiple: 1.5, min_loci_for_threshold: 1, depth_bins: 1, stratify_threshold: true, ..Default::default() };
+        let (excluded, _normalized, cell_thresholds, summary) = find_outlier_cells(&params, &cell_data, &likelihoods, &HashSet::new(), 0);
+        let mut excluded: Vec<usize> = excluded.into_iter().collect();
+        excluded.sort();
+        assert_eq!(excluded, (100..num_cells).collect::<Vec<usize>>());
+        assert_eq!(summary.group_thresholds.len(), 2);
+        assert_eq!(cell_thresholds[num_cells - 1].threshold, Some(summary.threshold));
+        // the t cells keep their own quartiles
+        assert!(cell_thresholds[2].threshold.unwrap() != summary.threshold);
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::{BufWriter, Write};
+use hashbrown::{HashMap, HashSet};
+use File;
+use Params;
+use variants;
+use load_data::CellData;
+
+// per cell results as {output_directory}/cellector_obs.csv, one row per cell keyed by barcode so it can be joined
+// onto an AnnData obs (pd.read_csv(index_col=0)) or a Seurat object's meta.data (read.csv(row.names=1))
+pub struct CellResults<'a> {
+    pub assignments: &'a Vec<String>,
+    pub posteriors: &'a Vec<f64>, // posterior of assignment 0 (minority)
+    pub doublet_posteriors: &'a Vec<f64>,
+    pub anomalous: &'a Vec<bool>,
+    pub normalized_log_likelihoods: &'a Vec<f64>,
+    pub loci_used: &'a Vec<f64>,
+    pub quals: &'a Vec<usize>,
+}
+
+// the barcode without a trailing -N gem group or dataset suffix
+pub fn barcode_core(barcode: &str) -> &str {
+    if let Some(dash) = barcode.rfind('-') {
+        let suffix = &barcode[dash + 1..];
+        if !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit()) { return &barcode[..dash]; }
+    }
+    return barcode;
+}
+
+// maps each barcode to the expression matrix barcode with the same core if there is exactly one and no other cell
+// already has it (eg AAAC-1 and AAAC-2 from two datasets against an expression matrix with only AAAC-1), otherwise
+// leaves it as is
+pub fn normalize_barcodes(barcodes: &Vec<String>, expression_barcodes: &HashSet<String>) -> Vec<String> {
+    let mut by_core: HashMap<&str, Vec<&String>> = HashMap::new();
+    for barcode in expression_barcodes {
+        by_core.entry(barcode_core(barcode)).or_default().push(barcode);
+    }
+    let mut taken: HashSet<&String> = barcodes.iter().filter(|barcode| expression_barcodes.contains(*barcode)).collect();
+    let (mut num_exact, mut num_renamed, mut num_ambiguous, mut num_missing) = (0, 0, 0, 0);
+    let mut normalized: Vec<String> = Vec::new();
+    for barcode in barcodes {
+        if expression_barcodes.contains(barcode) {
+            num_exact += 1;
+            normalized.push(barcode.clone());
+            continue;
+        }
+        match by_core.get(barcode_core(barcode)) {
+            Some(matches) if matches.len() == 1 && taken.insert(matches[0]) => {
+                num_renamed += 1;
+                normalized.push(matches[0].clone());
+            },
+            Some(_matches) => {
+                num_ambiguous += 1;
+                normalized.push(barcode.clone());
+            },
+            None => {
+                num_missing += 1;
+                normalized.push(barcode.clone());
+            },
+        }
+    }
+    println!("matched cell barcodes to the expression barcodes: {} exact, {} after changing the suffix, {} ambiguous (more than one suffix in the expression barcodes or already another cell's) and {} not found, the last two are written unchanged",
+        num_exact, num_renamed, num_ambiguous, num_missing);
+    return normalized;
+}
+
+// barcodes that are still shared by two cells after normalization (eg the same barcode in two datasets without
+// --channels prefixes) cannot key obs, they are returned as an error and no csv is written
+pub fn write_obs(params: &Params, cell_data: &Vec<CellData>, results: &CellResults) -> Result<(), String> {
+    let mut barcodes: Vec<String> = cell_data.iter().map(|cell| cell.barcode.clone()).collect();
+    if let Some(expression_barcodes) = &params.expression_barcodes {
+        let expression_barcodes = variants::load_valid_barcodes(&Some(expression_barcodes.clone())).unwrap();
+        barcodes = normalize_barcodes(&barcodes, &expression_barcodes);
+    }
+    let mut seen: HashSet<&String> = HashSet::new();
+    let mut duplicates: Vec<&String> = Vec::new();
+    for barcode in &barcodes {
+        if !seen.insert(barcode) { duplicates.push(barcode); }
+    }
+    if !duplicates.is_empty() {
+        return Err(format!("{} barcodes appear more than once (eg {}), not writing cellector_obs.csv. give each dataset a distinct suffix or prefix, or check --expression_barcodes",
+            duplicates.len(), duplicates.iter().take(3).map(|barcode| barcode.as_str()).collect::<Vec<&str>>().join(", ")));
+    }
+
+    let filename = format!("{}/cellector_obs.csv", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    writer.write_all(b"barcode,cellector_assignment,cellector_posterior_0,cellector_posterior_1,cellector_doublet_posterior,cellector_anomalous,cellector_log_likelihood_loci_normalized,cellector_loci_used,cellector_assign_qual\n")
+        .expect("could not write obs csv");
+    for (cell_id, barcode) in barcodes.iter().enumerate() {
+        // posteriors of 0, 1 and doublet sum to 1
+        let majority_posterior = (1.0 - results.posteriors[cell_id] - results.doublet_posteriors[cell_id]).max(0.0);
+        let line = format!("{},{},{},{},{},{},{},{},{}\n", barcode, results.assignments[cell_id], results.posteriors[cell_id], majority_posterior,
+            results.doublet_posteriors[cell_id], results.anomalous[cell_id], results.normalized_log_likelihoods[cell_id], results.loci_used[cell_id] as usize, results.quals[cell_id]);
+        writer.write_all(line.as_bytes()).expect("could not write obs csv");
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_barcode_is_not_renamed_onto_another_cell() {
+        let barcodes: Vec<String> = vec!["AAAC-1".to_string(), "AAAC-2".to_string(), "GGGT".to_string()];
+        let expression_barcodes: HashSet<String> = ["AAAC-1", "GGGT-1"].iter().map(|barcode| barcode.to_string()).collect();
+        assert_eq!(normalize_barcodes(&barcodes, &expression_barcodes), vec!["AAAC-1", "AAAC-2", "GGGT-1"]);
+    }
+}
@@ -0,0 +1,188 @@
+use clap::ArgMatches;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use File;
+use count;
+use variants;
+use check;
+use compare;
+use plot;
+use load_data;
+
+// end to end replacement for cellector_pipeline.py. each stage writes {output_directory}/{stage}.done when it finishes
+// and is skipped on later runs in the same directory unless it is at or after --rerun_from
+pub struct PipelineParams {
+    pub bam: String,
+    pub barcodes: String,
+    pub fasta: String,
+    pub threads: usize,
+    pub output_directory: String,
+    pub common_variants: String,
+    pub min_alt: usize,
+    pub min_ref: usize,
+    pub program_preference: String,
+    pub rerun_from: Option<String>,
+    pub souporcell_binary: String,
+    pub troublet_binary: String,
+    pub ignore: bool,
+}
+
+pub const STAGES: [&str; 8] = ["check", "variants", "counts", "cellector", "souporcell", "troublet", "final_output", "graphs"];
+
+pub fn load_pipeline_params(params: &ArgMatches) -> PipelineParams {
+    let bam = params.value_of("bam").unwrap().to_string();
+    let barcodes = params.value_of("barcodes").unwrap().to_string();
+    let fasta = params.value_of("fasta").unwrap().to_string();
+    let threads = params.value_of("threads").unwrap().to_string().parse::<usize>().unwrap();
+    let output_directory = params.value_of("output_directory").unwrap().to_string();
+    let common_variants = params.value_of("common_variants").unwrap().to_string();
+    let min_alt = params.value_of("min_alt").unwrap_or("4");
+    let min_alt = min_alt.to_string().parse::<usize>().unwrap();
+    let min_ref = params.value_of("min_ref").unwrap_or("4");
+    let min_ref = min_ref.to_string().parse::<usize>().unwrap();
+    let program_preference = params.value_of("program_preference").unwrap_or("auto").to_string();
+    assert!(program_preference == "auto" || program_preference == "cellector" || program_preference == "souporcell",
+        "--program_preference must be auto, cellector or souporcell");
+    let rerun_from = match params.value_of("rerun_from") {
+        Some(x) => Some(x.to_string()),
+        None => None,
+    };
+    if let Some(stage) = &rerun_from {
+        assert!(STAGES.contains(&stage.as_str()), "--rerun_from must be one of {}", STAGES.join(", "));
+    }
+    let souporcell_binary = params.value_of("souporcell_binary").unwrap_or("souporcell").to_string();
+    let troublet_binary = params.value_of("troublet_binary").unwrap_or("troublet").to_string();
+    return PipelineParams {
+        bam: bam,
+        barcodes: barcodes,
+        fasta: fasta,
+        threads: threads,
+        output_directory: output_directory,
+        common_variants: common_variants,
+        min_alt: min_alt,
+        min_ref: min_ref,
+        program_preference: program_preference,
+        rerun_from: rerun_from,
+        souporcell_binary: souporcell_binary,
+        troublet_binary: troublet_binary,
+        ignore: params.is_present("ignore"),
+    };
+}
+
+pub fn run_pipeline(params: &PipelineParams) {
+    if Path::new(&params.output_directory).is_dir() {
+        println!("restarting pipeline in existing directory {}", params.output_directory);
+    }
+    load_data::create_output_dir(&params.output_directory);
+    if let Some(stage) = &params.rerun_from {
+        let first = STAGES.iter().position(|s| s == stage).unwrap();
+        for stage in &STAGES[first..] {
+            let _ = fs::remove_file(done_file(params, stage));
+        }
+    }
+    run_stage(params, "check", || {
+        check::check_inputs(&check::CheckParams {
+            bam: params.bam.clone(),
+            barcodes: params.barcodes.clone(),
+            fasta: Some(params.fasta.clone()),
+            common_variants: Some(params.common_variants.clone()),
+            ignore: params.ignore,
+        });
+    });
+    run_stage(params, "variants", || {
+        variants::select_covered_variants(&variants::VariantsParams {
+            bam: params.bam.clone(),
+            common_variants: params.common_variants.clone(),
+            barcodes: Some(params.barcodes.clone()),
+            output_directory: params.output_directory.clone(),
+            threads: params.threads,
+            min_coverage: params.min_ref + params.min_alt,
+            max_coverage: 100000,
+        });
+    });
+    run_stage(params, "counts", || {
+        let cellector_params = ::params_from_args(&cellector_args(params, true));
+        let (_cell_id_to_barcode, barcode_to_cell_id) = load_data::load_barcodes(&cellector_params);
+        let vcf_data = load_data::load_vcf_data(&cellector_params).unwrap();
+        let allele_matrix = count::count_alleles(&cellector_params, &vcf_data, &barcode_to_cell_id);
+        count::write_matrices(&cellector_params, &allele_matrix);
+    });
+    run_stage(params, "cellector", || {
+        ::run(&::params_from_args(&cellector_args(params, false)));
+    });
+    run_stage(params, "souporcell", || {
+        let mut command = Command::new(&params.souporcell_binary);
+        command.args(["-a", &alt_mtx(params), "-r", &ref_mtx(params), "--barcodes", &params.barcodes,
+            "-t", &params.threads.to_string(), "-k", "2", "--min_ref", &params.min_ref.to_string(), "--min_alt", &params.min_alt.to_string()]);
+        run_tool(params, "souporcell", command);
+    });
+    run_stage(params, "troublet", || {
+        let mut command = Command::new(&params.troublet_binary);
+        command.args(["--alts", &alt_mtx(params), "--refs", &ref_mtx(params), "--clusters", &format!("{}/souporcell.out", params.output_directory)]);
+        run_tool(params, "troublet", command);
+    });
+    run_stage(params, "final_output", || {
+        compare::compare_methods(&compare::CompareParams {
+            cellector_assignments: format!("{}/cellector_assignments.tsv", params.output_directory),
+            troublet: format!("{}/troublet.out", params.output_directory),
+            output_directory: params.output_directory.clone(),
+            program_preference: params.program_preference.clone(),
+        });
+    });
+    run_stage(params, "graphs", || {
+        plot::plot_iterations(&plot::PlotParams { output_directory: params.output_directory.clone() });
+    });
+    println!("[pipeline] finished, results in {}/final_output.tsv", params.output_directory);
+}
+
+fn done_file(params: &PipelineParams, stage: &str) -> String {
+    return format!("{}/{}.done", params.output_directory, stage);
+}
+
+fn alt_mtx(params: &PipelineParams) -> String { return format!("{}/alt.mtx", params.output_directory); }
+
+fn ref_mtx(params: &PipelineParams) -> String { return format!("{}/ref.mtx", params.output_directory); }
+
+fn covered_variants(params: &PipelineParams) -> String { return format!("{}/common_variants_covered.vcf", params.output_directory); }
+
+fn run_stage<F: FnOnce()>(params: &PipelineParams, stage: &str, stage_fn: F) {
+    let done = done_file(params, stage);
+    if Path::new(&done).exists() {
+        println!("[pipeline] {} already done ({} exists), skipping", stage, done);
+        return;
+    }
+    println!("[pipeline] {} started", stage);
+    let start = Instant::now();
+    stage_fn();
+    File::create(&done).expect(&format!("Unable to create file {}", &done));
+    println!("[pipeline] {} done in {:.1}s", stage, start.elapsed().as_secs_f64());
+}
+
+// runs an external tool with stdout and stderr going to {output_directory}/{name}.out and .err
+fn run_tool(params: &PipelineParams, name: &str, mut command: Command) {
+    let out = format!("{}/{}.out", params.output_directory, name);
+    let err = format!("{}/{}.err", params.output_directory, name);
+    println!("[pipeline] running {:?}", command);
+    let status = command
+        .stdout(Stdio::from(File::create(&out).expect(&format!("Unable to create file {}", &out))))
+        .stderr(Stdio::from(File::create(&err).expect(&format!("Unable to create file {}", &err))))
+        .status()
+        .expect(&format!("could not run {:?}, set the path to {} with its --{}_binary option", command, name, name));
+    assert!(status.success(), "{} failed with {}, see {}", name, status, err);
+}
+
+fn cellector_args(params: &PipelineParams, count_only: bool) -> Vec<String> {
+    let mut args: Vec<String> = vec!["cellector".to_string()];
+    if count_only {
+        args.extend(vec!["--bam".to_string(), params.bam.clone(), "--fasta".to_string(), params.fasta.clone(),
+            "--threads".to_string(), params.threads.to_string()]);
+    } else {
+        args.extend(vec!["--alt".to_string(), alt_mtx(params), "--ref".to_string(), ref_mtx(params)]);
+    }
+    args.extend(vec!["--vcf".to_string(), covered_variants(params), "--barcodes".to_string(), params.barcodes.clone(),
+        "--output_directory".to_string(), params.output_directory.clone(),
+        "--min_alt".to_string(), params.min_alt.to_string(), "--min_ref".to_string(), params.min_ref.to_string()]);
+    return args;
+}
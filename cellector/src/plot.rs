@@ -0,0 +1,255 @@
+use clap::ArgMatches;
+use plotters::prelude::*;
+use std::fs;
+use std::io::{BufWriter, Write, BufRead};
+use File;
+use load_data::reader;
+
+// diagnostic plots of the per iteration outputs, replaces grapher.py. each plot is an svg string so the html report can inline them
+pub struct PlotParams {
+    pub output_directory: String,
+}
+
+pub fn load_plot_params(params: &ArgMatches) -> PlotParams {
+    let output_directory = params.value_of("output_directory").unwrap().to_string();
+    return PlotParams {
+        output_directory: output_directory,
+    };
+}
+
+pub struct IterationData {
+    pub assignments: Vec<String>, // ground truth, na if not given
+    pub normalized_log_likelihoods: Vec<f64>, // log likelihood / loci used, 0 for cells with no loci (same as the threshold calculation)
+    pub loci_used: Vec<f64>,
+    pub threshold: f64, // global
+    pub group_thresholds: Vec<(String, f64)>, // with --depth_bins or --stratify_threshold, empty otherwise
+    pub cell_thresholds: Vec<Option<f64>>, // the threshold each cell was held to, empty if not known
+}
+
+pub struct LocusContributions {
+    pub minority_per_cell: Vec<f64>,
+    pub majority_per_cell: Vec<f64>,
+}
+
+const WIDTH: u32 = 900;
+const HEIGHT: u32 = 500;
+
+pub fn plot_iterations(params: &PlotParams) {
+    let iterations = find_iterations(&params.output_directory);
+    assert!(!iterations.is_empty(), "no iteration_N.tsv files in {}, run cellector first", params.output_directory);
+    for iteration in iterations {
+        let data = load_iteration(&params.output_directory, iteration);
+        write_svg(&format!("{}/iteration_{}_log_likelihood.svg", params.output_directory, iteration),
+            &log_likelihood_svg(&data, &format!("iteration {}", iteration)));
+        write_svg(&format!("{}/iteration_{}_histogram.svg", params.output_directory, iteration),
+            &histogram_svg(&data.normalized_log_likelihoods, Some(data.threshold), &data.group_thresholds, &format!("iteration {}", iteration), "log likelihood / loci used"));
+        let locus_file = format!("{}/iteration_{}_locus_contribution.tsv", params.output_directory, iteration);
+        if fs::metadata(&locus_file).is_ok() {
+            write_svg(&format!("{}/iteration_{}_locus_contribution.svg", params.output_directory, iteration),
+                &locus_contribution_svg(&load_locus_contributions(&locus_file), &format!("iteration {}", iteration)));
+        }
+    }
+    println!("wrote plots to {}", params.output_directory);
+}
+
+// iteration numbers with an iteration_N.tsv in the output directory, sorted
+pub fn find_iterations(output_directory: &str) -> Vec<usize> {
+    let mut iterations: Vec<usize> = Vec::new();
+    let entries = fs::read_dir(output_directory).expect(&format!("could not read directory {}", output_directory));
+    for entry in entries {
+        let name = entry.expect("could not read directory entry").file_name().to_string_lossy().to_string();
+        if name.starts_with("iteration_") && name.ends_with(".tsv") {
+            if let Ok(iteration) = name["iteration_".len()..name.len() - ".tsv".len()].parse::<usize>() {
+                iterations.push(iteration);
+            }
+        }
+    }
+    iterations.sort();
+    return iterations;
+}
+
+pub fn load_iteration(output_directory: &str, iteration: usize) -> IterationData {
+    let filename = format!("{}/iteration_{}.tsv", output_directory, iteration);
+    let mut assignments: Vec<String> = Vec::new();
+    let mut normalized_log_likelihoods: Vec<f64> = Vec::new();
+    let mut loci_used: Vec<f64> = Vec::new();
+    let mut cell_thresholds: Vec<Option<f64>> = Vec::new();
+    for line in reader(&filename).lines().skip(1) {
+        let line = line.expect(&format!("Unable to read a line in {}", filename));
+        let toks: Vec<&str> = line.split('\t').collect();
+        let log_likelihood = toks[3].parse::<f64>().unwrap();
+        let cell_loci_used = toks[5].parse::<f64>().unwrap();
+        assignments.push(toks[2].to_string());
+        normalized_log_likelihoods.push(if cell_loci_used > 0.0 { log_likelihood / cell_loci_used } else { 0.0 });
+        loci_used.push(cell_loci_used);
+        // na for cells left out of the threshold
+        if let Some(threshold) = toks.get(7) { cell_thresholds.push(threshold.parse::<f64>().ok()); }
+    }
+    let (threshold, group_thresholds) = load_thresholds(&format!("{}/iteration_{}_threshold.tsv", output_directory, iteration));
+    return IterationData {
+        assignments: assignments,
+        normalized_log_likelihoods: normalized_log_likelihoods,
+        loci_used: loci_used,
+        threshold: threshold,
+        group_thresholds: group_thresholds,
+        cell_thresholds: cell_thresholds,
+    };
+}
+
+// the global threshold and the group thresholds from iteration_N_threshold.tsv (group, cells, threshold rows, the
+// global one first), or from the single number older versions wrote
+fn load_thresholds(filename: &str) -> (f64, Vec<(String, f64)>) {
+    let contents = fs::read_to_string(filename).expect(&format!("could not read {}", filename));
+    if let Ok(threshold) = contents.trim().parse::<f64>() { return (threshold, Vec::new()); }
+    let mut threshold: Option<f64> = None;
+    let mut group_thresholds: Vec<(String, f64)> = Vec::new();
+    for line in contents.lines().skip(1) {
+        let toks: Vec<&str> = line.split('\t').collect();
+        let value = toks[2].parse::<f64>().expect(&format!("could not parse threshold in {}", filename));
+        if toks[0] == "global" { threshold = Some(value); } else { group_thresholds.push((toks[0].to_string(), value)); }
+    }
+    return (threshold.expect(&format!("no global threshold in {}", filename)), group_thresholds);
+}
+
+pub fn load_locus_contributions(filename: &str) -> LocusContributions {
+    let mut minority_per_cell: Vec<f64> = Vec::new();
+    let mut majority_per_cell: Vec<f64> = Vec::new();
+    for line in reader(filename).lines().skip(1) {
+        let line = line.expect(&format!("Unable to read a line in {}", filename));
+        let toks: Vec<&str> = line.split('\t').collect();
+        let minority = toks[9].parse::<f64>().unwrap_or(f64::NAN);
+        let majority = toks[10].parse::<f64>().unwrap_or(f64::NAN);
+        if minority.is_finite() && majority.is_finite() {
+            minority_per_cell.push(minority);
+            majority_per_cell.push(majority);
+        }
+    }
+    return LocusContributions { minority_per_cell: minority_per_cell, majority_per_cell: majority_per_cell };
+}
+
+pub fn write_svg(filename: &str, svg: &str) {
+    let filehandle = File::create(filename).expect(&format!("Unable to create file {}", filename));
+    let mut writer = BufWriter::new(filehandle);
+    writer.write_all(svg.as_bytes()).expect("could not write svg");
+}
+
+// min and max of the values padded by 5% so points are not drawn on the axes
+fn padded_range(values: &Vec<f64>) -> (f64, f64) {
+    let min = values.iter().cloned().filter(|value| value.is_finite()).fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().filter(|value| value.is_finite()).fold(f64::NEG_INFINITY, f64::max);
+    if !min.is_finite() { return (0.0, 1.0); }
+    let pad = ((max - min) * 0.05).max(1e-3);
+    return (min - pad, max + pad);
+}
+
+// normalized log likelihood against cell index, colored by ground truth and sized by loci used, with the iteration's
+// global outlier threshold and, when cells were held to group thresholds, each cell's own threshold as a short dash
+pub fn log_likelihood_svg(data: &IterationData, title: &str) -> String {
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, (WIDTH, HEIGHT)).into_drawing_area();
+        root.fill(&WHITE).expect("could not draw plot");
+        let mut values = data.normalized_log_likelihoods.clone();
+        values.push(data.threshold);
+        values.extend(data.cell_thresholds.iter().filter_map(|threshold| *threshold));
+        let (y_min, y_max) = padded_range(&values);
+        let num_cells = data.normalized_log_likelihoods.len();
+        let max_loci_used = data.loci_used.iter().cloned().fold(1.0, f64::max); // point size scales with loci used
+        let mut chart = ChartBuilder::on(&root)
+            .caption(format!("{} normalized log likelihood", title), ("sans-serif", 20))
+            .margin(10).x_label_area_size(40).y_label_area_size(70)
+            .build_cartesian_2d(0f64..(num_cells.max(1) as f64), y_min..y_max).expect("could not draw plot");
+        chart.configure_mesh().disable_mesh().x_desc("cell index").y_desc("log likelihood / loci used").draw().expect("could not draw plot");
+        let mut groups: Vec<String> = data.assignments.clone();
+        groups.sort();
+        groups.dedup();
+        for (index, group) in groups.iter().enumerate() {
+            let color = Palette99::pick(index).to_rgba();
+            let points: Vec<(f64, f64, u32)> = (0..num_cells).filter(|&cell_id| &data.assignments[cell_id] == group)
+                .map(|cell_id| (cell_id as f64, data.normalized_log_likelihoods[cell_id], 1 + (3.0 * data.loci_used[cell_id] / max_loci_used).round() as u32)).collect();
+            chart.draw_series(points.iter().map(|&(x, y, size)| Circle::new((x, y), size, color.filled()))).expect("could not draw plot")
+                .label(format!("{} ({})", group, points.len()))
+                .legend(move |(x, y)| Circle::new((x, y), 3, color.filled()));
+        }
+        chart.draw_series(LineSeries::new(vec![(0.0, data.threshold), (num_cells as f64, data.threshold)], RED.stroke_width(2))).expect("could not draw plot")
+            .label(format!("global threshold {:.3}", data.threshold))
+            .legend(|(x, y)| PathElement::new(vec![(x - 5, y), (x + 5, y)], RED.stroke_width(2)));
+        if data.cell_thresholds.iter().any(|threshold| threshold.is_some_and(|threshold| threshold != data.threshold)) {
+            let dashes: Vec<(f64, f64)> = data.cell_thresholds.iter().enumerate()
+                .filter_map(|(cell_id, threshold)| threshold.map(|threshold| (cell_id as f64, threshold))).collect();
+            chart.draw_series(dashes.iter().map(|&(x, y)| PathElement::new(vec![(x - 0.4, y), (x + 0.4, y)], MAGENTA.stroke_width(2)))).expect("could not draw plot")
+                .label("the cell's group threshold")
+                .legend(|(x, y)| PathElement::new(vec![(x - 5, y), (x + 5, y)], MAGENTA.stroke_width(2)));
+        }
+        chart.configure_series_labels().background_style(WHITE.mix(0.8)).border_style(BLACK).draw().expect("could not draw plot");
+        root.present().expect("could not draw plot");
+    }
+    return svg;
+}
+
+// threshold is drawn as the global threshold, group_thresholds as thinner lines
+pub fn histogram_svg(values: &Vec<f64>, threshold: Option<f64>, group_thresholds: &Vec<(String, f64)>, title: &str, x_desc: &str) -> String {
+    let num_bins = 50;
+    let mut with_threshold = values.clone();
+    if let Some(threshold) = threshold { with_threshold.push(threshold); }
+    with_threshold.extend(group_thresholds.iter().map(|(_group, threshold)| *threshold));
+    let (x_min, x_max) = padded_range(&with_threshold);
+    let bin_width = (x_max - x_min) / (num_bins as f64);
+    let mut counts: Vec<usize> = vec![0; num_bins];
+    for value in values {
+        if !value.is_finite() { continue; }
+        let bin = (((value - x_min) / bin_width) as usize).min(num_bins - 1);
+        counts[bin] += 1;
+    }
+    let max_count = *counts.iter().max().unwrap_or(&1) as f64;
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, (WIDTH, HEIGHT)).into_drawing_area();
+        root.fill(&WHITE).expect("could not draw plot");
+        let mut chart = ChartBuilder::on(&root)
+            .caption(format!("{} {}", title, x_desc), ("sans-serif", 20))
+            .margin(10).x_label_area_size(40).y_label_area_size(60)
+            .build_cartesian_2d(x_min..x_max, 0f64..(max_count * 1.1).max(1.0)).expect("could not draw plot");
+        chart.configure_mesh().disable_mesh().x_desc(x_desc).y_desc("cells").draw().expect("could not draw plot");
+        chart.draw_series(counts.iter().enumerate().map(|(bin, &count)| {
+            let left = x_min + (bin as f64) * bin_width;
+            Rectangle::new([(left, 0.0), (left + bin_width, count as f64)], BLUE.mix(0.6).filled())
+        })).expect("could not draw plot");
+        if let Some(threshold) = threshold {
+            chart.draw_series(LineSeries::new(vec![(threshold, 0.0), (threshold, max_count * 1.1)], RED.stroke_width(2))).expect("could not draw plot")
+                .label(format!("global threshold {:.3}", threshold))
+                .legend(|(x, y)| PathElement::new(vec![(x - 5, y), (x + 5, y)], RED.stroke_width(2)));
+        }
+        if !group_thresholds.is_empty() {
+            chart.draw_series(group_thresholds.iter().map(|(_group, threshold)| PathElement::new(vec![(*threshold, 0.0), (*threshold, max_count * 1.1)], MAGENTA.stroke_width(1))))
+                .expect("could not draw plot")
+                .label(format!("{} group thresholds", group_thresholds.len()))
+                .legend(|(x, y)| PathElement::new(vec![(x - 5, y), (x + 5, y)], MAGENTA.stroke_width(1)));
+        }
+        if threshold.is_some() || !group_thresholds.is_empty() {
+            chart.configure_series_labels().background_style(WHITE.mix(0.8)).border_style(BLACK).draw().expect("could not draw plot");
+        }
+        root.present().expect("could not draw plot");
+    }
+    return svg;
+}
+
+// per locus log likelihood per cell in the minority against the majority, loci far below the diagonal separate the genotypes
+pub fn locus_contribution_svg(contributions: &LocusContributions, title: &str) -> String {
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, (WIDTH, HEIGHT)).into_drawing_area();
+        root.fill(&WHITE).expect("could not draw plot");
+        let (x_min, x_max) = padded_range(&contributions.majority_per_cell);
+        let (y_min, y_max) = padded_range(&contributions.minority_per_cell);
+        let mut chart = ChartBuilder::on(&root)
+            .caption(format!("{} per locus contribution ({} loci)", title, contributions.minority_per_cell.len()), ("sans-serif", 20))
+            .margin(10).x_label_area_size(40).y_label_area_size(70)
+            .build_cartesian_2d(x_min..x_max, y_min..y_max).expect("could not draw plot");
+        chart.configure_mesh().disable_mesh().x_desc("majority log likelihood per cell").y_desc("minority log likelihood per cell").draw().expect("could not draw plot");
+        chart.draw_series(contributions.majority_per_cell.iter().zip(contributions.minority_per_cell.iter())
+            .map(|(&majority, &minority)| Circle::new((majority, minority), 2, BLUE.mix(0.5).filled()))).expect("could not draw plot");
+        root.present().expect("could not draw plot");
+    }
+    return svg;
+}
@@ -0,0 +1,224 @@
+use std::io::{BufWriter, Write};
+use hashbrown::HashMap;
+use statrs::statistics::{Data, OrderStatistics};
+use File;
+use Params;
+use load_data::CellData;
+use plot;
+use stats::FractionEstimate;
+use cell_types::CellTypeSummary;
+use em::EmIteration;
+
+// single static html summary of a run at {output_directory}/cellector_report.html, the plots are inline svg so it
+// can be emailed or opened offline
+pub struct IterationSummary {
+    pub num_anomalous: usize,
+    pub num_new_anomalous: usize,
+    pub num_rescued: usize,
+    pub median: f64,
+    pub interquartile_range: f64,
+    pub threshold: f64, // global, over all cells with enough loci
+    pub group_thresholds: Vec<GroupThreshold>, // one per --depth_bins / --stratify_threshold group, a single all group otherwise
+    pub num_loci_used: usize,
+}
+
+pub struct GroupThreshold {
+    pub group: String,
+    pub num_cells: usize,
+    pub threshold: f64,
+}
+
+pub struct Report<'a> {
+    pub cell_data: &'a Vec<CellData>,
+    pub num_input_loci: Option<usize>,
+    pub num_loci_passing: usize, // loci with at least min_ref and min_alt
+    pub iterations: &'a Vec<IterationSummary>,
+    pub normalized_log_likelihoods: &'a Vec<f64>, // from the last iteration
+    pub loci_used_per_cell: &'a Vec<f64>,
+    pub assignment_gt_counts: &'a HashMap<String, HashMap<String, usize>>,
+    pub genotype_pair_counts: &'a HashMap<(String, String), usize>,
+    pub minority_fraction: &'a FractionEstimate,
+    pub locus_bootstrap_interval: Option<(f64, f64)>,
+    pub cell_type_summaries: &'a Vec<CellTypeSummary>, // empty without --cell_types
+    pub em_iterations: &'a Vec<EmIteration>, // empty unless --fit_mode em
+}
+
+const STYLE: &str = "body { font-family: sans-serif; max-width: 960px; margin: auto; padding: 20px; color: #222; }
+table { border-collapse: collapse; margin: 10px 0 20px 0; }
+th, td { border: 1px solid #ccc; padding: 4px 10px; text-align: right; }
+th { background: #f0f0f0; }
+td:first-child, th:first-child { text-align: left; }
+.note { color: #555; font-size: 0.9em; }";
+
+fn escape(text: &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+}
+
+fn table(header: &Vec<String>, rows: &Vec<Vec<String>>) -> String {
+    let mut html = String::from("<table>\n<tr>");
+    for column in header { html.push_str(&format!("<th>{}</th>", escape(column))); }
+    html.push_str("</tr>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for value in row { html.push_str(&format!("<td>{}</td>", escape(value))); }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+    return html;
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    return values.iter().map(|value| value.to_string()).collect();
+}
+
+pub fn write_report(params: &Params, report: &Report) {
+    let mut html = String::new();
+    html.push_str(&format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>cellector report</title>\n<style>\n{}\n</style>\n</head>\n<body>\n", STYLE));
+    html.push_str("<h1>cellector report</h1>\n");
+    html.push_str(&format!("<p class=\"note\">output directory {}</p>\n", escape(&params.output_directory)));
+
+    html.push_str("<h2>Inputs</h2>\n");
+    let num_cells = report.cell_data.len();
+    let cells_without_loci = report.loci_used_per_cell.iter().filter(|loci| **loci == 0.0).count();
+    let median_loci = if num_cells > 0 { Data::new(report.loci_used_per_cell.clone()).median() } else { 0.0 };
+    let mut rows: Vec<Vec<String>> = vec![vec!["cells".to_string(), num_cells.to_string()]];
+    if let Some(num_input_loci) = report.num_input_loci { rows.push(vec!["loci in the vcf".to_string(), num_input_loci.to_string()]); }
+    rows.push(vec![format!("loci with at least {} ref and {} alt", params.min_ref, params.min_alt), report.num_loci_passing.to_string()]);
+    let num_loci_used = report.iterations.last().map(|iteration| iteration.num_loci_used).unwrap_or(report.num_loci_passing);
+    rows.push(vec!["loci used after locus filtering".to_string(), num_loci_used.to_string()]);
+    rows.push(vec!["loci filtered for outlying minority log likelihood".to_string(), (report.num_loci_passing - num_loci_used).to_string()]);
+    rows.push(vec!["median loci used per cell".to_string(), format!("{}", median_loci)]);
+    rows.push(vec!["cells with no usable loci".to_string(), cells_without_loci.to_string()]);
+    html.push_str(&table(&strings(&["", "count"]), &rows));
+
+    html.push_str("<h2>Iterations</h2>\n<p>Cells whose log likelihood / loci used falls below the threshold (first quartile minus ");
+    html.push_str(&format!("{} times the interquartile range) are called anomalous, iterating until no cell changes.</p>\n", params.interquartile_range_multiple));
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for (iteration, summary) in report.iterations.iter().enumerate() {
+        rows.push(vec![iteration.to_string(), summary.num_anomalous.to_string(), summary.num_new_anomalous.to_string(), summary.num_rescued.to_string(),
+            format!("{:.4}", summary.median), format!("{:.4}", summary.interquartile_range), format!("{:.4}", summary.threshold), summary.num_loci_used.to_string()]);
+    }
+    html.push_str(&table(&strings(&["iteration", "anomalous cells", "new", "rescued", "median", "interquartile range", "global threshold", "loci used"]), &rows));
+    let group_thresholds: Vec<(String, f64)> = match report.iterations.last() {
+        Some(last) if last.group_thresholds.len() > 1 => last.group_thresholds.iter().map(|group| (group.group.clone(), group.threshold)).collect(),
+        _ => Vec::new(),
+    };
+    if let Some(last) = report.iterations.last() {
+        if !group_thresholds.is_empty() {
+            html.push_str("<p>Thresholds of the final iteration by group (--depth_bins, --stratify_threshold), groups with too few cells use the global threshold.</p>\n");
+            let rows: Vec<Vec<String>> = last.group_thresholds.iter().map(|group| vec![group.group.clone(), group.num_cells.to_string(), format!("{:.4}", group.threshold)]).collect();
+            html.push_str(&table(&strings(&["group", "cells", "threshold"]), &rows));
+        }
+    }
+
+    if !report.em_iterations.is_empty() {
+        html.push_str("<h2>Mixture model</h2>\n<p>--fit_mode em: starting from the posteriors of the iterations above, the minority, majority and doublet beta binomial mixture was refit with soft responsibilities over all cells.</p>\n");
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        for (iteration, em_iteration) in report.em_iterations.iter().enumerate() {
+            rows.push(vec![(iteration + 1).to_string(), format!("{:.4}", em_iteration.log_likelihood), format!("{:.4}", em_iteration.minority_fraction), format!("{:.4}", em_iteration.doublet_fraction), em_iteration.num_minority.to_string()]);
+        }
+        html.push_str(&table(&strings(&["em iteration", "total log likelihood", "minority fraction", "doublet fraction", "minority cells"]), &rows));
+    }
+
+    html.push_str("<h2>Final assignments</h2>\n<p>0 is the minority (anomalous) genotype, 1 the majority.</p>\n");
+    let mut assignments: Vec<&String> = report.assignment_gt_counts.keys().collect();
+    assignments.sort();
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for assignment in &assignments {
+        let count: usize = report.assignment_gt_counts.get(*assignment).unwrap().values().sum();
+        rows.push(vec![assignment.to_string(), count.to_string(), format!("{:.1}%", 100.0 * (count as f64) / (num_cells.max(1) as f64))]);
+    }
+    html.push_str(&table(&strings(&["assignment", "cells", "percent"]), &rows));
+
+    html.push_str("<h2>Minority fraction</h2>\n<p>Mean probability of being minority rather than majority over the cells that are not doublets and have at least ");
+    html.push_str(&format!("{} loci, with 95% intervals.</p>\n", params.min_loci_used));
+    let estimate = report.minority_fraction;
+    let mut rows: Vec<Vec<String>> = vec![
+        vec!["posterior (uncertainty of the calls only, no width when they are confident)".to_string(), format!("{:.4}", estimate.fraction), format!("{:.4}", estimate.posterior_interval.0), format!("{:.4}", estimate.posterior_interval.1)],
+        vec![format!("bootstrap over cells ({} resamplings)", params.bootstrap_iterations), format!("{:.4}", estimate.fraction), format!("{:.4}", estimate.bootstrap_interval.0), format!("{:.4}", estimate.bootstrap_interval.1)],
+    ];
+    if let Some((low, high)) = report.locus_bootstrap_interval {
+        rows.push(vec![format!("bootstrap over loci ({} resamplings)", params.locus_bootstrap_iterations), format!("{:.4}", estimate.fraction), format!("{:.4}", low), format!("{:.4}", high)]);
+    }
+    html.push_str(&table(&strings(&["interval", "minority fraction", "low", "high"]), &rows));
+
+    if !report.cell_type_summaries.is_empty() {
+        html.push_str("<h2>Cell types</h2>\n");
+        if params.stratify_threshold { html.push_str("<p class=\"note\">outlier thresholds were stratified by cell type</p>\n"); }
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        for summary in report.cell_type_summaries {
+            let estimate = &summary.minority_fraction;
+            rows.push(vec![summary.cell_type.clone(), summary.num_cells.to_string(), summary.count("0").to_string(), summary.count("1").to_string(),
+                summary.count("doublet").to_string(), summary.count("unassigned").to_string(), format!("{:.4}", summary.mean_posterior),
+                format!("{:.4}", estimate.fraction), format!("{:.4} to {:.4}", estimate.bootstrap_interval.0, estimate.bootstrap_interval.1)]);
+        }
+        html.push_str(&table(&strings(&["cell type", "cells", "minority", "majority", "doublet", "unassigned", "mean posterior", "minority fraction", "95% bootstrap interval"]), &rows));
+    }
+
+    html.push_str("<h2>Outlier statistic</h2>\n");
+    if let Some(last) = report.iterations.last() {
+        let data = plot::IterationData {
+            assignments: report.cell_data.iter().map(|cell| cell.assignment.clone()).collect(),
+            normalized_log_likelihoods: report.normalized_log_likelihoods.clone(),
+            loci_used: report.loci_used_per_cell.clone(),
+            threshold: last.threshold,
+            group_thresholds: group_thresholds.clone(),
+            cell_thresholds: Vec::new(),
+        };
+        html.push_str(&plot::log_likelihood_svg(&data, "final iteration"));
+        html.push('\n');
+        html.push_str(&plot::histogram_svg(report.normalized_log_likelihoods, Some(last.threshold), &group_thresholds, "final iteration", "log likelihood / loci used"));
+        html.push('\n');
+    }
+
+    html.push_str("<h2>Genotype concordance</h2>\n");
+    if report.genotype_pair_counts.is_empty() {
+        html.push_str("<p class=\"note\">no vcf given, cellector.vcf was not written</p>\n");
+    } else {
+        let (mut both_called, mut concordant) = (0, 0);
+        let mut pairs: Vec<(&(String, String), &usize)> = report.genotype_pair_counts.iter().collect();
+        pairs.sort();
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        for ((majority, minority), count) in pairs {
+            if majority != "./." && minority != "./." {
+                both_called += count;
+                if majority == minority { concordant += count; }
+            }
+            rows.push(vec![majority.clone(), minority.clone(), count.to_string()]);
+        }
+        html.push_str(&format!("<p>Genotypes called in cellector.vcf for both the majority and minority at {} loci, {} ({:.1}%) are the same and {} differ.</p>\n",
+            both_called, concordant, 100.0 * (concordant as f64) / (both_called.max(1) as f64), both_called - concordant));
+        html.push_str(&table(&strings(&["majority genotype", "minority genotype", "loci"]), &rows));
+    }
+
+    let has_ground_truth = report.cell_data.iter().any(|cell| cell.assignment != "na");
+    if has_ground_truth {
+        html.push_str("<h2>Ground truth</h2>\n");
+        let mut truths: Vec<String> = report.cell_data.iter().map(|cell| cell.assignment.clone()).collect();
+        truths.sort();
+        truths.dedup();
+        let mut header: Vec<String> = vec!["ground truth".to_string()];
+        for assignment in &assignments { header.push(format!("assigned {}", assignment)); }
+        header.push("percent assigned 0".to_string());
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        for truth in &truths {
+            let mut row: Vec<String> = vec![truth.clone()];
+            let mut total = 0;
+            for assignment in &assignments {
+                let count = *report.assignment_gt_counts.get(*assignment).unwrap().get(truth).unwrap_or(&0);
+                total += count;
+                row.push(count.to_string());
+            }
+            let minority = report.assignment_gt_counts.get("0").map(|counts| *counts.get(truth).unwrap_or(&0)).unwrap_or(0);
+            row.push(format!("{:.1}%", 100.0 * (minority as f64) / (total.max(1) as f64)));
+            rows.push(row);
+        }
+        html.push_str(&table(&header, &rows));
+    }
+    html.push_str("</body>\n</html>\n");
+
+    let filename = format!("{}/cellector_report.html", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    writer.write_all(html.as_bytes()).expect("could not write report");
+}
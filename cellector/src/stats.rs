@@ -1,4 +1,6 @@
 extern crate statrs;
+use rand::Rng;
+use rand::rngs::StdRng;
 
 pub struct LogBetaBinomialExpectation{
     pub expected_log_likelihood: f64,
@@ -18,7 +20,7 @@ pub fn expected_log_beta_binomial_pmf(total_count: usize, alpha: f64, beta: f64,
     }
     let mut expectation = 2.0*log_likelihoods[0]; // 2.0* in log space to square it
     for k in 1..(total_count+1) {
-        expectation = logsumexp(expectation, 2.0*log_likelihoods[k]); // again 2.0* in log to square
+        expectation = logsumexp(&[expectation, 2.0*log_likelihoods[k]]); // again 2.0* in log to square
     }
     let mut variance: f64 = 0.0; // um how do we compute in log space? or even should we?
     // I guess this doesnt need to be in log
@@ -32,9 +34,11 @@ pub fn expected_log_beta_binomial_pmf(total_count: usize, alpha: f64, beta: f64,
     };
 }
 
-pub fn logsumexp(val_1: f64, val_2: f64) -> f64 {
-    let max = val_1.max(val_2);
-    let sum = (val_1 - max).exp() + (val_2 - max).exp();
+// -inf if every value is -inf (eg all zero probabilities) rather than the nan of -inf - -inf
+pub fn logsumexp(values: &[f64]) -> f64 {
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY { return max; }
+    let sum: f64 = values.iter().map(|value| (value - max).exp()).sum();
     max + sum.ln()
 }
 
@@ -64,4 +68,50 @@ pub fn precompute_log_binomial_coefficients(max_n: usize) -> Vec<Vec<f64>> {
     return log_binomial_coefficients;
 }
 
+pub fn log_binomial_coefficient(total_count: usize, k: usize, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> f64 {
+    if total_count < precomputed_log_binomial_coefficients.len() {
+        return precomputed_log_binomial_coefficients[total_count][k];
+    }
+    return statrs::function::factorial::ln_binomial(total_count as u64, k as u64) as f64;
+}
+
+// minority fraction of a group of cells from each cell's probability of being minority rather than majority, with 95%
+// intervals from the variance of the posterior sum and from a percentile bootstrap over cells. the posterior interval
+// (sum of p(1-p) / n^2) only covers the uncertainty of these cells' calls, it has no width when the calls are
+// confident however few cells there are, so it is not a confidence interval for the fraction. the bootstrap interval
+// also covers which cells were sequenced and is the one to report
+pub struct FractionEstimate {
+    pub fraction: f64,
+    pub posterior_interval: (f64, f64),
+    pub bootstrap_interval: (f64, f64),
+}
+
+pub fn minority_fraction_estimate(minority_probabilities: &Vec<f64>, bootstrap_iterations: usize, rng: &mut StdRng) -> FractionEstimate {
+    let num_cells = minority_probabilities.len();
+    if num_cells == 0 {
+        return FractionEstimate { fraction: 0.0, posterior_interval: (0.0, 0.0), bootstrap_interval: (0.0, 0.0) };
+    }
+    let fraction = minority_probabilities.iter().sum::<f64>() / num_cells as f64;
+    let variance: f64 = minority_probabilities.iter().map(|p| p * (1.0 - p)).sum::<f64>() / (num_cells * num_cells) as f64;
+    let half_width = 1.96 * variance.sqrt();
+    let mut bootstrap_fractions: Vec<f64> = Vec::new();
+    for _ in 0..bootstrap_iterations {
+        let mut sum = 0.0;
+        for _ in 0..num_cells { sum += minority_probabilities[rng.gen_range(0..num_cells)]; }
+        bootstrap_fractions.push(sum / num_cells as f64);
+    }
+    let bootstrap_interval = percentile_interval(bootstrap_fractions).unwrap_or((fraction, fraction));
+    return FractionEstimate {
+        fraction: fraction,
+        posterior_interval: ((fraction - half_width).max(0.0), (fraction + half_width).min(1.0)),
+        bootstrap_interval: bootstrap_interval,
+    };
+}
 
+// 2.5th and 97.5th percentiles of bootstrap replicates, None without any
+pub fn percentile_interval(mut values: Vec<f64>) -> Option<(f64, f64)> {
+    if values.is_empty() { return None; }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let len = values.len();
+    return Some((values[((len as f64 * 0.025) as usize).min(len - 1)], values[((len as f64 * 0.975) as usize).min(len - 1)]));
+}
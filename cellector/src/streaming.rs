@@ -0,0 +1,256 @@
+use hashbrown::HashSet;
+use Params;
+use stats;
+use AlphaBeta;
+use AlleleCount;
+use CellLogLikelihoodData;
+use LocusLogLikelihoodData;
+use FinalResults;
+use report::IterationSummary;
+use load_data;
+use classify;
+use load_data::{VartrixDatum, VcfLocusData};
+
+// bounded memory mode (--streaming) for atlases where the sparse matrix does not fit. alt.mtx and ref.mtx are read again
+// on every pass instead of being loaded, only per locus statistics (pooled counts and the excluded cells' counts, which
+// is all the alpha betas need) and per cell scalars are kept. each iteration is two passes: the cell log likelihoods,
+// then the locus contributions of the new outliers, which also sums the excluded cells' counts for the next iteration
+struct LocusTotals {
+    total_cells: usize,
+    locus_ids: Vec<usize>, // used locus index to locus id
+    locus_to_used_index: Vec<Option<usize>>,
+    locus_counts: Vec<[f64; 2]>, // [ref, alt] pooled over all cells per used locus
+}
+
+pub fn run_streaming(params: &Params, cell_id_to_barcode: &Vec<String>, cell_id_to_assignment: &Vec<String>, vcf_data: &Option<Vec<VcfLocusData>>) {
+    let totals = load_locus_totals(params);
+    let cell_data = load_data::init_cell_data(params, totals.total_cells, cell_id_to_barcode, cell_id_to_assignment);
+    let precomputed_log_binomial_coefficients = stats::precompute_log_binomial_coefficients(100);
+    let num_loci_passing = totals.locus_ids.len();
+    let mut loci_used: Vec<bool> = vec![true; num_loci_passing];
+    let mut minority_counts: Vec<[f64; 2]> = vec![[0.0; 2]; num_loci_passing];
+    let mut excluded_cells: HashSet<usize> = HashSet::new();
+    let mut iteration_summaries: Vec<IterationSummary> = Vec::new();
+    let mut iteration = 0;
+    let (log_likelihoods_loci_normalized, loci_used_per_cell) = loop {
+        let alpha_betas = group_alpha_betas(&totals.locus_counts, &minority_counts, false);
+        let cell_log_likelihood_data = stream_cell_log_likelihoods(params, &totals, &loci_used, &alpha_betas, &precomputed_log_binomial_coefficients);
+        let (new_excluded, normalized_log_likelihoods, cell_thresholds, mut summary) = ::find_outlier_cells(params, &cell_data, &cell_log_likelihood_data, &excluded_cells, iteration);
+        let (locus_data, new_minority_counts) = stream_locus_log_likelihoods(params, &totals, &loci_used, &alpha_betas, &new_excluded, &precomputed_log_binomial_coefficients);
+        ::locus_filter_and_output_locus_data(params, &mut loci_used, &locus_data, &totals.locus_ids, vcf_data, iteration);
+        ::output_iteration_tsv(params, &cell_data, &cell_log_likelihood_data, &cell_thresholds, &summary, iteration);
+        summary.num_loci_used = loci_used.iter().filter(|used| **used).count();
+        let any_change = summary.num_new_anomalous > 0 || summary.num_rescued > 0;
+        iteration_summaries.push(summary);
+        excluded_cells = new_excluded;
+        minority_counts = new_minority_counts;
+        iteration += 1;
+        if !any_change { break (normalized_log_likelihoods, cell_log_likelihood_data.loci_used_per_cell); }
+    };
+
+    let minority_fraction = (excluded_cells.len() as f64 + 1.0)/(cell_data.len() as f64 + 1.0);
+    let (alpha_betas_minority_dist, alpha_betas_majority_dist, alpha_betas_doublet_dist) = ::posterior_alpha_betas(minority_fraction,
+        group_alpha_betas(&totals.locus_counts, &minority_counts, true), group_alpha_betas(&totals.locus_counts, &minority_counts, false));
+    let minority_counts_rounded: Vec<[usize; 2]> = minority_counts.iter().map(|counts| [counts[0] as usize, counts[1] as usize]).collect();
+    let loci_used_for_posteriors = ::get_loci_used_for_posterior_calc(params, &loci_used, &minority_counts_rounded, &totals.locus_counts);
+    let final_pass = stream_final_pass(params, &totals, &loci_used_for_posteriors, &[&alpha_betas_minority_dist, &alpha_betas_majority_dist, &alpha_betas_doublet_dist],
+        &excluded_cells, &precomputed_log_binomial_coefficients);
+    let minority_fraction = minority_fraction.max(0.01);
+    let (posteriors, doublet_posteriors, log_likelihoods) = ::combine_posteriors(minority_fraction, &final_pass.log_likelihoods[0], &final_pass.log_likelihoods[1], &final_pass.log_likelihoods[2]);
+    ::write_final_outputs(params, &cell_data, &loci_used, &totals.locus_ids, vcf_data, None, &FinalResults {
+        excluded_cells: excluded_cells,
+        posteriors: posteriors,
+        doublet_posteriors: doublet_posteriors,
+        log_likelihoods: log_likelihoods,
+        normalized_log_likelihoods: log_likelihoods_loci_normalized,
+        loci_used_per_cell: loci_used_per_cell,
+        cell_num_loci: final_pass.cell_num_loci,
+        locus_alleles: final_pass.locus_alleles,
+        iteration_summaries: iteration_summaries,
+        num_loci_passing: num_loci_passing,
+        locus_bootstrap_interval: None,
+        em_iterations: Vec::new(),
+        model: Some(classify::FittedModel {
+            minority_fraction: minority_fraction,
+            loci_used: loci_used_for_posteriors,
+            distributions: [alpha_betas_minority_dist, alpha_betas_majority_dist, alpha_betas_doublet_dist],
+        }),
+    });
+}
+
+// one pass over the mtx files, skipping zero entries as the in memory CountStore does
+fn nonzero_entries(params: &Params) -> (usize, usize, Box<dyn Iterator<Item = VartrixDatum>>) {
+    let (total_loci, total_cells, entries) = load_data::matrix_entries(params, &None);
+    return (total_loci, total_cells, Box::new(entries.filter(|data| data.ref_count + data.alt_count > 0)));
+}
+
+// first pass: the loci with at least min_ref cells with ref and min_alt cells with alt umis and their pooled counts
+fn load_locus_totals(params: &Params) -> LocusTotals {
+    let (total_loci, total_cells, entries) = nonzero_entries(params);
+    let mut cells_with_ref_alt: Vec<[usize; 2]> = vec![[0; 2]; total_loci];
+    let mut pooled: Vec<[f64; 2]> = vec![[0.0; 2]; total_loci];
+    for data in entries {
+        if data.ref_count > 0 { cells_with_ref_alt[data.locus][0] += 1; }
+        if data.alt_count > 0 { cells_with_ref_alt[data.locus][1] += 1; }
+        pooled[data.locus][0] += data.ref_count as f64;
+        pooled[data.locus][1] += data.alt_count as f64;
+    }
+    let mut locus_ids: Vec<usize> = Vec::new();
+    let mut locus_to_used_index: Vec<Option<usize>> = vec![None; total_loci];
+    let mut locus_counts: Vec<[f64; 2]> = Vec::new();
+    for locus_id in 0..total_loci {
+        if cells_with_ref_alt[locus_id][0] < params.min_ref || cells_with_ref_alt[locus_id][1] < params.min_alt { continue; }
+        locus_to_used_index[locus_id] = Some(locus_ids.len());
+        locus_ids.push(locus_id);
+        locus_counts.push(pooled[locus_id]);
+    }
+    println!("streaming {} cells, {} of {} loci pass --min_ref and --min_alt", total_cells, locus_ids.len(), total_loci);
+    return LocusTotals {
+        total_cells: total_cells,
+        locus_ids: locus_ids,
+        locus_to_used_index: locus_to_used_index,
+        locus_counts: locus_counts,
+    };
+}
+
+// alpha betas of the excluded cells (minority) or of everything else, from the pooled and excluded counts
+fn group_alpha_betas(locus_counts: &Vec<[f64; 2]>, minority_counts: &Vec<[f64; 2]>, minority: bool) -> Vec<AlphaBeta> {
+    let mut alpha_betas: Vec<AlphaBeta> = Vec::new();
+    for (pooled, excluded) in locus_counts.iter().zip(minority_counts.iter()) {
+        if minority {
+            alpha_betas.push(AlphaBeta{alpha: excluded[1] + 1.0, beta: excluded[0] + 1.0});
+        } else {
+            alpha_betas.push(AlphaBeta{alpha: pooled[1] + 1.0 - excluded[1], beta: pooled[0] + 1.0 - excluded[0]});
+        }
+    }
+    return alpha_betas;
+}
+
+fn log_pmf(alpha_beta: &AlphaBeta, ref_count: usize, alt_count: usize, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> f64 {
+    let log_binomial_coefficient = stats::log_binomial_coefficient(ref_count + alt_count, alt_count, precomputed_log_binomial_coefficients);
+    return stats::log_beta_binomial_pmf(alt_count as f64, ref_count as f64, alpha_beta.alpha, alpha_beta.beta, log_binomial_coefficient);
+}
+
+fn stream_cell_log_likelihoods(params: &Params, totals: &LocusTotals, loci_used: &Vec<bool>, alpha_betas: &Vec<AlphaBeta>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> CellLogLikelihoodData {
+    let mut log_likelihoods: Vec<f64> = vec![0.0; totals.total_cells];
+    let mut loci_used_per_cell: Vec<f64> = vec![0.0; totals.total_cells];
+    let mut expected_log_likelihoods: Vec<f64> = vec![0.0; totals.total_cells];
+    let mut expected_log_variances: Vec<f64> = vec![0.0; totals.total_cells];
+    let (_total_loci, _total_cells, entries) = nonzero_entries(params);
+    for data in entries {
+        let locus_index = match totals.locus_to_used_index[data.locus] {
+            Some(locus_index) if loci_used[locus_index] => locus_index,
+            _ => continue,
+        };
+        let alpha_beta = &alpha_betas[locus_index];
+        log_likelihoods[data.cell_id] += log_pmf(alpha_beta, data.ref_count, data.alt_count, precomputed_log_binomial_coefficients);
+        let expected_log_pmf = stats::expected_log_beta_binomial_pmf(data.ref_count + data.alt_count, alpha_beta.alpha, alpha_beta.beta, precomputed_log_binomial_coefficients);
+        expected_log_likelihoods[data.cell_id] += expected_log_pmf.expected_log_likelihood;
+        expected_log_variances[data.cell_id] += expected_log_pmf.expected_log_variance;
+        loci_used_per_cell[data.cell_id] += 1.0;
+    }
+    return CellLogLikelihoodData {
+        log_likelihoods: log_likelihoods,
+        loci_used_per_cell: loci_used_per_cell,
+        expected_log_likelihoods: expected_log_likelihoods,
+        expected_log_variances: expected_log_variances,
+        all_pmfs: Vec::new(),
+    };
+}
+
+// per locus contributions of the excluded and other cells (as get_locus_log_likelihoods) and the excluded cells'
+// [ref, alt] counts at every used locus index, filtered or not, for the next alpha betas
+fn stream_locus_log_likelihoods(params: &Params, totals: &LocusTotals, loci_used: &Vec<bool>, alpha_betas: &Vec<AlphaBeta>, excluded_cells: &HashSet<usize>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> (LocusLogLikelihoodData, Vec<[f64; 2]>) {
+    let num_loci = loci_used.len();
+    let mut contributions: [Vec<f64>; 2] = [vec![0.0; num_loci], vec![0.0; num_loci]]; // minority, majority
+    let mut cells: [Vec<usize>; 2] = [vec![0; num_loci], vec![0; num_loci]];
+    let mut alleles: [Vec<[usize; 2]>; 2] = [vec![[0; 2]; num_loci], vec![[0; 2]; num_loci]];
+    let mut minority_counts: Vec<[f64; 2]> = vec![[0.0; 2]; num_loci];
+    let mut is_excluded: Vec<bool> = vec![false; totals.total_cells];
+    for cell_id in excluded_cells { is_excluded[*cell_id] = true; }
+    let (_total_loci, _total_cells, entries) = nonzero_entries(params);
+    for data in entries {
+        let locus_index = match totals.locus_to_used_index[data.locus] {
+            Some(locus_index) => locus_index,
+            None => continue,
+        };
+        if is_excluded[data.cell_id] {
+            minority_counts[locus_index][0] += data.ref_count as f64;
+            minority_counts[locus_index][1] += data.alt_count as f64;
+        }
+        if !loci_used[locus_index] { continue; }
+        let group = if is_excluded[data.cell_id] { 0 } else { 1 };
+        contributions[group][locus_index] += log_pmf(&alpha_betas[locus_index], data.ref_count, data.alt_count, precomputed_log_binomial_coefficients);
+        cells[group][locus_index] += 1;
+        alleles[group][locus_index][0] += data.ref_count;
+        alleles[group][locus_index][1] += data.alt_count;
+    }
+    let allele_counts = |group: &Vec<[usize; 2]>| -> Vec<AlleleCount> { group.iter().map(|counts| AlleleCount{ ref_count: counts[0], alt_count: counts[1] }).collect() };
+    let [contributions_minority, contributions_majority] = contributions;
+    let [cells_minority, cells_majority] = cells;
+    let locus_data = LocusLogLikelihoodData {
+        locus_contributions_minority: contributions_minority.clone(),
+        locus_contributions_majority: contributions_majority.clone(),
+        locus_cells_minority: cells_minority,
+        locus_cells_majority: cells_majority,
+        locus_alleles_minority: allele_counts(&alleles[0]),
+        locus_alleles_majority: allele_counts(&alleles[1]),
+        locus_expected_contribution_minority: contributions_minority, // as in get_locus_log_likelihoods
+        locus_expected_contribution_majority: contributions_majority,
+    };
+    return (locus_data, minority_counts);
+}
+
+struct FinalPass {
+    log_likelihoods: Vec<Vec<f64>>, // per distribution, per cell
+    cell_num_loci: Vec<usize>,
+    locus_alleles: (Vec<AlleleCount>, Vec<AlleleCount>), // (minority, majority) of every locus for cellector.vcf
+}
+
+// last pass: the cell log likelihoods under each of the posterior distributions, the used loci per cell and, with --vcf,
+// the minority and majority counts at every locus
+fn stream_final_pass(params: &Params, totals: &LocusTotals, loci_used: &Vec<bool>, distributions: &[&Vec<AlphaBeta>], excluded_cells: &HashSet<usize>, precomputed_log_binomial_coefficients: &Vec<Vec<f64>>) -> FinalPass {
+    let mut log_likelihoods: Vec<Vec<f64>> = distributions.iter().map(|_| vec![0.0; totals.total_cells]).collect();
+    let mut cell_num_loci: Vec<usize> = vec![0; totals.total_cells];
+    let (total_loci, _total_cells, entries) = nonzero_entries(params);
+    let num_vcf_loci = if params.vcf.is_some() { total_loci } else { 0 };
+    let mut locus_alleles_minority: Vec<AlleleCount> = (0..num_vcf_loci).map(|_| AlleleCount{ ref_count: 0, alt_count: 0 }).collect();
+    let mut locus_alleles_majority: Vec<AlleleCount> = (0..num_vcf_loci).map(|_| AlleleCount{ ref_count: 0, alt_count: 0 }).collect();
+    for data in entries {
+        if num_vcf_loci > 0 {
+            let group = if excluded_cells.contains(&data.cell_id) { &mut locus_alleles_minority } else { &mut locus_alleles_majority };
+            group[data.locus].ref_count += data.ref_count;
+            group[data.locus].alt_count += data.alt_count;
+        }
+        let locus_index = match totals.locus_to_used_index[data.locus] {
+            Some(locus_index) => locus_index,
+            None => continue,
+        };
+        cell_num_loci[data.cell_id] += 1;
+        if !loci_used[locus_index] { continue; }
+        for (distribution, alpha_betas) in distributions.iter().enumerate() {
+            log_likelihoods[distribution][data.cell_id] += log_pmf(&alpha_betas[locus_index], data.ref_count, data.alt_count, precomputed_log_binomial_coefficients);
+        }
+    }
+    return FinalPass {
+        log_likelihoods: log_likelihoods,
+        cell_num_loci: cell_num_loci,
+        locus_alleles: (locus_alleles_minority, locus_alleles_majority),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn streaming_matches_in_memory() {
+        let in_memory = ::run_test_fixture("streaming_in_memory", &["--write_obs"]);
+        let streaming = ::run_test_fixture("streaming", &["--write_obs", "--streaming"]);
+        // the assignments and, in the obs csv, the posteriors
+        for filename in ["cellector_assignments.tsv", "cellector_obs.csv"].iter() {
+            let read = |directory: &str| fs::read_to_string(format!("{}/{}", directory, filename)).unwrap();
+            assert_eq!(read(&in_memory), read(&streaming), "{} differs between --streaming and the default mode", filename);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use clap::ArgMatches;
+use rayon::prelude::*;
+use std::io::{BufWriter, Write, BufRead};
+use hashbrown::{HashMap, HashSet};
+use File;
+use bam;
+use load_data;
+use load_data::reader;
+
+// selects the common variant sites with enough cell barcoded coverage in the bam to be worth counting.
+// replaces samtools depth | bedtools merge | bedtools intersect from the python pipeline
+pub struct VariantsParams {
+    pub bam: String,
+    pub common_variants: String,
+    pub barcodes: Option<String>,
+    pub output_directory: String,
+    pub threads: usize,
+    pub min_coverage: usize,
+    pub max_coverage: usize,
+}
+
+pub fn load_variants_params(params: &ArgMatches) -> VariantsParams {
+    let bam = params.value_of("bam").unwrap().to_string();
+    let common_variants = params.value_of("common_variants").unwrap().to_string();
+    let barcodes = match params.value_of("barcodes") {
+        Some(x) => Some(x.to_string()),
+        None => None,
+    };
+    let output_directory = params.value_of("output_directory").unwrap().to_string();
+    let threads = params.value_of("threads").unwrap_or("1");
+    let threads = threads.to_string().parse::<usize>().unwrap();
+    let min_coverage = params.value_of("min_coverage").unwrap_or("8");
+    let min_coverage = min_coverage.to_string().parse::<usize>().unwrap();
+    let max_coverage = params.value_of("max_coverage").unwrap_or("100000");
+    let max_coverage = max_coverage.to_string().parse::<usize>().unwrap();
+    return VariantsParams {
+        bam: bam,
+        common_variants: common_variants,
+        barcodes: barcodes,
+        output_directory: output_directory,
+        threads: threads,
+        min_coverage: min_coverage,
+        max_coverage: max_coverage,
+    };
+}
+
+pub struct VariantSite {
+    pub chrom: String,
+    pub pos: usize,
+    pub line: String,
+}
+
+// writes {output_directory}/common_variants_covered.vcf and returns its path
+pub fn select_covered_variants(params: &VariantsParams) -> String {
+    load_data::create_output_dir(&params.output_directory);
+    let (header_lines, sites) = load_sites(&params.common_variants);
+    let valid_barcodes = load_valid_barcodes(&params.barcodes);
+    let mut sites_by_chrom: HashMap<String, Vec<(usize, usize)>> = HashMap::new(); // chrom to sorted (pos, site index)
+    for (index, site) in sites.iter().enumerate() {
+        sites_by_chrom.entry(site.chrom.clone()).or_insert(Vec::new()).push((site.pos, index));
+    }
+    for chrom_sites in sites_by_chrom.values_mut() { chrom_sites.sort(); }
+
+    let (_reader, header) = bam::open_bam(&params.bam);
+    let regions = bam::get_bam_regions(&header, params.threads);
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(params.threads).build().expect("could not build thread pool");
+    let region_coverages: Vec<Vec<(usize, usize)>> = pool.install(|| {
+        regions.par_iter().map(|region| region_coverage(params, region, &sites_by_chrom, &valid_barcodes)).collect()
+    });
+    let mut coverage: Vec<usize> = vec![0; sites.len()];
+    for region_coverage in region_coverages {
+        for (site_index, depth) in region_coverage { coverage[site_index] += depth; }
+    }
+
+    let filename = format!("{}/common_variants_covered.vcf", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    for line in &header_lines {
+        writer.write_all(format!("{}\n", line).as_bytes()).expect("could not write covered variants vcf");
+    }
+    let mut num_covered = 0;
+    for (index, site) in sites.iter().enumerate() {
+        if coverage[index] >= params.min_coverage && coverage[index] < params.max_coverage {
+            writer.write_all(format!("{}\n", site.line).as_bytes()).expect("could not write covered variants vcf");
+            num_covered += 1;
+        }
+    }
+    println!("{} of {} common variant sites have between {} and {} cell barcoded reads", num_covered, sites.len(), params.min_coverage, params.max_coverage);
+    return filename;
+}
+
+pub fn load_sites(vcf: &str) -> (Vec<String>, Vec<VariantSite>) {
+    let mut header_lines: Vec<String> = Vec::new();
+    let mut sites: Vec<VariantSite> = Vec::new();
+    let reader = reader(vcf);
+    for line in reader.lines() {
+        let line = line.expect("Unable to read a line in the common variants vcf");
+        if line.starts_with("#") {
+            header_lines.push(line);
+            continue;
+        }
+        let toks: Vec<&str> = line.split('\t').collect();
+        let chrom = toks[0].to_string();
+        let pos = toks[1].to_string().parse::<usize>().unwrap();
+        sites.push(VariantSite{ chrom: chrom, pos: pos, line: line.clone() });
+    }
+    return (header_lines, sites);
+}
+
+pub fn load_valid_barcodes(barcodes: &Option<String>) -> Option<HashSet<String>> {
+    let barcodes = match barcodes {
+        Some(barcodes) => barcodes,
+        None => return None,
+    };
+    let mut valid_barcodes: HashSet<String> = HashSet::new();
+    let reader = reader(barcodes);
+    for line in reader.lines() {
+        let line = line.expect("Unable to read line");
+        valid_barcodes.insert(line.trim().to_string());
+    }
+    return Some(valid_barcodes);
+}
+
+// read depth at each site in the region group, counting only reads with a UB tag and a CB tag (in the barcode list if given).
+// each site belongs to exactly one region so reads spanning region boundaries are not double counted
+pub fn region_coverage(params: &VariantsParams, region: &Vec<(String, usize, usize)>, sites_by_chrom: &HashMap<String, Vec<(usize, usize)>>, valid_barcodes: &Option<HashSet<String>>) -> Vec<(usize, usize)> {
+    let mut coverage: Vec<(usize, usize)> = Vec::new();
+    let (mut reader, header) = bam::open_bam(&params.bam);
+    for (chrom, start, end) in region {
+        let chrom_sites = match sites_by_chrom.get(chrom) {
+            Some(chrom_sites) => chrom_sites,
+            None => continue,
+        };
+        // vcf positions are 1-based, region is 0-based half open
+        let first = chrom_sites.partition_point(|&(pos, _)| pos <= *start);
+        let last = chrom_sites.partition_point(|&(pos, _)| pos <= *end);
+        if first == last { continue; }
+        let region_sites = &chrom_sites[first..last];
+        let mut depths: Vec<usize> = vec![0; region_sites.len()];
+        let query = reader.query(&header, &bam::query_region(chrom, *start, *end)).expect("could not query bam");
+        for record in query.records() {
+            let record = record.expect("could not read bam record");
+            if !bam::is_primary_usable(&record) { continue; }
+            if bam::string_tag(&record, bam::UMI_TAG).is_none() { continue; }
+            match bam::string_tag(&record, bam::CELL_TAG) {
+                Some(barcode) => {
+                    if let Some(valid_barcodes) = valid_barcodes {
+                        if !valid_barcodes.contains(&barcode) { continue; }
+                    }
+                },
+                None => continue,
+            }
+            let (read_start, read_end) = match bam::alignment_span(&record) {
+                Some(span) => span,
+                None => continue,
+            };
+            let from = region_sites.partition_point(|&(pos, _)| pos < read_start);
+            for index in from..region_sites.len() {
+                let pos = region_sites[index].0;
+                if pos > read_end { break; }
+                if bam::read_offset_at(&record, pos).is_some() { depths[index] += 1; }
+            }
+        }
+        for (index, &(_pos, site_index)) in region_sites.iter().enumerate() {
+            coverage.push((site_index, depths[index]));
+        }
+    }
+    return coverage;
+}
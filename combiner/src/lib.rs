@@ -0,0 +1,98 @@
+// the output files of the combiner, in a library so cellector's tests can load what it writes
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::ffi::OsStr;
+use std::fs::File;
+
+// alt.mtx and ref.mtx share their coordinates so both have the same entry count in the header.
+// coordinates are 1-based (locus, cell) and values are integer counts. entries with no observations (eg after
+// downsampling) are not stored in a sparse matrix and are left out
+pub fn write_mtxs(output_directory: &str, gzip: bool, total_loci: usize, total_cells: usize, lines: &Vec<(usize, usize, usize, usize)>) {
+    let extension = if gzip { ".gz" } else { "" };
+    let mut alt_writer = writer(&format!("{}/alt.mtx{}", output_directory, extension));
+    let mut ref_writer = writer(&format!("{}/ref.mtx{}", output_directory, extension));
+    let num_entries = lines.iter().filter(|&&(_locus_id, _cell_id, ref_count, alt_count)| ref_count + alt_count > 0).count();
+    let header = format!("%%MatrixMarket matrix coordinate integer general\n% written by combiner\n{} {} {}\n", total_loci, total_cells, num_entries);
+    alt_writer.write_all(header.as_bytes()).expect("could not write alt mtx");
+    ref_writer.write_all(header.as_bytes()).expect("could not write ref mtx");
+    for (locus_id, cell_id, ref_count, alt_count) in lines {
+        if ref_count + alt_count == 0 { continue; }
+        assert!(*locus_id >= 1 && *cell_id >= 1, "matrix market coordinates are 1-based");
+        alt_writer.write_all(format!("{} {} {}\n", locus_id, cell_id, alt_count).as_bytes()).expect("could not write alt mtx");
+        ref_writer.write_all(format!("{} {} {}\n", locus_id, cell_id, ref_count).as_bytes()).expect("could not write ref mtx");
+    }
+    alt_writer.finish().expect("could not write alt mtx");
+    ref_writer.finish().expect("could not write ref mtx");
+}
+
+// an output cell in output cell id order
+pub struct OutputCell {
+    pub barcode: String,
+    pub dataset: usize,
+    pub assignment: &'static str, // majority for dataset1, minority for dataset2
+    pub contamination: f64,
+    pub original_barcode: String,
+}
+
+// barcodes.tsv, gt.tsv (barcode, assignment, contamination) and barcode_mapping.tsv
+pub fn write_cells(output_directory: &str, cells: &Vec<OutputCell>) {
+    let filename = format!("{}/barcodes.tsv", output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    let filename = format!("{}/gt.tsv", output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut gt_writer = BufWriter::new(filehandle);
+    let filename = format!("{}/barcode_mapping.tsv", output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut mapping_writer = BufWriter::new(filehandle);
+    mapping_writer.write_all(b"barcode\tdataset\toriginal_barcode\n").expect("could not write barcode mapping");
+    for cell in cells {
+        writer.write_all(format!("{}\n", cell.barcode).as_bytes()).expect("could not write barcodes");
+        gt_writer.write_all(format!("{}\t{}\t{}\n", cell.barcode, cell.assignment, cell.contamination).as_bytes()).expect("could not write gt");
+        mapping_writer.write_all(format!("{}\t{}\t{}\n", cell.barcode, cell.dataset, cell.original_barcode).as_bytes()).expect("could not write barcode mapping");
+    }
+}
+
+// plain or gzip output by extension. finish() must be called, dropping a gzip writer would swallow any error writing
+// the last block and the gzip trailer
+pub enum OutputWriter {
+    Plain(BufWriter<File>),
+    Gzip(BufWriter<GzEncoder<File>>),
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            OutputWriter::Gzip(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Gzip(writer) => writer.flush(),
+        }
+    }
+}
+
+impl OutputWriter {
+    pub fn finish(self) -> io::Result<()> {
+        let file = match self {
+            OutputWriter::Plain(writer) => writer.into_inner()?,
+            OutputWriter::Gzip(writer) => writer.into_inner()?.finish()?,
+        };
+        return file.sync_all();
+    }
+}
+
+pub fn writer(filename: &str) -> OutputWriter {
+    let filehandle = File::create(filename).expect(&format!("Unable to create file {}", filename));
+    if Path::new(filename).extension() == Some(OsStr::new("gz")) {
+        OutputWriter::Gzip(BufWriter::new(GzEncoder::new(filehandle, Compression::default())))
+    } else {
+        OutputWriter::Plain(BufWriter::new(filehandle))
+    }
+}
@@ -2,16 +2,18 @@
 extern crate clap;
 extern crate hashbrown;
 extern crate rand;
+extern crate rand_distr;
 extern crate flate2;
 extern crate itertools;
 
 use clap::App;
-use rand::Rng;
 use rand::seq::IteratorRandom;
 use rand::rngs::StdRng;
 use rand::SeedableRng;
+use rand_distr::{Beta, Binomial, Distribution};
 use flate2::read::MultiGzDecoder;
 use std::io::{BufWriter, Write, BufRead, BufReader};
+use combiner::{write_mtxs, write_cells, OutputCell};
 use std::path::Path;
 use std::ffi::OsStr;
 use std::fs::File;
@@ -23,7 +25,7 @@ use hashbrown::{HashMap,HashSet};
 fn main() {
     let params = load_params();
     create_output_dir(&params);
-    let (locus_data2_to_data1, total_loci_out) = get_locus_mapping(&params);
+    let (locus_data1_to_out, locus_data2_to_out, total_loci_out) = get_locus_mapping(&params);
     
     let (mut alt_reader, mut ref_reader) = (reader(&params.alt1), reader(&params.ref1));
     let (_total_loci, total_cells) = consume_mtx_header(&mut alt_reader, &mut ref_reader);
@@ -40,35 +42,23 @@ fn main() {
         cells2 = select_cells(&params, params.num_cells_2.unwrap(), total_cells);
         num_cells_2 = params.num_cells_2.unwrap();
     }
+    let contamination = draw_contamination(&params, params.num_cells_1 + num_cells_2);
     // decide which cells are doublets and that mapping
-    let (cell_ids_data1, cell_ids_data2) = get_cell_ids_and_output_barcodes(&params, &cells1, &cells2);
-    output_new_mtxs(&params, &locus_data2_to_data1, total_loci_out, &cell_ids_data1, &cell_ids_data2, num_cells_2);
+    let (cell_ids_data1, cell_ids_data2) = get_cell_ids_and_output_barcodes(&params, &cells1, &cells2, &contamination);
+    let downsample_rates = get_downsample_rates(&params, &cell_ids_data1, &cell_ids_data2);
+    output_new_mtxs(&params, &locus_data1_to_out, &locus_data2_to_out, total_loci_out, &cell_ids_data1, &cell_ids_data2, num_cells_2, &contamination, downsample_rates);
     // so now we need to make a new barcodes file
     println!("{},{}", params.num_cells_1, num_cells_2);
     // and now a new 
     //println!("{:?}", cells1);  
 }
 
-fn output_new_mtxs(params: &Params, locus_data2_to_data1: &HashMap<usize, usize>, total_loci_out: usize, cell_ids_data1: &HashMap<usize, usize>, cell_ids_data2: &HashMap<usize, usize>, num_cells_2: usize) {
-    let filename = format!("{}/alt.mtx", params.output_directory);
-    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
-    let mut alt_writer = BufWriter::new(filehandle);
-    let filename = format!("{}/ref.mtx", params.output_directory);
-    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
-    let mut ref_writer = BufWriter::new(filehandle);
+fn output_new_mtxs(params: &Params, locus_data1_to_out: &HashMap<usize, usize>, locus_data2_to_out: &HashMap<usize, (usize, bool)>, total_loci_out: usize, cell_ids_data1: &HashMap<usize, usize>, cell_ids_data2: &HashMap<usize, usize>, num_cells_2: usize, contamination: &Vec<f64>, downsample_rates: (f64, f64)) {
     let tmpseed = params.seed.to_be_bytes();//[params.seed; 32]; // 4 guaranteed random number by fair dice roll https://xkcd.com/221/
     let mut seed = [0u8;32];
     for i in 0..8 { seed[i] = tmpseed[i]; }
     let mut rng: StdRng = SeedableRng::from_seed(seed);
 
-    let total_cells = params.num_cells_1 + num_cells_2;
-    let total_loci = total_loci_out;
-    let total_entries = 0;
-    alt_writer.write_all(b"%%MatrixMarket matrix coordinate real general\n% written by sprs\n");
-    ref_writer.write_all(b"%%MatrixMarket matrix coordinate real general\n% written by sprs\n");
-    alt_writer.write_all(format!("{}\t{}\t{}\n", total_loci, total_cells, total_entries).as_bytes());
-    ref_writer.write_all(format!("{}\t{}\t{}\n", total_loci, total_cells, total_entries).as_bytes());
-
     let mut lines: Vec<(usize, usize, usize, usize)> = Vec::new(); // locus_id, cell_id, refcount, altcount
 
     let (mut alt_reader, mut ref_reader) = (reader(&params.alt1), reader(&params.ref1));
@@ -78,15 +68,9 @@ fn output_new_mtxs(params: &Params, locus_data2_to_data1: &HashMap<usize, usize>
         let mut data: VartrixDatum = read_mtx_lines(alt_line, ref_line);
         if let Some(&cell_id) = cell_ids_data1.get(&data.cell_id) {
             if data.locus < 1 {println!("what1? {}",data.locus); }
-            let refcount = data.ref_count.clone();
-            let altcount = data.alt_count.clone();
-            for _ in 0..refcount {
-                if rng.gen::<f64>() < params.downsample_rate { data.ref_count -= 1; }
-            }
-            for _ in 0..altcount {
-                if rng.gen::<f64>() < params.downsample_rate { data.alt_count -= 1; }
-            }
-            lines.push((data.locus, cell_id, data.ref_count, data.alt_count));
+            data.ref_count = downsample(data.ref_count, downsample_rates.0, &mut rng);
+            data.alt_count = downsample(data.alt_count, downsample_rates.0, &mut rng);
+            lines.push((locus_data1_to_out[&data.locus], cell_id, data.ref_count, data.alt_count));
         }
     }
 
@@ -96,22 +80,119 @@ fn output_new_mtxs(params: &Params, locus_data2_to_data1: &HashMap<usize, usize>
         let (alt_line, ref_line) = (alt_line.expect("cannot read alt mtx"), ref_line.expect("cannot read ref mtx"));
         let mut data: VartrixDatum = read_mtx_lines(alt_line, ref_line);
         if let Some(&cell_id) = cell_ids_data2.get(&data.cell_id) {
-            if *locus_data2_to_data1.get(&data.locus).unwrap() < 1 { println!("what? {}", locus_data2_to_data1.get(&data.locus).unwrap());}
-            let refcount = data.ref_count.clone();
-            let altcount = data.alt_count.clone();
-            for _ in 0..refcount {
-                if rng.gen::<f64>() < params.downsample_rate { data.ref_count -= 1; }
+            // loci whose alleles conflict with dataset1 are not in the mapping and are dropped
+            let (locus_out, swapped) = match locus_data2_to_out.get(&data.locus) {
+                Some(&mapping) => mapping,
+                None => continue,
+            };
+            if swapped {
+                std::mem::swap(&mut data.ref_count, &mut data.alt_count);
             }
-            for _ in 0..altcount {
-                if rng.gen::<f64>() < params.downsample_rate { data.alt_count -= 1; }
-            }
-            lines.push((*locus_data2_to_data1.get(&data.locus).unwrap(), cell_id, data.ref_count, data.alt_count));
+            data.ref_count = downsample(data.ref_count, downsample_rates.1, &mut rng);
+            data.alt_count = downsample(data.alt_count, downsample_rates.1, &mut rng);
+            lines.push((locus_out, cell_id, data.ref_count, data.alt_count));
         }
     }
+    if contamination.iter().any(|&c| c > 0.0) {
+        add_ambient_contamination(&mut lines, total_loci_out, contamination, &mut rng);
+    }
     lines.sort();
-    for (locus_id, cell_id, ref_count, alt_count) in lines {
-        alt_writer.write_all(format!("{}\t{}\t{}\n", locus_id, cell_id, alt_count).as_bytes());
-        ref_writer.write_all(format!("{}\t{}\t{}\n", locus_id, cell_id, ref_count).as_bytes());
+    let total_cells = params.num_cells_1 + num_cells_2;
+    write_mtxs(&params.output_directory, params.gzip, total_loci_out, total_cells, &lines);
+}
+
+// each read is kept with probability 1 - rate, so the kept count is binomial
+fn downsample(count: usize, rate: f64, rng: &mut StdRng) -> usize {
+    if rate <= 0.0 || count == 0 { return count; }
+    return Binomial::new(count as u64, 1.0 - rate).unwrap().sample(rng) as usize;
+}
+
+// downsample rates for (dataset1, dataset2). with depth matching each dataset gets its own rate so that the median
+// allele count per selected cell matches the target (or the shallower dataset), otherwise --downsample_rate is used for both. load_params rejects
+// --downsample_rate together with depth matching
+fn get_downsample_rates(params: &Params, cell_ids_data1: &HashMap<usize, usize>, cell_ids_data2: &HashMap<usize, usize>) -> (f64, f64) {
+    if params.target_median_depth.is_none() && !params.match_depth {
+        return (params.downsample_rate, params.downsample_rate);
+    }
+    let median1 = median_cell_depth(&params.alt1, &params.ref1, cell_ids_data1);
+    let median2 = median_cell_depth(&params.alt2, &params.ref2, cell_ids_data2);
+    let target = params.target_median_depth.unwrap_or(median1.min(median2));
+    let mut rates = [0.0; 2];
+    for (index, median) in [median1, median2].iter().enumerate() {
+        if *median > target {
+            rates[index] = 1.0 - target / median;
+        } else if *median < target {
+            eprintln!("dataset{} median depth {} is below the target {}, it will not be downsampled", index + 1, median, target);
+        }
+    }
+    println!("median allele count per cell {} and {}, target {}, downsample rates {} and {}", median1, median2, target, rates[0], rates[1]);
+    return (rates[0], rates[1]);
+}
+
+// median over the selected cells of the total ref + alt count across variant loci
+fn median_cell_depth(alt: &str, reference: &str, cell_ids: &HashMap<usize, usize>) -> f64 {
+    let mut depths: HashMap<usize, usize> = HashMap::new();
+    for cell_id in cell_ids.keys() { depths.insert(*cell_id, 0); }
+    let (mut alt_reader, mut ref_reader) = (reader(alt), reader(reference));
+    consume_mtx_header(&mut alt_reader, &mut ref_reader);
+    for (alt_line, ref_line) in izip!(alt_reader.lines(), ref_reader.lines()) {
+        let (alt_line, ref_line) = (alt_line.expect("cannot read alt mtx"), ref_line.expect("cannot read ref mtx"));
+        let data: VartrixDatum = read_mtx_lines(alt_line, ref_line);
+        if let Some(depth) = depths.get_mut(&data.cell_id) {
+            *depth += data.alt_count + data.ref_count;
+        }
+    }
+    let mut depths: Vec<usize> = depths.values().cloned().collect();
+    if depths.is_empty() { return 0.0; }
+    depths.sort();
+    let middle = depths.len() / 2;
+    if depths.len() % 2 == 0 {
+        return (depths[middle - 1] + depths[middle]) as f64 / 2.0;
+    }
+    return depths[middle] as f64;
+}
+
+// per output cell fraction of allele observations that came from the soup, indexed by output cell_id - 1
+fn draw_contamination(params: &Params, num_cells: usize) -> Vec<f64> {
+    let mut contamination: Vec<f64> = Vec::new();
+    match (params.contamination_alpha, params.contamination_beta) {
+        (Some(alpha), Some(beta)) => {
+            let tmpseed = params.seed.to_be_bytes();
+            let mut seed = [0u8;32];
+            for i in 0..8 { seed[i] = tmpseed[i]; }
+            let mut rng: StdRng = SeedableRng::from_seed(seed);
+            let beta_dist = Beta::new(alpha, beta).expect("contamination_alpha and contamination_beta must be > 0");
+            for _ in 0..num_cells { contamination.push(beta_dist.sample(&mut rng)); }
+        },
+        (None, None) => {
+            let rate = params.contamination.unwrap_or(0.0);
+            for _ in 0..num_cells { contamination.push(rate); }
+        },
+        _ => panic!("contamination_alpha and contamination_beta must be used together"),
+    }
+    return contamination;
+}
+
+// replace a fraction of each cell's allele observations with draws from the pooled allele frequency of the mixture.
+// each observation is replaced with probability equal to the cell's contamination, so the number replaced is binomial
+fn add_ambient_contamination(lines: &mut Vec<(usize, usize, usize, usize)>, total_loci: usize, contamination: &Vec<f64>, rng: &mut StdRng) {
+    let mut pooled_counts: Vec<[usize; 2]> = vec![[0; 2]; total_loci + 1]; // locus ids are 1 indexed, [ref, alt]
+    for (locus_id, _cell_id, ref_count, alt_count) in lines.iter() {
+        pooled_counts[*locus_id][0] += ref_count;
+        pooled_counts[*locus_id][1] += alt_count;
+    }
+    for (locus_id, cell_id, ref_count, alt_count) in lines.iter_mut() {
+        let rate = contamination[*cell_id - 1];
+        if rate <= 0.0 { continue; }
+        let pooled_total = pooled_counts[*locus_id][0] + pooled_counts[*locus_id][1];
+        if pooled_total == 0 { continue; }
+        let soup_alt_frac = (pooled_counts[*locus_id][1] as f64) / (pooled_total as f64);
+        let replaced_ref = Binomial::new(*ref_count as u64, rate).unwrap().sample(rng) as usize;
+        let replaced_alt = Binomial::new(*alt_count as u64, rate).unwrap().sample(rng) as usize;
+        let replaced = replaced_ref + replaced_alt;
+        let soup_alt = Binomial::new(replaced as u64, soup_alt_frac).unwrap().sample(rng) as usize;
+        *ref_count = *ref_count - replaced_ref + (replaced - soup_alt);
+        *alt_count = *alt_count - replaced_alt + soup_alt;
     }
 }
 
@@ -138,7 +219,7 @@ fn read_mtx_lines(alt_line: String, ref_line: String) ->
     };
 }
 
-fn get_cell_ids_and_output_barcodes(params: &Params, cells1: &Vec<usize>, cells2: &Vec<usize>) -> (HashMap<usize, usize>, HashMap<usize, usize>) {
+fn get_cell_ids_and_output_barcodes(params: &Params, cells1: &Vec<usize>, cells2: &Vec<usize>, contamination: &Vec<f64>) -> (HashMap<usize, usize>, HashMap<usize, usize>) {
     let mut barcodes1: Vec<String> = Vec::new();
     let mut barcodes2: Vec<String> = Vec::new();
     let reader1 = reader(&params.barcodes1);
@@ -151,42 +232,44 @@ fn get_cell_ids_and_output_barcodes(params: &Params, cells1: &Vec<usize>, cells2
         let line = line.expect("cannot read barcodes2");
         barcodes2.push(line.to_string());
     }
-    let filename = format!("{}/barcodes.tsv",params.output_directory);
-    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
-    let mut writer = BufWriter::new(filehandle);
-    let filename = format!("{}/gt.tsv",params.output_directory);
-    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
-    let mut gt_writer = BufWriter::new(filehandle);
-    
+    let mut cells_out: Vec<OutputCell> = Vec::new();
     let mut cell_mapping_data1: HashMap<usize, usize> = HashMap::new();
     let mut cell_mapping_data2: HashMap<usize, usize> = HashMap::new();
+    let mut barcodes_out: HashSet<String> = HashSet::new();
     let mut cell_id_out = 1;
-    for cell_id_in in cells1 {
-        cell_mapping_data1.insert(*cell_id_in, cell_id_out);
-        let mut bc = barcodes1[cell_id_in-1].to_string();
-        bc.push_str("\n");
-        writer.write_all(bc.as_bytes());
-        cell_id_out += 1;
-        let mut gt_line = barcodes1[cell_id_in-1].to_string();
-        gt_line.push_str(&format!("\tmajority\n"));
-        gt_writer.write_all(gt_line.as_bytes());
-    }
-    for cell_id_in in cells2 {
-        cell_mapping_data2.insert(*cell_id_in, cell_id_out);
-        let mut bc = barcodes2[cell_id_in-1].to_string();
-        bc.pop();
-        bc.push_str("2\n");
-        let mut gt_line = bc.clone();
-        gt_line.pop();
-        writer.write_all(bc.as_bytes());
-        cell_id_out += 1;
-        gt_line.push_str(&format!("\tminority\n"));
-        gt_writer.write_all(gt_line.as_bytes());
+    let datasets = [
+        (cells1, &barcodes1, &mut cell_mapping_data1, 1, "majority", &params.barcode_prefix1, &params.barcode_suffix1),
+        (cells2, &barcodes2, &mut cell_mapping_data2, 2, "minority", &params.barcode_prefix2, &params.barcode_suffix2),
+    ];
+    for (cells, barcodes, cell_mapping, dataset, assignment, prefix, suffix) in datasets {
+        for cell_id_in in cells {
+            cell_mapping.insert(*cell_id_in, cell_id_out);
+            let contamination_rate = contamination[cell_id_out - 1];
+            let original = &barcodes[cell_id_in-1];
+            let bc = rewrite_barcode(original, prefix, suffix);
+            assert!(barcodes_out.insert(bc.clone()),
+                "output barcode {} from dataset{} barcode {} collides with another cell, use --barcode_prefix1/2 or --barcode_suffix1/2 to make them unique", bc, dataset, original);
+            cells_out.push(OutputCell { barcode: bc, dataset: dataset, assignment: assignment, contamination: contamination_rate, original_barcode: original.to_string() });
+            cell_id_out += 1;
+        }
     }
-    
+    write_cells(&params.output_directory, &cells_out);
+
     return (cell_mapping_data1, cell_mapping_data2);
 }
 
+// a suffix replaces an existing -N suffix (eg the -1 from cellranger) or is appended if there is none, then the prefix is prepended
+fn rewrite_barcode(barcode: &str, prefix: &str, suffix: &Option<String>) -> String {
+    let mut base = barcode;
+    if suffix.is_some() {
+        if let Some(dash) = barcode.rfind('-') {
+            let tail = &barcode[(dash + 1)..];
+            if !tail.is_empty() && tail.chars().all(|c| c.is_ascii_digit()) { base = &barcode[..dash]; }
+        }
+    }
+    return format!("{}{}{}", prefix, base, suffix.as_deref().unwrap_or(""));
+}
+
 pub fn create_output_dir(params: &Params) {
     Command::new("mkdir")
         .arg(&params.output_directory)
@@ -194,40 +277,118 @@ pub fn create_output_dir(params: &Params) {
         .expect("failed to create output directory");
 }
 
-fn get_locus_mapping(params: &Params) -> (HashMap<usize, usize>, usize) {
-    let mut locus_data2_to_data1: HashMap<usize, usize> = HashMap::new(); // mapping from locus_id in dataset2 to locus_id in dataset1
-    let mut chr_pos_to_locus_id: HashMap<(String, usize), usize> = HashMap::new();    
-    let reader1 = reader(&params.vcf1);
-    let mut record_number = 1; // because stupid matrix market format is 1 indexed, we start with 1 here
-    for line in reader1.lines() {
-        let line = line.expect("Unable to read a line in vcf1");
-        if line.starts_with("#") { continue; }   
-        let toks: Vec<&str> = line.split('\t').collect();
-        let chrom = toks[0].to_string();
-        let pos = toks[1].to_string();
-        let pos = pos.parse::<usize>().unwrap();
-        chr_pos_to_locus_id.insert((chrom, pos), record_number);
-        record_number += 1;
-    }
-    let mut record_number2 = 1; // reset counter for new vcf
-    let reader2 = reader(&params.vcf2);
-    for line in reader2.lines() {
-        let line = line.expect("Unable to read a line in vcf2");
+struct VcfRecord {
+    chrom: String,
+    pos: usize,
+    ref_allele: String,
+    alt_allele: String,
+    line: String,
+}
+
+// returns the ## meta lines and the records (without sample columns) of a vcf
+fn load_vcf(filename: &str) -> (Vec<String>, Vec<VcfRecord>) {
+    let mut meta: Vec<String> = Vec::new();
+    let mut records: Vec<VcfRecord> = Vec::new();
+    let reader = reader(filename);
+    for line in reader.lines() {
+        let line = line.expect(&format!("Unable to read a line in {}", filename));
+        if line.starts_with("##") {
+            meta.push(line);
+            continue;
+        }
         if line.starts_with("#") { continue; }
         let toks: Vec<&str> = line.split('\t').collect();
-        let chrom = toks[0].to_string();
-        let pos = toks[1].to_string();
-        let pos = pos.parse::<usize>().unwrap();
-        
-        if let Some(locus_id) = chr_pos_to_locus_id.get(&(chrom, pos)) {
-            locus_data2_to_data1.insert(record_number2, *locus_id);
+        records.push(VcfRecord{
+            chrom: toks[0].to_string(),
+            pos: toks[1].parse::<usize>().unwrap(),
+            ref_allele: toks[3].to_string(),
+            alt_allele: toks[4].to_string(),
+            line: toks[..toks.len().min(8)].join("\t"),
+        });
+    }
+    return (meta, records);
+}
+
+// maps locus_id in dataset1 to locus_id in the output and locus_id in dataset2 to (locus_id in output, whether ref and
+// alt are swapped relative to the output locus). loci are matched on chrom, pos, ref and alt. dataset2 loci at a
+// dataset1 position with different alleles are dropped and written to conflicting_loci.tsv. the output loci are the
+// dataset1 loci and the unmatched dataset2 loci sorted by contig (in order of first appearance) and position, and are
+// written to merged.vcf in locus_id order
+fn get_locus_mapping(params: &Params) -> (HashMap<usize, usize>, HashMap<usize, (usize, bool)>, usize) {
+    let mut locus_data2_to_data1: HashMap<usize, (usize, bool)> = HashMap::new();
+    let (meta1, records1) = load_vcf(&params.vcf1);
+    let (meta2, records2) = load_vcf(&params.vcf2);
+    let mut locus_key_to_locus_id: HashMap<(String, usize, String, String), usize> = HashMap::new();
+    let mut positions1: HashSet<(String, usize)> = HashSet::new();
+    for (index, record) in records1.iter().enumerate() {
+        // because stupid matrix market format is 1 indexed, we start with 1 here
+        locus_key_to_locus_id.insert((record.chrom.clone(), record.pos, record.ref_allele.clone(), record.alt_allele.clone()), index + 1);
+        positions1.insert((record.chrom.clone(), record.pos));
+    }
+    // (dataset, locus_id in that dataset, record)
+    let mut records_out: Vec<(usize, usize, &VcfRecord)> = records1.iter().enumerate().map(|(index, record)| (1, index + 1, record)).collect();
+
+    let filename = format!("{}/conflicting_loci.tsv", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut conflict_writer = BufWriter::new(filehandle);
+    conflict_writer.write_all(b"chrom\tpos\tref2\talt2\n").expect("could not write conflicting loci file");
+    let mut num_swapped = 0;
+    let mut num_conflicting = 0;
+    for (index, record) in records2.iter().enumerate() {
+        let record_number2 = index + 1;
+        let key = (record.chrom.clone(), record.pos, record.ref_allele.clone(), record.alt_allele.clone());
+        let swapped_key = (record.chrom.clone(), record.pos, record.alt_allele.clone(), record.ref_allele.clone());
+        if let Some(&locus_id) = locus_key_to_locus_id.get(&key) {
+            locus_data2_to_data1.insert(record_number2, (locus_id, false));
+        } else if let Some(&locus_id) = locus_key_to_locus_id.get(&swapped_key) {
+            locus_data2_to_data1.insert(record_number2, (locus_id, true));
+            num_swapped += 1;
+        } else if positions1.contains(&(record.chrom.clone(), record.pos)) {
+            conflict_writer.write_all(format!("{}\t{}\t{}\t{}\n", record.chrom, record.pos, record.ref_allele, record.alt_allele).as_bytes())
+                .expect("could not write conflicting loci file");
+            num_conflicting += 1;
+        } else {
+            records_out.push((2, record_number2, record));
+        }
+    }
+    eprintln!("matched {} dataset2 loci with swapped ref/alt, dropped {} dataset2 loci with conflicting alleles", num_swapped, num_conflicting);
+    let mut contig_rank: HashMap<&String, usize> = HashMap::new();
+    for record in records1.iter().chain(records2.iter()) {
+        let rank = contig_rank.len();
+        contig_rank.entry(&record.chrom).or_insert(rank);
+    }
+    // stable, so a dataset2 locus at the position of a dataset1 locus comes after it
+    records_out.sort_by_key(|(_dataset, _locus_id, record)| (contig_rank[&record.chrom], record.pos));
+    let mut locus_data1_to_out: HashMap<usize, usize> = HashMap::new();
+    let mut locus_data2_to_out: HashMap<usize, (usize, bool)> = HashMap::new();
+    for (index, (dataset, locus_id, _record)) in records_out.iter().enumerate() {
+        if *dataset == 1 {
+            locus_data1_to_out.insert(*locus_id, index + 1);
         } else {
-            locus_data2_to_data1.insert(record_number2, record_number);
-            record_number += 1;
+            locus_data2_to_out.insert(*locus_id, (index + 1, false));
         }
-        record_number2 += 1;
     }
-    return (locus_data2_to_data1, record_number - 1);
+    for (record_number2, (locus_id, swapped)) in locus_data2_to_data1 {
+        locus_data2_to_out.insert(record_number2, (locus_data1_to_out[&locus_id], swapped));
+    }
+    write_merged_vcf(params, &meta1, &meta2, &records_out.iter().map(|(_dataset, _locus_id, record)| *record).collect());
+    return (locus_data1_to_out, locus_data2_to_out, records_out.len());
+}
+
+fn write_merged_vcf(params: &Params, meta1: &Vec<String>, meta2: &Vec<String>, records: &Vec<&VcfRecord>) {
+    let filename = format!("{}/merged.vcf", params.output_directory);
+    let filehandle = File::create(&filename).expect(&format!("Unable to create file {}", &filename));
+    let mut writer = BufWriter::new(filehandle);
+    let mut seen: HashSet<&String> = HashSet::new();
+    for line in meta1.iter().chain(meta2.iter().filter(|line| !line.starts_with("##fileformat"))) {
+        if seen.insert(line) {
+            writer.write_all(format!("{}\n", line).as_bytes()).expect("could not write merged vcf");
+        }
+    }
+    writer.write_all(b"#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n").expect("could not write merged vcf");
+    for record in records {
+        writer.write_all(format!("{}\n", record.line).as_bytes()).expect("could not write merged vcf");
+    }
 }
 
 pub fn reader(filename: &str) -> Box<dyn BufRead> {
@@ -279,22 +440,25 @@ fn select_cells_by_barcode(params: &Params, dataset2_mask: String) -> Vec<usize>
 fn consume_mtx_header(alt_reader: &mut Box<dyn BufRead>, ref_reader: &mut Box<dyn BufRead>) ->
     (usize, usize) {
     // total_loci, total_cells
+    // skip the banner and any number of % comment lines, then read the size line
     let mut line = String::new();
-    let mut total_loci = 0;
-    let mut total_cells = 0;
-    for x in 0..3 {
+    loop {
+        line.clear();
         alt_reader.read_line(&mut line).expect("cannot read line from alt matrix market file");
+        if !line.starts_with('%') { break; }
+    }
+    loop {
         line.clear();
         ref_reader.read_line(&mut line).expect("cannot read line from ref matrix market file");
-        if x == 2 {
-            let toks: Vec<&str> = line.split_whitespace().collect();
-            total_loci  = toks[0].to_string().parse::<usize>().unwrap();
-            total_cells = toks[1].to_string().parse::<usize>().unwrap();
-        }
+        if !line.starts_with('%') { break; }
     }
+    let toks: Vec<&str> = line.split_whitespace().collect();
+    let total_loci  = toks[0].to_string().parse::<usize>().unwrap();
+    let total_cells = toks[1].to_string().parse::<usize>().unwrap();
     return (total_loci, total_cells);
 }
 
+#[derive(Default)]
 pub struct Params {
     vcf1: String,
     vcf2: String,
@@ -310,6 +474,16 @@ pub struct Params {
     output_directory: String,
     seed: usize,
     downsample_rate: f64,
+    target_median_depth: Option<f64>,
+    match_depth: bool,
+    gzip: bool,
+    barcode_prefix1: String,
+    barcode_suffix1: Option<String>,
+    barcode_prefix2: String,
+    barcode_suffix2: Option<String>,
+    contamination: Option<f64>,
+    contamination_alpha: Option<f64>,
+    contamination_beta: Option<f64>,
 }
 
 fn load_params() -> Params{
@@ -338,6 +512,36 @@ fn load_params() -> Params{
     let seed = seed.parse::<usize>().unwrap();
     let downsample_rate = params.value_of("downsample_rate").unwrap_or("0.0").to_string();
     let downsample_rate = downsample_rate.parse::<f64>().unwrap();
+    let target_median_depth = match params.value_of("target_median_depth") {
+        Some(x) => Some(x.to_string().parse::<f64>().unwrap()),
+        None => None,
+    };
+    let match_depth = params.is_present("match_depth");
+    // depth matching computes both rates itself, a --downsample_rate given with it would be ignored
+    assert!(!params.is_present("downsample_rate") || (target_median_depth.is_none() && !match_depth), "--downsample_rate cannot be used with --target_median_depth or --match_depth");
+    let gzip = params.is_present("gzip");
+    let barcode_prefix1 = params.value_of("barcode_prefix1").unwrap_or("").to_string();
+    let barcode_suffix1 = match params.value_of("barcode_suffix1") {
+        Some(x) => Some(x.to_string()),
+        None => None,
+    };
+    let barcode_prefix2 = params.value_of("barcode_prefix2").unwrap_or("").to_string();
+    let barcode_suffix2 = Some(params.value_of("barcode_suffix2").unwrap_or("-2").to_string());
+    let contamination = match params.value_of("contamination") {
+        Some(x) => Some(x.to_string().parse::<f64>().unwrap()),
+        None => None,
+    };
+    if let Some(rate) = contamination {
+        assert!((0.0..=1.0).contains(&rate), "contamination must be a probability between 0 and 1");
+    }
+    let contamination_alpha = match params.value_of("contamination_alpha") {
+        Some(x) => Some(x.to_string().parse::<f64>().unwrap()),
+        None => None,
+    };
+    let contamination_beta = match params.value_of("contamination_beta") {
+        Some(x) => Some(x.to_string().parse::<f64>().unwrap()),
+        None => None,
+    };
 
     return Params {
         vcf1: vcf1,
@@ -354,5 +558,62 @@ fn load_params() -> Params{
         output_directory: output_directory,
         seed: seed,
         downsample_rate: downsample_rate,
+        target_median_depth: target_median_depth,
+        match_depth: match_depth,
+        gzip: gzip,
+        barcode_prefix1: barcode_prefix1,
+        barcode_suffix1: barcode_suffix1,
+        barcode_prefix2: barcode_prefix2,
+        barcode_suffix2: barcode_suffix2,
+        contamination: contamination,
+        contamination_alpha: contamination_alpha,
+        contamination_beta: contamination_beta,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // dataset2 has a new locus between two dataset1 loci, one matching with ref and alt swapped, one conflicting and a new contig
+    #[test]
+    fn merged_vcf_is_sorted() {
+        let directory = std::env::temp_dir().join(format!("combiner_test_{}_vcf", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let directory = directory.to_str().unwrap().to_string();
+        let (vcf1, vcf2) = (format!("{}/1.vcf", directory), format!("{}/2.vcf", directory));
+        File::create(&vcf1).unwrap().write_all(b"##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\nchr1\t100\t.\tA\tG\t.\t.\t.\nchr1\t300\t.\tC\tT\t.\t.\t.\nchr2\t50\t.\tG\tA\t.\t.\t.\n").unwrap();
+        File::create(&vcf2).unwrap().write_all(b"##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\nchr1\t200\t.\tT\tC\t.\t.\t.\nchr1\t300\t.\tT\tC\t.\t.\t.\nchr2\t50\t.\tG\tC\t.\t.\t.\nchr3\t10\t.\tA\tC\t.\t.\t.\n").unwrap();
+        let params = Params { vcf1: vcf1, vcf2: vcf2, output_directory: directory.clone(), ..Default::default() };
+        let (locus_data1_to_out, locus_data2_to_out, total_loci) = get_locus_mapping(&params);
+        assert_eq!(total_loci, 5);
+        assert_eq!((locus_data1_to_out[&1], locus_data1_to_out[&2], locus_data1_to_out[&3]), (1, 3, 4));
+        assert_eq!((locus_data2_to_out[&1], locus_data2_to_out[&2], locus_data2_to_out[&4]), ((2, false), (3, true), (5, false)));
+        assert!(!locus_data2_to_out.contains_key(&3));
+        let positions: Vec<String> = reader(&format!("{}/merged.vcf", directory)).lines().map(|line| line.unwrap())
+            .filter(|line| !line.starts_with('#')).map(|line| line.split('\t').take(2).collect::<Vec<&str>>().join(":")).collect();
+        assert_eq!(positions, vec!["chr1:100", "chr1:200", "chr1:300", "chr2:50", "chr3:10"]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // both mtx files written plain and gzipped read back with the same header and entries, less the zero entry
+    #[test]
+    fn mtx_write_read_round_trip() {
+        let lines: Vec<(usize, usize, usize, usize)> = vec![(1, 2, 3, 0), (1, 5, 1, 1), (2, 1, 0, 7), (3, 3, 0, 0), (4, 5, 12, 2)];
+        for gzip in [false, true] {
+            let directory = std::env::temp_dir().join(format!("combiner_test_{}_{}", std::process::id(), gzip));
+            std::fs::create_dir_all(&directory).unwrap();
+            let directory = directory.to_str().unwrap().to_string();
+            write_mtxs(&directory, gzip, 4, 5, &lines);
+            let extension = if gzip { ".gz" } else { "" };
+            let (mut alt_reader, mut ref_reader) = (reader(&format!("{}/alt.mtx{}", directory, extension)), reader(&format!("{}/ref.mtx{}", directory, extension)));
+            assert_eq!(consume_mtx_header(&mut alt_reader, &mut ref_reader), (4, 5));
+            let read: Vec<(usize, usize, usize, usize)> = izip!(alt_reader.lines(), ref_reader.lines()).map(|(alt_line, ref_line)| {
+                let data = read_mtx_lines(alt_line.unwrap(), ref_line.unwrap());
+                (data.locus, data.cell_id, data.ref_count, data.alt_count)
+            }).collect();
+            assert_eq!(read, lines.iter().cloned().filter(|line| line.2 + line.3 > 0).collect::<Vec<(usize, usize, usize, usize)>>());
+            std::fs::remove_dir_all(&directory).unwrap();
+        }
     }
 }